@@ -117,6 +117,7 @@ var statusOrder = {
     'Failure': 3,
     'Success': 4,
     'Reviewing': 5,
+    'Draft': 6,
 };
 var sorters = {
     priority: function(a, b) {
@@ -163,7 +164,8 @@ function doSort() {
 };
 $sort.onchange = doSort;
 doSort();
-$('rollup').onclick = function() {
+var $rollup = $('rollup');
+if ($rollup) $rollup.onclick = function() {
     var allInputs = document.querySelectorAll('#queue .number input');
     var prs = [];
     for (var i = allInputs.length - 1; i >= 0; -- i) {
@@ -182,7 +184,62 @@ $('rollup').onclick = function() {
     }
 };
 
+var $sync = $('sync');
+if ($sync) $sync.onclick = function() {
+    if ($sync.dataset.backend === 'homu' && confirm('Also ask Homu to synchronize? This requires signing in with GitHub.')) {
+        var state = encodeURIComponent(JSON.stringify({
+            cmd: 'synch',
+            repo_label: HOMU_URL,
+        }));
+        open('https://github.com/login/oauth/authorize?client_id=' + CLIENT_ID + '&scope=public_repo,admin:repo_hook&state=' + state);
+    }
+    // Syncing spends the rate limit budget, so it must be a POST request rather than a link.
+    var form = document.createElement('form');
+    form.method = 'post';
+    form.action = 'sync';
+    document.body.appendChild(form);
+    form.submit();
+};
+
 var CACHED_TIMELINES = {};
+function bindLoadEarlier(comment, number) {
+    var buttons = comment.getElementsByClassName('load-earlier');
+    for (var i = buttons.length - 1; i >= 0; -- i) {
+        buttons[i].onclick = loadEarlier(comment, number, buttons[i]);
+    }
+}
+function loadEarlier(comment, number, button) {
+    return function(e) {
+        // The button is about to be removed, which would confuse the handler hiding comments.
+        e.stopPropagation();
+        var more = button.parentNode;
+        var all = button.dataset.all === 'true';
+        var query = all ? 'all=true' : 'before=' + encodeURIComponent(button.dataset.before);
+        if (!all && button.dataset.until) {
+            query += '&until=' + encodeURIComponent(button.dataset.until);
+        }
+        more.textContent = '(Loading…)';
+        var xhr = new XMLHttpRequest();
+        xhr.onreadystatechange = function() {
+            if (xhr.readyState === 4) {
+                if (xhr.status !== 200) {
+                    more.textContent = xhr.responseText;
+                } else if (all) {
+                    comment.innerHTML = xhr.responseText;
+                } else {
+                    var scrollBottom = comment.scrollHeight - comment.scrollTop;
+                    more.insertAdjacentHTML('afterend', xhr.responseText);
+                    more.parentNode.removeChild(more);
+                    comment.scrollTop = comment.scrollHeight - scrollBottom;
+                }
+                recomputeRelativeTime();
+                bindLoadEarlier(comment, number);
+            }
+        };
+        xhr.open('GET', 'timeline/' + number + '?' + query, true);
+        xhr.send();
+    };
+}
 function loadTimeline(comment) {
     var number = comment.parentNode.parentNode.dataset.number;
     return function() {
@@ -193,12 +250,17 @@ function loadTimeline(comment) {
         var xhr = new XMLHttpRequest();
         xhr.onreadystatechange = function() {
             if (xhr.readyState === 4) {
+                if (xhr.status !== 200) {
+                    // Allow retrying, e.g. after the rate limit budget is restored.
+                    delete CACHED_TIMELINES[number];
+                }
                 comment.innerHTML = xhr.responseText;
                 recomputeRelativeTime();
+                bindLoadEarlier(comment, number);
                 comment.scrollTop = comment.scrollHeight;
             }
         };
-        xhr.open('GET', '/timeline/' + number, true);
+        xhr.open('GET', 'timeline/' + number, true);
         xhr.send();
     };
 }
@@ -276,6 +338,24 @@ for (var i = commentMetadata.length - 1; i >= 0; -- i) {
     e.onclick = toggleVisibility(comment);
 }
 
+var ciLogButtons = document.getElementsByClassName('load-ci-log');
+for (var i = ciLogButtons.length - 1; i >= 0; -- i) {
+    ciLogButtons[i].onclick = function(e) {
+        var button = e.target;
+        var container = button.parentNode;
+        button.disabled = true;
+        button.textContent = 'Loading…';
+        var xhr = new XMLHttpRequest();
+        xhr.onreadystatechange = function() {
+            if (xhr.readyState === 4) {
+                container.innerHTML = xhr.responseText;
+            }
+        };
+        xhr.open('GET', 'ci-log/' + button.dataset.number, true);
+        xhr.send();
+    };
+}
+
 var manualRollups = document.getElementsByClassName('manual-rollup');
 for (var i = manualRollups.length - 1; i >= 0; -- i) {
     manualRollups[i].onclick = function(e) { alert(e.target.dataset.instruction); };
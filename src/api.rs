@@ -0,0 +1,182 @@
+//! JSON API exposing the merged queue data.
+//!
+//! See the "JSON API" section of the README for the wire format.
+
+use chrono::{DateTime, Utc};
+use query::Query;
+use queue::Status;
+use render::{summarize_prs, Pr, PrStats};
+use serde_urlencoded;
+use std::collections::HashMap;
+
+/// Version of the JSON wire format.
+///
+/// This must be bumped whenever a field is removed or changes its meaning. Adding new fields is
+/// considered backward-compatible and does not require a version bump.
+pub const API_VERSION: u32 = 2;
+
+/// A request which cannot be served because its parameters are invalid.
+///
+/// The server responds to this error with "400 Bad Request" instead of "500 Internal Server
+/// Error".
+#[derive(Debug, Fail)]
+#[fail(display = "{}", _0)]
+pub struct BadRequest(pub String);
+
+/// A request which is refused because it cannot be authenticated.
+///
+/// The server responds to this error with "403 Forbidden".
+#[derive(Debug, Fail)]
+#[fail(display = "{}", _0)]
+pub struct Forbidden(pub &'static str);
+
+/// A request whose body is larger than allowed.
+///
+/// The server responds to this error with "413 Payload Too Large".
+#[derive(Debug, Fail)]
+#[fail(display = "{}", _0)]
+pub struct PayloadTooLarge(pub &'static str);
+
+/// A request which is refused because the same request was made too recently.
+///
+/// The server responds to this error with "429 Too Many Requests".
+#[derive(Debug, Fail)]
+#[fail(display = "{}", _0)]
+pub struct TooManyRequests(pub String);
+
+/// Query parameters accepted by `/api/prs` and `/api/stats`.
+#[derive(Deserialize, Default)]
+struct RawFilter {
+    /// Comma-separated list of Homu statuses. A PR matches if it has any of them.
+    status: Option<String>,
+    /// GitHub username of the PR author.
+    author: Option<String>,
+    /// Comma-separated list of label names. A PR matches if it has all of them.
+    label: Option<String>,
+    /// Query in the structured query language.
+    q: Option<String>,
+}
+
+/// Filter restricting which PRs are returned from the API.
+#[derive(Default)]
+pub struct Filter {
+    /// The PR must have one of these statuses. Empty means any status.
+    statuses: Vec<Status>,
+    /// The PR must be authored by this user (case-insensitive).
+    author: Option<String>,
+    /// The PR must have all of these labels (case-insensitive).
+    labels: Vec<String>,
+    /// The PR must satisfy this query.
+    query: Option<Query>,
+    /// The original query string, empty if there is no query.
+    pub query_text: String,
+}
+
+impl Filter {
+    /// Parses the filter from the query string of the request URL.
+    pub fn from_query(query: Option<&str>) -> Result<Self, BadRequest> {
+        let raw = match query {
+            Some(query) => serde_urlencoded::from_str::<RawFilter>(query)
+                .map_err(|e| BadRequest(format!("invalid query string: {}", e)))?,
+            None => RawFilter::default(),
+        };
+
+        let statuses = split_list(raw.status.as_ref())
+            .map(|s| {
+                s.parse()
+                    .map_err(|_| BadRequest(format!("unknown status {:?}", s)))
+            })
+            .collect::<Result<_, _>>()?;
+        let labels = split_list(raw.label.as_ref())
+            .map(str::to_lowercase)
+            .collect();
+        let author = raw.author.map(|a| a.to_lowercase());
+        let query_text = raw.q.unwrap_or_default();
+        let query = Query::parse(&query_text)?;
+
+        Ok(Self {
+            statuses,
+            author,
+            labels,
+            query,
+            query_text,
+        })
+    }
+
+    /// Checks whether the PR satisfies this filter.
+    pub fn matches(&self, number: u32, pr: &Pr, now: DateTime<Utc>) -> bool {
+        if !self.statuses.is_empty() && !self.statuses.contains(&pr.status) {
+            return false;
+        }
+        if let Some(ref author) = self.author {
+            if pr.author.to_lowercase() != *author {
+                return false;
+            }
+        }
+        let has_labels = self.labels.iter().all(|name| {
+            pr.labels
+                .iter()
+                .any(|label| label.name.to_lowercase() == *name)
+        });
+        has_labels
+            && self
+                .query
+                .as_ref()
+                .map_or(true, |query| query.matches(number, pr, now))
+    }
+
+    /// Collects all PRs matching the filter.
+    pub fn apply<'a>(&self, prs: &'a HashMap<u32, Pr>) -> HashMap<u32, &'a Pr> {
+        let now = Utc::now();
+        prs.iter()
+            .filter(|&(number, pr)| self.matches(*number, pr, now))
+            .map(|(number, pr)| (*number, pr))
+            .collect()
+    }
+}
+
+/// Splits a comma-separated parameter into its non-empty components.
+fn split_list<'a>(list: Option<&'a String>) -> impl Iterator<Item = &'a str> + 'a {
+    list.into_iter()
+        .flat_map(|s| s.split(','))
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+}
+
+/// Reply of `/api/prs`.
+#[derive(Serialize)]
+pub struct PrsReply<'a> {
+    /// Version of the wire format, always `API_VERSION`.
+    version: u32,
+    /// The PRs matching the filter, keyed by PR number.
+    prs: HashMap<u32, &'a Pr>,
+}
+
+/// Reply of `/api/stats`.
+#[derive(Serialize)]
+pub struct StatsReply {
+    /// Version of the wire format, always `API_VERSION`.
+    version: u32,
+    /// Statistics of the PRs matching the filter.
+    stats: PrStats,
+}
+
+impl<'a> PrsReply<'a> {
+    /// Collects all PRs matching the filter into a reply.
+    pub fn new(prs: &'a HashMap<u32, Pr>, filter: &Filter) -> Self {
+        Self {
+            version: API_VERSION,
+            prs: filter.apply(prs),
+        }
+    }
+}
+
+impl StatsReply {
+    /// Summarizes all PRs matching the filter into a reply.
+    pub fn new(prs: &HashMap<u32, Pr>, filter: &Filter) -> Self {
+        Self {
+            version: API_VERSION,
+            stats: summarize_prs(filter.apply(prs).values().cloned()),
+        }
+    }
+}
@@ -1,69 +1,184 @@
 //! Argument parsing
 
+use config::Repository;
+use queue::Backend;
 use reqwest::Url;
-use serde::Serializer;
 use std::net::SocketAddr;
 use std::path::PathBuf;
 
 /// Stores the command line argument.
-#[derive(Debug, StructOpt, Serialize)]
+///
+/// Every setting is optional here. Missing settings are filled in from the environment, the
+/// configuration file and the default values when building the [`Config`].
+///
+/// [`Config`]: ../config/struct.Config.html
+#[derive(Debug, StructOpt)]
 pub struct Args {
+    /// Path to the TOML configuration file.
+    #[structopt(short = "c", long = "config", help = "Configuration file", parse(from_os_str))]
+    pub config: Option<PathBuf>,
+
     /// The token to access the GitHub APIs.
+    ///
+    /// The `GITHUB_TOKEN` environment variable is read separately, after `--token-file`.
     #[structopt(short = "t", long = "token", help = "GitHub token")]
-    pub token: String,
+    pub token: Option<String>,
+
+    /// Path to a file containing the token to access the GitHub APIs.
+    #[structopt(
+        long = "token-file",
+        help = "File containing the GitHub token",
+        parse(from_os_str)
+    )]
+    pub token_file: Option<PathBuf>,
 
     /// Owner of the GitHub repository.
-    #[structopt(long = "owner", help = "Repository owner", default_value = "rust-lang")]
-    pub owner: String,
+    #[structopt(long = "owner", help = "Repository owner [default: rust-lang]")]
+    pub owner: Option<String>,
 
     /// Name of the GitHub repository.
-    #[structopt(long = "repository", help = "Repository name", default_value = "rust")]
-    pub repository: String,
+    #[structopt(long = "repository", help = "Repository name [default: rust]")]
+    pub repository: Option<String>,
 
     /// URL to access the Homu queue.
     #[structopt(
         long = "homu-queue-url",
-        help = "URL to the Homu queue",
-        default_value = "https://buildbot2.rust-lang.org/homu/queue/rust"
+        help = "URL to the Homu queue [default: https://buildbot2.rust-lang.org/homu/queue/rust]"
+    )]
+    pub homu_url: Option<Url>,
+
+    /// Kind of bot maintaining the merge queue at `homu_url`.
+    #[structopt(
+        long = "queue-backend",
+        help = "Kind of the merge queue, `homu` or `bors` [default: homu]",
+        parse(try_from_str = "parse_backend")
+    )]
+    pub backend: Option<Backend>,
+
+    /// Additional repositories to show, each in the form `owner/name=[backend:]queue_url`.
+    ///
+    /// If this is not empty, `owner`, `repository`, `homu_url` and `backend` will be ignored.
+    #[structopt(
+        long = "repo",
+        help = "Repository to show, as `owner/name=[homu:|bors:]queue_url` (can be repeated)",
+        raw(number_of_values = "1")
     )]
-    #[serde(serialize_with = "serialize_url")]
-    pub homu_url: Url,
+    pub repos: Vec<Repository>,
 
     /// Client ID of the Homu GitHub OAuth App.
     #[structopt(
         long = "homu-client-id",
-        help = "Client ID of the Homu GitHub OAuth App",
-        default_value = "f828d548f928f1e11199"
+        help = "Client ID of the Homu GitHub OAuth App [default: f828d548f928f1e11199]"
     )]
-    pub homu_client_id: String,
+    pub homu_client_id: Option<String>,
 
     /// Socket address of the local web server.
     #[structopt(
         short = "l",
         long = "listen",
-        help = "Address of local server",
-        default_value = "127.0.0.1:55727"
+        help = "Address of local server [default: 127.0.0.1:55727, or 0.0.0.0:$PORT]"
     )]
-    pub address: SocketAddr,
+    pub address: Option<SocketAddr>,
 
     /// Directory to find Tera templates and static resources
     #[structopt(
         short = "i",
         long = "templates",
-        help = "Directory of the templates",
-        default_value = "res",
+        help = "Directory of the templates [default: res]",
+        parse(from_os_str)
+    )]
+    pub templates: Option<PathBuf>,
+
+    /// Number of seconds between each background refresh of the PR list.
+    #[structopt(
+        long = "refresh-interval",
+        help = "Seconds between background refreshes of the PR list [default: 120]"
+    )]
+    pub refresh_interval: Option<u64>,
+
+    /// Number of seconds between each full sync of the PR list. Other refreshes only fetch the PRs
+    /// updated since the last sync.
+    #[structopt(
+        long = "full-sync-interval",
+        help = "Seconds between full syncs of the PR list, 0 to always fully sync [default: 1800]"
+    )]
+    pub full_sync_interval: Option<u64>,
+
+    /// Number of GitHub rate limit points to keep for the background refresh. Timelines are not
+    /// fetched when fewer points remain.
+    #[structopt(
+        long = "rate-limit-reserve",
+        help = "GitHub rate limit points kept for refreshing the PR list [default: 500]"
+    )]
+    pub rate_limit_reserve: Option<u32>,
+
+    /// Client ID of the GitHub OAuth App for visitors to log in.
+    #[structopt(
+        long = "oauth-client-id",
+        help = "Client ID of the GitHub OAuth App for visitors to log in"
+    )]
+    pub oauth_client_id: Option<String>,
+
+    /// Client secret of the GitHub OAuth App for visitors to log in.
+    #[structopt(
+        long = "oauth-client-secret",
+        help = "Client secret of the GitHub OAuth App for visitors to log in",
+        env = "BORSHOLDER_OAUTH_CLIENT_SECRET",
+        raw(hide_env_values = "true")
+    )]
+    pub oauth_client_secret: Option<String>,
+
+    /// Secret shared with GitHub to verify the webhook deliveries at `/webhook/github`.
+    #[structopt(
+        long = "webhook-secret",
+        help = "Secret of the GitHub webhook delivering to /webhook/github",
+        env = "BORSHOLDER_WEBHOOK_SECRET",
+        raw(hide_env_values = "true")
+    )]
+    pub webhook_secret: Option<String>,
+
+    /// Path to the TOML database of known CI failure signatures.
+    #[structopt(
+        long = "failure-signatures",
+        help = "TOML file of known CI failure signatures to classify failures with",
         parse(from_os_str)
     )]
-    #[serde(skip_serializing)]
-    pub templates: PathBuf,
+    pub failure_signatures: Option<PathBuf>,
 
     /// HTTP(S) proxy server. If not `None`, all API requests will pass through this URL.
     #[structopt(short = "p", long = "proxy", help = "HTTP(S) proxy server")]
-    #[serde(skip_serializing)]
     pub proxy: Option<Url>,
+
+    /// Directory to persist the responses from GitHub and the merge queue across restarts.
+    #[structopt(
+        long = "cache-dir",
+        help = "Directory to cache the responses from GitHub and the merge queue across restarts",
+        parse(from_os_str)
+    )]
+    pub cache_dir: Option<PathBuf>,
+
+    /// Directory to save every raw response from GitHub and the merge queue.
+    #[structopt(
+        long = "record",
+        help = "Save all raw responses from GitHub and the merge queue into this directory",
+        parse(from_os_str),
+        raw(conflicts_with = "\"replay\"")
+    )]
+    pub record: Option<PathBuf>,
+
+    /// Directory to read the raw responses previously saved with `--record`.
+    ///
+    /// When specified, the network is never accessed.
+    #[structopt(
+        long = "replay",
+        help = "Serve responses previously saved with --record from this directory, without network access",
+        parse(from_os_str)
+    )]
+    pub replay: Option<PathBuf>,
 }
 
-/// Serializes a URL using serde.
-fn serialize_url<S: Serializer>(url: &Url, serializer: S) -> Result<S::Ok, S::Error> {
-    serializer.serialize_str(url.as_str())
+/// Parses the name of a merge queue backend.
+fn parse_backend(s: &str) -> Result<Backend, String> {
+    s.parse()
+        .map_err(|()| format!("unknown queue backend {:?}, expecting `homu` or `bors`", s))
 }
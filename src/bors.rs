@@ -0,0 +1,182 @@
+//! rust-lang/bors queue web scraper.
+//!
+//! Unlike Homu, the columns of the rust-lang/bors queue table are located from the table header,
+//! so reordering or adding columns does not break the scraper.
+
+use chrono::{DateTime, Utc};
+use failure::{err_msg, Error, ResultExt};
+use futures::Future;
+use kuchiki::NodeRef;
+use queue::{cached_html, fetch_html, row_cells, Entry, QueueSource, Status};
+use reqwest::async::Client;
+use reqwest::Url;
+
+/// The rust-lang/bors queue of a repository.
+pub struct Bors {
+    /// URL to access the bors queue page, e.g. `https://bors.rust-lang.org/queue/rust`.
+    url: Url,
+}
+
+impl Bors {
+    /// Creates a new bors queue source reading from the given URL.
+    pub fn new(url: Url) -> Self {
+        Self { url }
+    }
+}
+
+impl QueueSource for Bors {
+    /// Obtains the list of pull requests and associated information from bors queue.
+    fn query(&self, client: &Client) -> Box<Future<Item = Vec<Entry>, Error = Error>> {
+        info!("Preparing to send bors request");
+        Box::new(fetch_html(client, &self.url).and_then(parse_queue))
+    }
+
+    /// Obtains the list of pull requests from the cached bors queue page.
+    fn cached(&self) -> Option<(DateTime<Utc>, Vec<Entry>)> {
+        let (fetched_at, doc) = cached_html(&self.url)?;
+        match parse_queue(doc) {
+            Ok(entries) => Some((fetched_at, entries)),
+            Err(e) => {
+                warn!("cannot parse cached bors queue: {}", e);
+                None
+            }
+        }
+    }
+}
+
+/// Indices of the interesting columns in the queue table.
+#[derive(Default)]
+struct Columns {
+    /// Column of the PR number.
+    number: Option<usize>,
+    /// Column of the PR title.
+    title: Option<usize>,
+    /// Column of the approval status.
+    status: Option<usize>,
+    /// Column of the approver name.
+    approver: Option<usize>,
+    /// Column of the priority.
+    priority: Option<usize>,
+    /// Column of the rollup mode.
+    rollup: Option<usize>,
+}
+
+impl Columns {
+    /// Locates the columns from the header cells of the queue table.
+    fn from_headers<I: IntoIterator<Item = String>>(headers: I) -> Self {
+        let mut columns = Self::default();
+        for (i, header) in headers.into_iter().enumerate() {
+            let column = match &*header.to_ascii_lowercase() {
+                "#" | "pr" | "number" => &mut columns.number,
+                "title" => &mut columns.title,
+                "status" => &mut columns.status,
+                "approved by" | "approver" | "reviewer" => &mut columns.approver,
+                "priority" => &mut columns.priority,
+                "rollup" => &mut columns.rollup,
+                _ => continue,
+            };
+            *column = Some(i);
+        }
+        columns
+    }
+}
+
+/// Extracts the pull requests from the HTML document of the bors queue.
+fn parse_queue(doc: NodeRef) -> Result<Vec<Entry>, Error> {
+    let table = doc
+        .select_first("table")
+        .map_err(|()| err_msg("bors queue table not found. Aborting."))?;
+    let headers = table
+        .as_node()
+        .select("thead th")
+        .expect("well-formed CSS query")
+        .map(|th| th.text_contents().trim().to_owned());
+    let columns = Columns::from_headers(headers);
+    if columns.number.is_none() {
+        return Err(err_msg("bors queue structure probably changed. Aborting."));
+    }
+
+    let mut res = Vec::new();
+    for tr in table
+        .as_node()
+        .select("tbody > tr")
+        .expect("well-formed CSS query")
+    {
+        let tds = row_cells(tr.as_node());
+        let number = cell(&tds, columns.number)
+            .trim_left_matches('#')
+            .parse::<u32>()
+            .context("invalid PR number")?;
+        let priority = if cell(&tds, columns.rollup).eq_ignore_ascii_case("always") {
+            -1
+        } else {
+            cell(&tds, columns.priority).parse().unwrap_or(0)
+        };
+
+        res.push(Entry {
+            number,
+            title: cell(&tds, columns.title).to_owned(),
+            status: parse_status(cell(&tds, columns.status)),
+            is_trying: false,
+            priority,
+            approver: cell(&tds, columns.approver).to_owned(),
+        });
+    }
+
+    info!("Obtained {} PRs from bors", res.len());
+    Ok(res)
+}
+
+/// Obtains the text of a cell, or an empty string if the column does not exist.
+fn cell(tds: &[String], column: Option<usize>) -> &str {
+    column.and_then(|i| tds.get(i)).map_or("", |s| &**s)
+}
+
+/// Parses the rendered approval status string.
+///
+/// Try builds are listed separately by bors, so they never affect the status here.
+fn parse_status(status_str: &str) -> Status {
+    match &*status_str.to_ascii_lowercase() {
+        "approved" => Status::Approved,
+        "pending" | "in progress" | "running" => Status::Pending,
+        "success" | "merged" => Status::Success,
+        "failure" | "failed" => Status::Failure,
+        "error" | "timed out" | "timeout" => Status::Error,
+        _ => Status::Reviewing,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kuchiki::parse_html;
+    use kuchiki::traits::TendrilSink;
+
+    #[test]
+    fn test_parse_queue() {
+        let doc = parse_html().one(include_str!("../tests/fixtures/bors-queue.html"));
+        let entries = parse_queue(doc).unwrap();
+        let summary = entries
+            .iter()
+            .map(|e| (e.number, e.status, e.priority, &*e.approver))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                (140_001, Status::Pending, 5, "matthiaskrgr"),
+                (139_872, Status::Approved, 1, "fee1-dead"),
+                (139_954, Status::Approved, -1, "workingjubilee"),
+                (139_760, Status::Failure, 0, "weihanglo"),
+                (139_512, Status::Reviewing, 0, ""),
+            ]
+        );
+        assert_eq!(entries[4].title, "Add `core::hint::select_unpredictable` & friends");
+        assert!(entries.iter().all(|e| !e.is_trying));
+    }
+
+    #[test]
+    fn test_parse_queue_without_number_column() {
+        let doc = parse_html().one("<table><thead><tr><th>Title</th></tr></thead></table>");
+        assert!(parse_queue(doc).is_err());
+    }
+}
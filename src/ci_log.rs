@@ -0,0 +1,687 @@
+//! Excerpts of the job logs of failed CI statuses.
+//!
+//! Each CI service stores its logs differently, and is accessed through an implementation of
+//! [`LogFetcher`] recognizing the target URL of the status. The error region of the log is then
+//! extracted, so the reason of a failure can be seen without visiting the CI page. The failure is
+//! also classified against the known signatures in [`spurious`].
+//!
+//! [`LogFetcher`]: trait.LogFetcher.html
+//! [`spurious`]: ../spurious/index.html
+
+use failure::{err_msg, Error};
+use futures::future::join_all;
+use futures::{Future, IntoFuture, Stream};
+use github::graphql::{StatusContext, StatusState};
+use lru_time_cache::LruCache;
+use recording::{self, sanitize};
+use regex::Regex;
+use reqwest::async::{Client, RequestBuilder};
+use reqwest::header::{AUTHORIZATION, USER_AGENT};
+use reqwest::Url;
+use serde_json;
+use spurious::{self, Signature, Verdict};
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio_core::reactor::{Handle, Timeout};
+
+/// Number of seconds to keep an excerpt. Logs of finished jobs do not change, so this only bounds
+/// the memory used.
+const EXCERPT_EXPIRY: u64 = 86400;
+
+/// Number of seconds to remember that a log cannot be fetched, before trying again.
+const FAILED_FETCH_EXPIRY: u64 = 600;
+
+/// Number of lines shown before the first error line.
+const LINES_BEFORE: usize = 5;
+
+/// Maximum number of lines in an excerpt.
+const MAX_LINES: usize = 40;
+
+/// Maximum size of a log in bytes. Larger logs are not shown.
+const MAX_LOG_SIZE: usize = 32 << 20;
+
+/// Number of seconds to wait for a log before giving up.
+const FETCH_TIMEOUT: u64 = 30;
+
+/// Where CI logs may be fetched from, set in the configuration file.
+#[derive(Debug, Clone, Default)]
+pub struct LogSources {
+    /// Hosts serving plain text logs, whose URLs end with `.log` or `.txt`. Entries may include
+    /// the port, e.g. `127.0.0.1:8000`.
+    pub plain_text_hosts: Vec<String>,
+    /// If set, every log is fetched from this base URL instead of the host of the CI service,
+    /// keeping the path and query, e.g. to use a local stand-in log server.
+    pub base_url: Option<Url>,
+}
+
+/// The log excerpt of a failed status.
+#[derive(Serialize, Clone)]
+pub struct Excerpt {
+    /// Name of the status context.
+    pub context: String,
+    /// URL of the CI job.
+    pub url: String,
+    /// The error region of the log, `None` if no error line is found.
+    pub excerpt: Option<String>,
+    /// Why the log cannot be shown, e.g. the CI service is not supported.
+    pub error: Option<String>,
+    /// Whether the failure matches a known signature.
+    pub verdict: Verdict,
+}
+
+/// A source of CI job logs.
+pub trait LogFetcher {
+    /// Finds the URL to fetch the log of the job at the target URL of a status.
+    ///
+    /// Returns `None` if the URL does not belong to this CI service.
+    fn log_url(&self, url: &Url, sources: &LogSources) -> Option<Url>;
+
+    /// Fetches the raw log from the URL returned by `log_url()`. `base_url` is the base URL from
+    /// [`LogSources`], which every request must be sent to if set.
+    ///
+    /// [`LogSources`]: struct.LogSources.html
+    fn fetch(
+        &self,
+        client: &Client,
+        _token: &str,
+        log_url: Url,
+        base_url: Option<&Url>,
+    ) -> Box<Future<Item = String, Error = Error>> {
+        get_text(client, client.get(log_url), base_url)
+    }
+
+    /// Whether the logs are fetched with the GitHub token, so they may only be visible to the
+    /// owner of the token.
+    fn uses_token(&self) -> bool {
+        false
+    }
+}
+
+/// GitHub Actions, whose check run URLs look like `https://github.com/«owner»/«repo»/runs/«job»`
+/// or `https://github.com/«owner»/«repo»/actions/runs/«run»/job/«job»`.
+struct GitHubActions;
+
+impl LogFetcher for GitHubActions {
+    fn log_url(&self, url: &Url, _: &LogSources) -> Option<Url> {
+        if url.host_str() != Some("github.com") {
+            return None;
+        }
+        let segments = url.path_segments()?.collect::<Vec<_>>();
+        let job = match *segments {
+            [_, _, "runs", job] | [_, _, "actions", "runs", _, "job", job] => job,
+            _ => return None,
+        };
+        let log_url = format!(
+            "https://api.github.com/repos/{}/{}/actions/jobs/{}/logs",
+            segments[0], segments[1], job
+        );
+        log_url.parse().ok()
+    }
+
+    fn fetch(
+        &self,
+        client: &Client,
+        token: &str,
+        log_url: Url,
+        base_url: Option<&Url>,
+    ) -> Box<Future<Item = String, Error = Error>> {
+        let request = client
+            .get(log_url)
+            .header(AUTHORIZATION, format!("token {}", token))
+            .header(USER_AGENT, "borsholder");
+        get_text(client, request, base_url)
+    }
+
+    fn uses_token(&self) -> bool {
+        true
+    }
+}
+
+/// Azure Pipelines, whose build URLs look like
+/// `https://dev.azure.com/«org»/«project»/_build/results?buildId=«build»`.
+///
+/// The build timeline is fetched first to find the log of the first failed task.
+struct AzurePipelines;
+
+/// The parts of an Azure Pipelines build timeline needed to find the failed task.
+#[derive(Deserialize)]
+struct AzureTimeline {
+    /// The jobs and tasks of the build.
+    records: Vec<AzureRecord>,
+}
+
+/// A job or task in an Azure Pipelines build timeline.
+#[derive(Deserialize)]
+struct AzureRecord {
+    /// The kind of the record, e.g. `Job` or `Task`.
+    #[serde(rename = "type")]
+    kind: String,
+    /// The result, e.g. `succeeded` or `failed`.
+    result: Option<String>,
+    /// The log of the record.
+    log: Option<AzureLog>,
+}
+
+/// Reference to the log of an Azure Pipelines timeline record.
+#[derive(Deserialize)]
+struct AzureLog {
+    /// URL of the raw log.
+    url: String,
+}
+
+impl LogFetcher for AzurePipelines {
+    /// Returns the URL of the build timeline, which is fetched first to find the log of the
+    /// failed task.
+    fn log_url(&self, url: &Url, _: &LogSources) -> Option<Url> {
+        if url.host_str() != Some("dev.azure.com") {
+            return None;
+        }
+        let segments = url.path_segments()?.collect::<Vec<_>>();
+        let (org, project) = match *segments {
+            [org, project, "_build", "results"] => (org, project),
+            _ => return None,
+        };
+        let build = url
+            .query_pairs()
+            .find(|&(ref key, _)| key == "buildId")?
+            .1
+            .into_owned();
+        let timeline_url = format!(
+            "https://dev.azure.com/{}/{}/_apis/build/builds/{}/timeline?api-version=5.0",
+            org, project, build
+        );
+        timeline_url.parse().ok()
+    }
+
+    fn fetch(
+        &self,
+        client: &Client,
+        _: &str,
+        log_url: Url,
+        base_url: Option<&Url>,
+    ) -> Box<Future<Item = String, Error = Error>> {
+        let client = client.clone();
+        let base_url = base_url.cloned();
+        Box::new(
+            get_text(&client, client.get(log_url), base_url.as_ref()).and_then(move |timeline| {
+                failed_task_log_url(&timeline)
+                    .into_future()
+                    .and_then(move |url| get_text(&client, client.get(url), base_url.as_ref()))
+            }),
+        )
+    }
+}
+
+/// Finds the log URL of the first failed task in an Azure Pipelines build timeline. Only logs on
+/// Azure DevOps itself are accepted.
+fn failed_task_log_url(timeline: &str) -> Result<Url, Error> {
+    let url = serde_json::from_str::<AzureTimeline>(timeline)?
+        .records
+        .into_iter()
+        .filter(|record| {
+            record.kind == "Task" && record.result.as_ref().map_or(false, |r| r == "failed")
+        })
+        .find_map(|record| record.log)
+        .map(|log| log.url)
+        .ok_or_else(|| err_msg("no failed task found in the build timeline"))?
+        .parse::<Url>()?;
+    if url.host_str() != Some("dev.azure.com") {
+        return Err(err_msg("the log of the failed task is not on Azure DevOps"));
+    }
+    Ok(url)
+}
+
+/// Travis CI, whose job URLs look like `https://travis-ci.org/«owner»/«repo»/jobs/«job»`. Build
+/// URLs are not supported, since a build consists of several jobs.
+struct Travis;
+
+impl LogFetcher for Travis {
+    fn log_url(&self, url: &Url, _: &LogSources) -> Option<Url> {
+        let api_host = match url.host_str()? {
+            "travis-ci.org" => "api.travis-ci.org",
+            "travis-ci.com" => "api.travis-ci.com",
+            _ => return None,
+        };
+        let segments = url.path_segments()?.collect::<Vec<_>>();
+        let job = match *segments {
+            [_, _, "jobs", job] => job,
+            _ => return None,
+        };
+        let log_url = format!("https://{}/v3/job/{}/log.txt", api_host, job);
+        log_url.parse().ok()
+    }
+}
+
+/// A URL to a plain text file ending with `.log` or `.txt`, on one of the hosts listed in
+/// `LogSources::plain_text_hosts`.
+struct PlainText;
+
+impl LogFetcher for PlainText {
+    fn log_url(&self, url: &Url, sources: &LogSources) -> Option<Url> {
+        if !url.path().ends_with(".log") && !url.path().ends_with(".txt") {
+            return None;
+        }
+        let host = url.host_str()?;
+        let host_and_port = url.port().map(|port| format!("{}:{}", host, port));
+        let is_allowed = sources.plain_text_hosts.iter().any(|allowed| {
+            allowed.eq_ignore_ascii_case(host)
+                || host_and_port.as_ref().map_or(false, |h| allowed.eq_ignore_ascii_case(h))
+        });
+        if is_allowed {
+            Some(url.clone())
+        } else {
+            None
+        }
+    }
+}
+
+/// The supported CI services, tried in order.
+static FETCHERS: &[&(LogFetcher + Sync)] =
+    &[&GitHubActions, &AzurePipelines, &Travis, &PlainText];
+
+lazy_static! {
+    /// Excerpts of the logs fetched recently, keyed by the target URL. Logs fetched with a
+    /// visitor's token are also keyed by the visitor, and are not shared with anyone else.
+    static ref EXCERPTS: Mutex<LruCache<(Option<String>, String), Excerpt>> =
+        Mutex::new(LruCache::with_expiry_duration(Duration::from_secs(EXCERPT_EXPIRY)));
+
+    /// Excerpts of the logs which could not be fetched recently, keyed like `EXCERPTS`. They
+    /// only carry the error, and are kept for a shorter time.
+    static ref FAILED_FETCHES: Mutex<LruCache<(Option<String>, String), Excerpt>> =
+        Mutex::new(LruCache::with_expiry_duration(Duration::from_secs(FAILED_FETCH_EXPIRY)));
+
+    /// Lines indicating where an error occurred.
+    static ref ERROR_LINE_RE: Regex = Regex::new(
+        r"^(error(\[E[0-9]+\])?:|test result: FAILED|thread '.*' panicked at|##\[error\]|failures:$|FAILED:|Build completed unsuccessfully)"
+    ).expect("error line regex");
+
+    /// ANSI color codes and the timestamps prepended by GitHub Actions and Azure Pipelines.
+    static ref NOISE_RE: Regex = Regex::new(
+        r"\x1b\[[0-9;]*[A-Za-z]|^[0-9]{4}-[0-9]{2}-[0-9]{2}T[0-9:.]+Z "
+    ).expect("log noise regex");
+}
+
+/// Finds the CI service of the target URL of a status, and the URL to fetch its log.
+fn find_fetcher(
+    url: &Url,
+    sources: &LogSources,
+) -> Option<(&'static (LogFetcher + Sync), Url)> {
+    FETCHERS
+        .iter()
+        .find_map(|&fetcher| Some((fetcher, fetcher.log_url(url, sources)?)))
+}
+
+/// Replaces the scheme, host and port of a URL with those of the base URL, and prepends the path
+/// of the base URL.
+fn rebase(url: &Url, base_url: &Url) -> Url {
+    let mut rebased = base_url.clone();
+    let path = format!("{}{}", base_url.path().trim_end_matches('/'), url.path());
+    rebased.set_path(&path);
+    rebased.set_query(url.query());
+    rebased
+}
+
+/// Whether the host belongs to GitHub, which may receive the GitHub token.
+fn is_github_host(host: Option<&str>) -> bool {
+    match host {
+        Some(host) => {
+            host.eq_ignore_ascii_case("github.com") || host.eq_ignore_ascii_case("api.github.com")
+        }
+        None => false,
+    }
+}
+
+/// Sends a request and reads the response as text. The request is sent to `base_url` instead if
+/// given, see `rebase()`, without the GitHub token unless `base_url` is on GitHub too. Responses
+/// larger than `MAX_LOG_SIZE` are refused.
+///
+/// The raw response is recorded or replayed according to the current [`recording::Mode`].
+///
+/// [`recording::Mode`]: ../recording/enum.Mode.html
+fn get_text(
+    client: &Client,
+    request: RequestBuilder,
+    base_url: Option<&Url>,
+) -> Box<Future<Item = String, Error = Error>> {
+    let mut request = match request.build() {
+        Ok(request) => request,
+        Err(e) => return Box::new(Err(Error::from(e)).into_future()),
+    };
+    let storage_path =
+        Path::new("ci-log").join(format!("{}.txt", sanitize(request.url().as_str())));
+    if let Some(body) = recording::replay(&storage_path) {
+        return Box::new(
+            body.map(|body| String::from_utf8_lossy(&body).into_owned())
+                .into_future(),
+        );
+    }
+    if let Some(base_url) = base_url {
+        let url = rebase(request.url(), base_url);
+        if !is_github_host(url.host_str()) {
+            request.headers_mut().remove(AUTHORIZATION);
+        }
+        *request.url_mut() = url;
+    }
+    Box::new(
+        client
+            .execute(request)
+            .and_then(|response| response.error_for_status())
+            .map_err(Error::from)
+            .and_then(|response| {
+                response
+                    .into_body()
+                    .map_err(Error::from)
+                    .fold(Vec::new(), |mut body, chunk| {
+                        if body.len() + chunk.len() > MAX_LOG_SIZE {
+                            let error = format!("the log is larger than {} MB", MAX_LOG_SIZE >> 20);
+                            return Err(err_msg(error));
+                        }
+                        body.extend_from_slice(&chunk);
+                        Ok(body)
+                    })
+            })
+            .map(move |body| {
+                recording::record(&storage_path, &body);
+                String::from_utf8_lossy(&body).into_owned()
+            }),
+    )
+}
+
+/// Extracts the error region of a log: a few lines before the first error line, up to
+/// `MAX_LINES` lines in total. Returns `None` if there is no error line.
+pub fn extract(log: &str) -> Option<String> {
+    let lines = log
+        .lines()
+        .map(|line| NOISE_RE.replace_all(line, ""))
+        .collect::<Vec<_>>();
+    let first_error = lines.iter().position(|line| ERROR_LINE_RE.is_match(line))?;
+    let start = first_error.saturating_sub(LINES_BEFORE);
+    let end = (start + MAX_LINES).min(lines.len());
+    Some(lines[start..end].join("\n"))
+}
+
+/// Forgets the excerpts fetched so far, e.g. after the failure signatures are changed.
+pub fn clear() {
+    EXCERPTS.lock().expect("poisoned").clear();
+    FAILED_FETCHES.lock().expect("poisoned").clear();
+}
+
+/// Fetches the log excerpts of the failed statuses, and classifies the failures against the
+/// signatures.
+///
+/// Statuses from unsupported CI services are reported with an error instead of failing the whole
+/// request, and are classified by their description alone. So are the logs which cannot be
+/// fetched, which are not tried again for `FAILED_FETCH_EXPIRY` seconds.
+///
+/// Every failure matching a signature is counted towards its hits.
+///
+/// If `viewer` is given, `token` belongs to that logged-in visitor.
+///
+/// Each log is given `FETCH_TIMEOUT` seconds to arrive.
+pub fn excerpts(
+    client: &Client,
+    handle: &Handle,
+    token: &str,
+    viewer: Option<&str>,
+    statuses: &[StatusContext],
+    signatures: &Rc<Vec<Signature>>,
+    sources: &LogSources,
+) -> Box<Future<Item = Vec<Excerpt>, Error = Error>> {
+    let futures = statuses
+        .iter()
+        .filter(|status| status.state == StatusState::Failure)
+        .map(|status| {
+            let signatures = Rc::clone(signatures);
+            excerpt(client, handle, token, viewer, status, signatures, sources)
+        })
+        .collect::<Vec<_>>();
+    Box::new(join_all(futures).inspect(|excerpts| {
+        for excerpt in excerpts {
+            spurious::record_hit(&excerpt.url, &excerpt.verdict);
+        }
+    }))
+}
+
+/// Fails the future if it does not complete within `FETCH_TIMEOUT` seconds.
+fn with_timeout(
+    handle: &Handle,
+    future: Box<Future<Item = String, Error = Error>>,
+) -> Box<Future<Item = String, Error = Error>> {
+    let timeout = match Timeout::new(Duration::from_secs(FETCH_TIMEOUT), handle) {
+        Ok(timeout) => timeout,
+        Err(e) => return Box::new(Err(Error::from(e)).into_future()),
+    };
+    let timeout = timeout.then(|_| Err(err_msg("timed out while fetching the log")));
+    Box::new(
+        future
+            .select(timeout)
+            .map(|(log, _)| log)
+            .map_err(|(e, _)| e),
+    )
+}
+
+/// Fetches the log excerpt of a single failed status.
+fn excerpt(
+    client: &Client,
+    handle: &Handle,
+    token: &str,
+    viewer: Option<&str>,
+    status: &StatusContext,
+    signatures: Rc<Vec<Signature>>,
+    sources: &LogSources,
+) -> Box<Future<Item = Excerpt, Error = Error>> {
+    let shared_key = (None, status.target_url.clone());
+    let viewer_key = viewer.map(|viewer| (Some(viewer.to_owned()), status.target_url.clone()));
+    for cache in &[&*EXCERPTS, &*FAILED_FETCHES] {
+        let mut cache = cache.lock().expect("poisoned");
+        if let Some(excerpt) = cache.get(&shared_key).cloned().or_else(|| {
+            let key = viewer_key.as_ref()?;
+            cache.get(key).cloned()
+        }) {
+            return Box::new(Ok(excerpt).into_future());
+        }
+    }
+    let description = status.description.clone();
+    let make_excerpt = {
+        let context = status.context.clone();
+        let url = status.target_url.clone();
+        move |excerpt, error, verdict| Excerpt {
+            context,
+            url,
+            excerpt,
+            error,
+            verdict,
+        }
+    };
+    let fetcher = Url::parse(&status.target_url)
+        .ok()
+        .and_then(|url| find_fetcher(&url, sources));
+    let (fetch, uses_token) = match fetcher {
+        Some((fetcher, log_url)) => {
+            let fetch = fetcher.fetch(client, token, log_url, sources.base_url.as_ref());
+            (with_timeout(handle, fetch), fetcher.uses_token())
+        }
+        None => {
+            let error = "the log of this CI service is not supported".to_owned();
+            let verdict = spurious::classify(&signatures, &description);
+            return Box::new(Ok(make_excerpt(None, Some(error), verdict)).into_future());
+        }
+    };
+    let key = match viewer_key {
+        Some(viewer_key) if uses_token => viewer_key,
+        _ => shared_key,
+    };
+    Box::new(fetch.then(move |result| {
+        let excerpt = match result {
+            Ok(log) => {
+                let text = format!("{}\n{}", description, log);
+                let verdict = spurious::classify(&signatures, &text);
+                let excerpt = make_excerpt(extract(&log), None, verdict);
+                EXCERPTS.lock().expect("poisoned").insert(key, excerpt.clone());
+                excerpt
+            }
+            Err(e) => {
+                warn!("cannot fetch CI log from {}: {}", key.1, e);
+                let verdict = spurious::classify(&signatures, &description);
+                let excerpt = make_excerpt(None, Some(e.to_string()), verdict);
+                FAILED_FETCHES.lock().expect("poisoned").insert(key, excerpt.clone());
+                excerpt
+            }
+        };
+        Ok(excerpt)
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{extract, find_fetcher, rebase, GitHubActions, LogFetcher, LogSources, MAX_LINES};
+    use reqwest::async::Client;
+    use reqwest::Url;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use tokio_core::reactor::Core;
+
+    /// Finds the URL of the log to fetch for a target URL.
+    fn log_url(url: &str, sources: &LogSources) -> Option<String> {
+        let url = Url::parse(url).unwrap();
+        find_fetcher(&url, sources).map(|(_, log_url)| log_url.into_string())
+    }
+
+    #[test]
+    fn test_extract() {
+        let log = "\
+            2019-01-01T00:00:00.0000000Z building\n\
+            line 1\n\
+            line 2\n\
+            line 3\n\
+            line 4\n\
+            line 5\n\
+            line 6\n\
+            \x1b[31merror[E0308]: mismatched types\x1b[0m\n\
+            after";
+        assert_eq!(
+            extract(log).unwrap(),
+            "line 2\nline 3\nline 4\nline 5\nline 6\nerror[E0308]: mismatched types\nafter"
+        );
+    }
+
+    #[test]
+    fn test_extract_without_error() {
+        assert_eq!(extract("all good\ntest result: ok"), None);
+    }
+
+    #[test]
+    fn test_extract_is_bounded() {
+        let mut log = "error: oops\n".to_owned();
+        for i in 0..100 {
+            log.push_str(&format!("line {}\n", i));
+        }
+        let excerpt = extract(&log).unwrap();
+        assert_eq!(excerpt.lines().count(), MAX_LINES);
+        assert!(excerpt.starts_with("error: oops"));
+    }
+
+    #[test]
+    fn test_fetcher_urls() {
+        let sources = LogSources::default();
+        assert_eq!(
+            log_url("https://github.com/rust-lang/rust/runs/123", &sources).unwrap(),
+            "https://api.github.com/repos/rust-lang/rust/actions/jobs/123/logs"
+        );
+        assert_eq!(
+            log_url(
+                "https://github.com/rust-lang/rust/actions/runs/1/job/456",
+                &sources
+            )
+            .unwrap(),
+            "https://api.github.com/repos/rust-lang/rust/actions/jobs/456/logs"
+        );
+        assert_eq!(
+            log_url(
+                "https://dev.azure.com/rust-lang/rust/_build/results?buildId=789",
+                &sources
+            )
+            .unwrap(),
+            "https://dev.azure.com/rust-lang/rust/_apis/build/builds/789/timeline?api-version=5.0"
+        );
+        assert_eq!(
+            log_url("https://travis-ci.com/rust-lang/rust/jobs/42", &sources).unwrap(),
+            "https://api.travis-ci.com/v3/job/42/log.txt"
+        );
+        assert_eq!(log_url("https://github.com/rust-lang/rust/pull/1", &sources), None);
+        assert_eq!(log_url("https://travis-ci.org/rust-lang/rust/builds/1", &sources), None);
+    }
+
+    #[test]
+    fn test_plain_text_hosts() {
+        let sources = LogSources {
+            plain_text_hosts: vec!["logs.example.org".to_owned(), "127.0.0.1:8000".to_owned()],
+            base_url: None,
+        };
+        assert_eq!(
+            log_url("https://logs.example.org/job/1.log", &sources).unwrap(),
+            "https://logs.example.org/job/1.log"
+        );
+        assert_eq!(
+            log_url("http://127.0.0.1:8000/job/1.txt", &sources).unwrap(),
+            "http://127.0.0.1:8000/job/1.txt"
+        );
+        assert_eq!(log_url("http://127.0.0.1:9000/job/1.txt", &sources), None);
+        assert_eq!(log_url("http://169.254.169.254/latest/meta-data.txt", &sources), None);
+        assert_eq!(log_url("https://logs.example.org/job/1", &sources), None);
+        assert_eq!(
+            log_url("https://logs.example.org/job/1.log", &LogSources::default()),
+            None
+        );
+    }
+
+    #[test]
+    fn test_rebase() {
+        let url = Url::parse("https://api.travis-ci.com/v3/job/42/log.txt?a=1").unwrap();
+        let base_url = Url::parse("http://127.0.0.1:8000/stand-in/").unwrap();
+        assert_eq!(
+            rebase(&url, &base_url).as_str(),
+            "http://127.0.0.1:8000/stand-in/v3/job/42/log.txt?a=1"
+        );
+    }
+
+    #[test]
+    fn test_fetch_from_base_url() {
+        // A stand-in log server, which answers a single request and returns its head.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}/logs/", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut head = Vec::new();
+            let mut buf = [0; 1024];
+            while !head.ends_with(b"\r\n\r\n") {
+                let len = stream.read(&mut buf).unwrap();
+                assert_ne!(len, 0, "connection closed before the request head ends");
+                head.extend_from_slice(&buf[..len]);
+            }
+            let response = "HTTP/1.1 200 OK\r\nContent-Length: 12\r\nConnection: close\r\n\r\n";
+            stream.write_all(response.as_bytes()).unwrap();
+            stream.write_all(b"error: oops\n").unwrap();
+            String::from_utf8(head).unwrap()
+        });
+
+        let url = Url::parse("https://github.com/rust-lang/rust/runs/123").unwrap();
+        let log_url = GitHubActions.log_url(&url, &LogSources::default()).unwrap();
+        let base_url = Url::parse(&base_url).unwrap();
+        let fetch = GitHubActions.fetch(&Client::new(), "secret-token", log_url, Some(&base_url));
+        let log = Core::new().unwrap().run(fetch).unwrap();
+        assert_eq!(log, "error: oops\n");
+
+        let head = server.join().unwrap();
+        assert!(
+            head.starts_with("GET /logs/repos/rust-lang/rust/actions/jobs/123/logs HTTP/1.1\r\n"),
+            "{}",
+            head
+        );
+        assert!(!head.to_ascii_lowercase().contains("authorization"), "{}", head);
+        assert!(!head.contains("secret-token"), "{}", head);
+    }
+}
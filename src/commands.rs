@@ -0,0 +1,339 @@
+//! Bors/Homu command history extracted from PR comments.
+//!
+//! The merge queue only tells the current approver of a PR. The whole history, e.g. who approved
+//! which commit and how many times the PR was retried, is reconstructed from the `@bors` commands
+//! in the comments and reviews of the PR timeline, and from the replies of the bot itself. The
+//! name of the bot is configured for each repository.
+
+use chrono::{DateTime, Utc};
+use regex::Regex;
+use timeline::graphql::{Comment, Event, KnownEvent};
+
+/// What a command or a bot reply does.
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Action {
+    /// `r+` or `r=«approver»`, optionally at a specific commit.
+    Approve {
+        /// The approver if given as `r=«approver»`, otherwise the commenter.
+        approver: Option<String>,
+        /// The commit approved, if given.
+        commit: Option<String>,
+    },
+    /// `r-`.
+    Unapprove,
+    /// `p=«priority»`.
+    Priority {
+        /// The new priority.
+        priority: i32,
+    },
+    /// `rollup`, `rollup=«mode»` or `rollup-`.
+    Rollup {
+        /// The rollup mode, `always`, `maybe`, `iffy` or `never`.
+        mode: String,
+    },
+    /// `delegate+` or `delegate=«user»`.
+    Delegate {
+        /// The delegated user if given, otherwise the PR author.
+        to: Option<String>,
+    },
+    /// `delegate-`.
+    Undelegate,
+    /// `retry`.
+    Retry,
+    /// `try`.
+    Try,
+    /// `treeclosed=«priority»`.
+    TreeClosed {
+        /// PRs below this priority are not tested.
+        priority: i32,
+    },
+    /// `treeclosed-`.
+    TreeOpened,
+    /// The bot replied that a commit has been approved.
+    Approved {
+        /// The commit approved.
+        commit: String,
+        /// The approver.
+        approver: String,
+    },
+    /// The bot replied that it started testing a commit.
+    Testing {
+        /// The commit being tested.
+        commit: String,
+    },
+    /// The bot replied that the test has passed.
+    TestSucceeded,
+    /// The bot replied that the test has failed.
+    TestFailed,
+}
+
+/// A command or a bot reply found in the timeline.
+#[derive(Serialize)]
+pub struct Command {
+    /// Who issued the command, or the bot for its replies.
+    pub actor: String,
+    /// When the comment was posted.
+    pub time: DateTime<Utc>,
+    /// Link to the comment.
+    pub url: String,
+    /// What the command does.
+    pub action: Action,
+}
+
+/// Counts of the interesting commands and replies in the history.
+#[derive(Serialize, Default)]
+pub struct Summary {
+    /// Number of times the bot acknowledged an approval.
+    pub approvals: usize,
+    /// Number of `retry` commands.
+    pub retries: usize,
+    /// Number of failed tests.
+    pub failures: usize,
+}
+
+impl Summary {
+    /// Counts the commands and replies in the history.
+    pub fn new(commands: &[Command]) -> Self {
+        let mut summary = Self::default();
+        for command in commands {
+            match command.action {
+                Action::Approved { .. } => summary.approvals += 1,
+                Action::Retry => summary.retries += 1,
+                Action::TestFailed => summary.failures += 1,
+                _ => {}
+            }
+        }
+        summary
+    }
+}
+
+lazy_static! {
+    /// The bot reply after a PR is approved.
+    static ref APPROVED_RE: Regex =
+        Regex::new(r":pushpin: Commit ([0-9a-f]+) has been approved by `([^`]+)`")
+            .expect("approved reply regex");
+    /// The bot reply when testing starts.
+    static ref TESTING_RE: Regex =
+        Regex::new(r":hourglass: Testing commit ([0-9a-f]+)").expect("testing reply regex");
+}
+
+/// Extracts the commands and bot replies from the timeline events, in the order of the events.
+/// `bot_name` is the login of the bot account, which is also the name mentioned in the commands.
+pub fn extract(events: &[Event], bot_name: &str) -> Vec<Command> {
+    let mut commands = Vec::new();
+    for event in events {
+        let comment = match *event {
+            Event::Known(KnownEvent::IssueComment(ref comment)) => comment,
+            Event::Known(KnownEvent::PullRequestReview(ref review)) => &review.comment,
+            _ => continue,
+        };
+        let actor = match comment.author {
+            Some(ref author) => &author.login,
+            None => continue,
+        };
+        let actions = if actor == bot_name {
+            parse_reply(&comment.body).into_iter().collect()
+        } else {
+            parse_commands(&comment.body, bot_name)
+        };
+        commands.extend(actions.into_iter().map(|action| command(comment, actor, action)));
+    }
+    commands
+}
+
+/// Creates a command found in a comment.
+fn command(comment: &Comment, actor: &str, action: Action) -> Command {
+    Command {
+        actor: actor.to_owned(),
+        time: comment.created_at,
+        url: comment.url.clone(),
+        action,
+    }
+}
+
+/// Recognizes a reply of the bot.
+fn parse_reply(body: &str) -> Option<Action> {
+    if let Some(captures) = APPROVED_RE.captures(body) {
+        Some(Action::Approved {
+            commit: captures[1].to_owned(),
+            approver: captures[2].to_owned(),
+        })
+    } else if let Some(captures) = TESTING_RE.captures(body) {
+        Some(Action::Testing {
+            commit: captures[1].to_owned(),
+        })
+    } else if body.contains(":sunny: Test successful") {
+        Some(Action::TestSucceeded)
+    } else if body.contains(":broken_heart: Test failed") {
+        Some(Action::TestFailed)
+    } else {
+        None
+    }
+}
+
+/// Parses the commands in a comment. Like Homu, the words after `@«bot_name»` on the same line are
+/// commands, and unrecognized words are skipped.
+fn parse_commands(body: &str, bot_name: &str) -> Vec<Action> {
+    let mention = format!("@{}", bot_name);
+    let mut actions = Vec::new();
+    // Quoted lines are not commands.
+    for line in body.lines().filter(|line| !line.trim_start().starts_with('>')) {
+        let mut words = line.split_whitespace().skip_while(|w| *w != mention).skip(1).peekable();
+        while let Some(word) = words.next() {
+            let (name, arg) = match word.find(|c| c == '=' || c == '+' || c == '-') {
+                Some(i) => (&word[..i], &word[i..]),
+                None => (word, ""),
+            };
+            let action = match (name, arg) {
+                ("r", "+") | ("r", "=me") => Action::Approve {
+                    approver: None,
+                    commit: None,
+                },
+                ("r", "-") => Action::Unapprove,
+                ("r", _) if arg.starts_with('=') => Action::Approve {
+                    approver: Some(arg[1..].to_owned()),
+                    commit: None,
+                },
+                ("p", _) | ("priority", _) if arg.starts_with('=') => match arg[1..].parse() {
+                    Ok(priority) => Action::Priority { priority },
+                    Err(_) => continue,
+                },
+                ("rollup", "") => Action::Rollup {
+                    mode: "always".to_owned(),
+                },
+                ("rollup", "-") => Action::Rollup {
+                    mode: "maybe".to_owned(),
+                },
+                ("rollup", _) if arg.starts_with('=') => Action::Rollup {
+                    mode: arg[1..].to_owned(),
+                },
+                ("delegate", "+") => Action::Delegate { to: None },
+                ("delegate", "-") => Action::Undelegate,
+                ("delegate", _) if arg.starts_with('=') => Action::Delegate {
+                    to: Some(arg[1..].to_owned()),
+                },
+                ("retry", "") => Action::Retry,
+                ("try", "") => Action::Try,
+                ("treeclosed", "-") => Action::TreeOpened,
+                ("treeclosed", _) if arg.starts_with('=') => match arg[1..].parse() {
+                    Ok(priority) => Action::TreeClosed { priority },
+                    Err(_) => continue,
+                },
+                _ => continue,
+            };
+            let action = match action {
+                // An approval may be followed by the commit hash to approve.
+                Action::Approve { approver, .. } => {
+                    let commit = words
+                        .peek()
+                        .filter(|w| w.len() >= 7 && w.chars().all(|c| c.is_ascii_hexdigit()))
+                        .map(|w| (*w).to_owned());
+                    if commit.is_some() {
+                        words.next();
+                    }
+                    Action::Approve { approver, commit }
+                }
+                action => action,
+            };
+            actions.push(action);
+        }
+    }
+    actions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_commands, Action};
+
+    #[test]
+    fn test_approve_commit() {
+        assert_eq!(
+            parse_commands("@bors r+ 0123abcd", "bors"),
+            vec![Action::Approve {
+                approver: None,
+                commit: Some("0123abcd".to_owned()),
+            }]
+        );
+        // Too short to be a commit hash.
+        assert_eq!(
+            parse_commands("@bors r+ abc", "bors"),
+            vec![Action::Approve {
+                approver: None,
+                commit: None,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_approve_as() {
+        assert_eq!(
+            parse_commands("@bors r=me", "bors"),
+            vec![Action::Approve {
+                approver: None,
+                commit: None,
+            }]
+        );
+        assert_eq!(
+            parse_commands("@bors r=alice", "bors"),
+            vec![Action::Approve {
+                approver: Some("alice".to_owned()),
+                commit: None,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_rollup() {
+        assert_eq!(
+            parse_commands("@bors rollup-", "bors"),
+            vec![Action::Rollup {
+                mode: "maybe".to_owned(),
+            }]
+        );
+        assert_eq!(
+            parse_commands("@bors rollup=never", "bors"),
+            vec![Action::Rollup {
+                mode: "never".to_owned(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_quoted_lines() {
+        assert_eq!(parse_commands("> @bors r+\nThanks!", "bors"), vec![]);
+        assert_eq!(parse_commands("  > @bors retry\n@bors try", "bors"), vec![Action::Try]);
+    }
+
+    #[test]
+    fn test_multiple_commands() {
+        assert_eq!(
+            parse_commands("LGTM @bors r+ p=1 rollup=never", "bors"),
+            vec![
+                Action::Approve {
+                    approver: None,
+                    commit: None,
+                },
+                Action::Priority { priority: 1 },
+                Action::Rollup {
+                    mode: "never".to_owned(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unknown_words() {
+        assert_eq!(
+            parse_commands("@bors please retry p=high thanks", "bors"),
+            vec![Action::Retry]
+        );
+        assert_eq!(parse_commands("r+ retry", "bors"), vec![]);
+    }
+
+    #[test]
+    fn test_bot_name() {
+        assert_eq!(parse_commands("@bors retry", "homu"), vec![]);
+        assert_eq!(parse_commands("@homu retry", "homu"), vec![Action::Retry]);
+    }
+}
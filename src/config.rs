@@ -0,0 +1,621 @@
+//! Configuration file and settings resolution.
+
+use args::Args;
+use ci_log::LogSources;
+use failure::{err_msg, Error, ResultExt};
+use notify::Subscription;
+use queue::Backend;
+use reqwest::Url;
+use serde::de::Error as DeError;
+use serde::{Deserialize, Deserializer, Serializer};
+use spurious::{self, Signature};
+use std::env;
+use std::fmt;
+use std::fs::read_to_string;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::str::FromStr;
+use toml;
+
+/// Default owner of the GitHub repository.
+const DEFAULT_OWNER: &str = "rust-lang";
+
+/// Default name of the GitHub repository.
+const DEFAULT_REPOSITORY: &str = "rust";
+
+/// Default URL to access the Homu queue.
+const DEFAULT_HOMU_URL: &str = "https://buildbot2.rust-lang.org/homu/queue/rust";
+
+/// Default login of the bot account maintaining the merge queue.
+const DEFAULT_BOT_NAME: &str = "bors";
+
+/// Default client ID of the Homu GitHub OAuth App.
+const DEFAULT_HOMU_CLIENT_ID: &str = "f828d548f928f1e11199";
+
+/// Default socket address of the local web server.
+const DEFAULT_ADDRESS: &str = "127.0.0.1:55727";
+
+/// Default directory to find Tera templates and static resources.
+const DEFAULT_TEMPLATES: &str = "res";
+
+/// Default number of seconds between each background refresh of the PR list.
+const DEFAULT_REFRESH_INTERVAL: u64 = 120;
+
+/// Default number of seconds between each full sync of the PR list.
+const DEFAULT_FULL_SYNC_INTERVAL: u64 = 1800;
+
+/// Default number of GitHub rate limit points to keep for the background refresh.
+const DEFAULT_RATE_LIMIT_RESERVE: u32 = 500;
+
+/// The settings of the borsholder server.
+///
+/// This is merged from the command line arguments, environment variables, the configuration file
+/// and the default values, in decreasing order of precedence.
+#[derive(Debug, Serialize)]
+pub struct Config {
+    /// The token to access the GitHub APIs.
+    #[serde(skip_serializing)]
+    pub token: String,
+
+    /// All repositories to show.
+    #[serde(skip_serializing)]
+    pub repos: Vec<Repository>,
+
+    /// Client ID of the Homu GitHub OAuth App.
+    pub homu_client_id: String,
+
+    /// Socket address of the local web server.
+    #[serde(skip_serializing)]
+    pub address: SocketAddr,
+
+    /// Directory to find Tera templates and static resources
+    #[serde(skip_serializing)]
+    pub templates: PathBuf,
+
+    /// Number of seconds between each background refresh of the PR list.
+    #[serde(skip_serializing)]
+    pub refresh_interval: u64,
+
+    /// Number of seconds between each full sync of the PR list. Other refreshes only fetch the PRs
+    /// updated since the last sync, and miss changes of CI status and mergeability. Unless a
+    /// webhook delivery triggers a full sync earlier, the notifications of these changes are
+    /// delayed by up to this interval.
+    #[serde(skip_serializing)]
+    pub full_sync_interval: u64,
+
+    /// Number of GitHub rate limit points to keep for the background refresh. Timelines are not
+    /// fetched when fewer points remain, nor are the check runs which do not fit in the PR list
+    /// query.
+    #[serde(skip_serializing)]
+    pub rate_limit_reserve: u32,
+
+    /// Client ID and secret of the GitHub OAuth App for visitors to log in. If `None`, logging in
+    /// is disabled, and every query uses `token`.
+    #[serde(skip_serializing)]
+    pub oauth: Option<(String, String)>,
+
+    /// Secret shared with GitHub to verify the webhook deliveries. If `None`, all deliveries are
+    /// rejected.
+    #[serde(skip_serializing)]
+    pub webhook_secret: Option<String>,
+
+    /// Known CI failure signatures, read from the file given in the arguments or the
+    /// configuration file.
+    #[serde(skip_serializing)]
+    pub failure_signatures: Rc<Vec<Signature>>,
+
+    /// Where CI logs may be fetched from, besides the supported CI services.
+    #[serde(skip_serializing)]
+    pub log_sources: LogSources,
+
+    /// HTTP(S) proxy server. If not `None`, all API requests will pass through this URL.
+    #[serde(skip_serializing)]
+    pub proxy: Option<Url>,
+
+    /// Directory to persist the responses from GitHub and the merge queue across restarts. If
+    /// `None`, responses are only cached in memory.
+    #[serde(skip_serializing)]
+    pub cache_dir: Option<PathBuf>,
+
+    /// Webhooks to notify when watched PRs change state.
+    #[serde(skip_serializing)]
+    pub subscriptions: Vec<Subscription>,
+}
+
+/// Content of the TOML configuration file.
+///
+/// The fields have the same meaning as the corresponding command line arguments.
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    /// The token to access the GitHub APIs.
+    token: Option<String>,
+    /// Path to a file containing the token.
+    token_file: Option<PathBuf>,
+    /// Name of the environment variable containing the token.
+    token_env: Option<String>,
+    /// Owner of the GitHub repository.
+    owner: Option<String>,
+    /// Name of the GitHub repository.
+    repository: Option<String>,
+    /// URL to access the Homu queue.
+    #[serde(default, deserialize_with = "deserialize_optional_url")]
+    homu_queue_url: Option<Url>,
+    /// Kind of bot maintaining the merge queue at `homu_queue_url`.
+    queue_backend: Option<Backend>,
+    /// Login of the bot account maintaining the merge queue at `homu_queue_url`.
+    bot_name: Option<String>,
+    /// All repositories to show, given as an array of tables.
+    #[serde(default, rename = "repo")]
+    repos: Vec<Repository>,
+    /// Client ID of the Homu GitHub OAuth App.
+    homu_client_id: Option<String>,
+    /// Socket address of the local web server.
+    listen: Option<SocketAddr>,
+    /// Directory to find Tera templates and static resources.
+    templates: Option<PathBuf>,
+    /// Number of seconds between each background refresh of the PR list.
+    refresh_interval: Option<u64>,
+    /// Number of seconds between each full sync of the PR list.
+    full_sync_interval: Option<u64>,
+    /// Number of GitHub rate limit points to keep for the background refresh.
+    rate_limit_reserve: Option<u32>,
+    /// Client ID of the GitHub OAuth App for visitors to log in.
+    oauth_client_id: Option<String>,
+    /// Client secret of the GitHub OAuth App for visitors to log in.
+    oauth_client_secret: Option<String>,
+    /// Secret shared with GitHub to verify the webhook deliveries.
+    webhook_secret: Option<String>,
+    /// Path to the TOML database of known CI failure signatures.
+    failure_signatures: Option<PathBuf>,
+    /// Hosts serving plain text CI logs.
+    #[serde(default)]
+    ci_log_hosts: Vec<String>,
+    /// Base URL to fetch every CI log from instead, e.g. a local stand-in log server.
+    #[serde(default, deserialize_with = "deserialize_optional_url")]
+    ci_log_base_url: Option<Url>,
+    /// HTTP(S) proxy server.
+    #[serde(default, deserialize_with = "deserialize_optional_url")]
+    proxy: Option<Url>,
+    /// Directory to persist the responses across restarts.
+    cache_dir: Option<PathBuf>,
+    /// Webhooks to notify when watched PRs change state, given as an array of tables.
+    #[serde(default, rename = "notify")]
+    subscriptions: Vec<Subscription>,
+}
+
+/// A GitHub repository together with its merge queue.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Repository {
+    /// Owner of the GitHub repository.
+    pub owner: String,
+    /// Name of the GitHub repository.
+    pub name: String,
+    /// URL to access the merge queue.
+    #[serde(serialize_with = "serialize_url", deserialize_with = "deserialize_url")]
+    pub queue_url: Url,
+    /// Kind of bot maintaining the merge queue.
+    #[serde(default)]
+    pub backend: Backend,
+    /// Login of the bot account maintaining the merge queue, which is also the name mentioned in
+    /// the commands.
+    #[serde(default = "default_bot_name")]
+    pub bot_name: String,
+}
+
+/// The default value of `Repository::bot_name`.
+fn default_bot_name() -> String {
+    DEFAULT_BOT_NAME.to_owned()
+}
+
+impl FromStr for Repository {
+    type Err = Error;
+
+    /// Parses the `owner/name=[backend:]queue_url` syntax.
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let mut parts = s.splitn(2, '=');
+        let full_name = parts.next().unwrap_or("");
+        let mut queue_url = parts
+            .next()
+            .ok_or_else(|| err_msg("missing `=queue_url` in repository"))?;
+        let mut backend = Backend::Homu;
+        for &(prefix, prefix_backend) in &[("homu:", Backend::Homu), ("bors:", Backend::Bors)] {
+            if queue_url.starts_with(prefix) {
+                queue_url = &queue_url[prefix.len()..];
+                backend = prefix_backend;
+            }
+        }
+        let queue_url = queue_url.parse()?;
+        let mut names = full_name.splitn(2, '/');
+        match (names.next(), names.next()) {
+            (Some(owner), Some(name)) if !owner.is_empty() && !name.is_empty() => Ok(Self {
+                owner: owner.to_owned(),
+                name: name.to_owned(),
+                queue_url,
+                backend,
+                bot_name: default_bot_name(),
+            }),
+            _ => Err(err_msg("repository should be in the form `owner/name`")),
+        }
+    }
+}
+
+impl fmt::Display for Repository {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.owner, self.name)
+    }
+}
+
+impl Config {
+    /// Builds the settings from the command line arguments.
+    ///
+    /// If a configuration file is specified in the arguments, it will be read again every time
+    /// this method is called.
+    pub fn load(args: &Args) -> Result<Self, Error> {
+        let file = match args.config {
+            Some(ref path) => read_config_file(path)?,
+            None => FileConfig::default(),
+        };
+
+        // Replaying recorded responses does not talk to GitHub, so the token is optional.
+        let token = if args.replay.is_some() {
+            resolve_token(args, &file).unwrap_or_default()
+        } else {
+            resolve_token(args, &file)?
+        };
+
+        let has_single_repo_args = args.owner.is_some()
+            || args.repository.is_some()
+            || args.homu_url.is_some()
+            || args.backend.is_some();
+        let repos = if !args.repos.is_empty() {
+            args.repos.clone()
+        } else if !file.repos.is_empty() && !has_single_repo_args {
+            file.repos
+        } else {
+            let queue_url = match args.homu_url.clone().or(file.homu_queue_url) {
+                Some(url) => url,
+                None => DEFAULT_HOMU_URL.parse()?,
+            };
+            vec![Repository {
+                owner: args
+                    .owner
+                    .clone()
+                    .or(file.owner)
+                    .unwrap_or_else(|| DEFAULT_OWNER.to_owned()),
+                name: args
+                    .repository
+                    .clone()
+                    .or(file.repository)
+                    .unwrap_or_else(|| DEFAULT_REPOSITORY.to_owned()),
+                queue_url,
+                backend: args.backend.or(file.queue_backend).unwrap_or_default(),
+                bot_name: file.bot_name.unwrap_or_else(default_bot_name),
+            }]
+        };
+
+        let address = match args.address.or(port_from_env()?).or(file.listen) {
+            Some(address) => address,
+            None => DEFAULT_ADDRESS.parse()?,
+        };
+
+        let oauth = match (
+            args.oauth_client_id.clone().or(file.oauth_client_id),
+            args.oauth_client_secret.clone().or(file.oauth_client_secret),
+        ) {
+            (Some(id), Some(secret)) => Some((id, secret)),
+            (None, None) => None,
+            _ => {
+                return Err(err_msg(
+                    "both the OAuth client ID and secret are needed to enable logging in",
+                ))
+            }
+        };
+
+        let failure_signatures = match args
+            .failure_signatures
+            .as_ref()
+            .or(file.failure_signatures.as_ref())
+        {
+            Some(path) => spurious::load(path)?,
+            None => Vec::new(),
+        };
+
+        let refresh_interval = args
+            .refresh_interval
+            .or(file.refresh_interval)
+            .unwrap_or(DEFAULT_REFRESH_INTERVAL);
+        if refresh_interval == 0 {
+            return Err(err_msg("the refresh interval must be at least 1 second"));
+        }
+
+        Ok(Self {
+            token,
+            repos,
+            homu_client_id: args
+                .homu_client_id
+                .clone()
+                .or(file.homu_client_id)
+                .unwrap_or_else(|| DEFAULT_HOMU_CLIENT_ID.to_owned()),
+            address,
+            templates: args
+                .templates
+                .clone()
+                .or(file.templates)
+                .unwrap_or_else(|| DEFAULT_TEMPLATES.into()),
+            refresh_interval,
+            full_sync_interval: args
+                .full_sync_interval
+                .or(file.full_sync_interval)
+                .unwrap_or(DEFAULT_FULL_SYNC_INTERVAL),
+            rate_limit_reserve: args
+                .rate_limit_reserve
+                .or(file.rate_limit_reserve)
+                .unwrap_or(DEFAULT_RATE_LIMIT_RESERVE),
+            oauth,
+            webhook_secret: args.webhook_secret.clone().or(file.webhook_secret),
+            failure_signatures: Rc::new(failure_signatures),
+            log_sources: LogSources {
+                plain_text_hosts: file.ci_log_hosts,
+                base_url: file.ci_log_base_url,
+            },
+            proxy: args.proxy.clone().or(file.proxy),
+            cache_dir: args.cache_dir.clone().or(file.cache_dir),
+            subscriptions: file.subscriptions,
+        })
+    }
+}
+
+/// Reads and parses the TOML configuration file.
+fn read_config_file(path: &Path) -> Result<FileConfig, Error> {
+    let content = read_to_string(path)
+        .with_context(|_| format!("cannot read configuration file {}", path.display()))?;
+    let file = toml::from_str(&content)
+        .with_context(|_| format!("invalid configuration file {}", path.display()))?;
+    Ok(file)
+}
+
+/// Finds the GitHub token from the arguments, the environment or the configuration file.
+fn resolve_token(args: &Args, file: &FileConfig) -> Result<String, Error> {
+    if let Some(ref token) = args.token {
+        return Ok(token.clone());
+    }
+    if let Some(ref path) = args.token_file {
+        return read_token_file(path);
+    }
+    if let Ok(token) = env::var("GITHUB_TOKEN") {
+        return Ok(token);
+    }
+    if let Some(ref name) = file.token_env {
+        if let Ok(token) = env::var(name) {
+            return Ok(token);
+        }
+    }
+    if let Some(ref path) = file.token_file {
+        return read_token_file(path);
+    }
+    file.token.clone().ok_or_else(|| {
+        err_msg("missing GitHub token, please specify it via --token, --token-file, $GITHUB_TOKEN or the configuration file")
+    })
+}
+
+/// Reads the GitHub token from a file.
+fn read_token_file(path: &Path) -> Result<String, Error> {
+    let content = read_to_string(path)
+        .with_context(|_| format!("cannot read token file {}", path.display()))?;
+    Ok(content.trim().to_owned())
+}
+
+/// Obtains the socket address to listen on from the `PORT` environment variable.
+///
+/// This is the convention used by Heroku and similar platforms.
+fn port_from_env() -> Result<Option<SocketAddr>, Error> {
+    match env::var("PORT") {
+        Ok(port) => {
+            let port = port.parse::<u16>().context("invalid $PORT")?;
+            Ok(Some(([0, 0, 0, 0], port).into()))
+        }
+        Err(_) => Ok(None),
+    }
+}
+
+/// Serializes a URL using serde.
+fn serialize_url<S: Serializer>(url: &Url, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(url.as_str())
+}
+
+/// Deserializes a URL using serde.
+pub(super) fn deserialize_url<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Url, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    s.parse().map_err(D::Error::custom)
+}
+
+/// Deserializes a URL using serde, for a field which may be missing.
+fn deserialize_optional_url<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Url>, D::Error> {
+    deserialize_url(deserializer).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Config, Repository, DEFAULT_ADDRESS, DEFAULT_REFRESH_INTERVAL};
+    use args::Args;
+    use queue::Backend;
+    use std::env;
+    use std::fs::{remove_file, write};
+    use std::path::PathBuf;
+    use std::process;
+    use std::sync::Mutex;
+    use structopt::StructOpt;
+
+    lazy_static! {
+        /// Serializes the tests reading or changing the environment variables.
+        static ref ENV_LOCK: Mutex<()> = Mutex::default();
+    }
+
+    /// A configuration file written to the temporary directory, deleted when dropped.
+    struct TempConfig(PathBuf);
+
+    impl TempConfig {
+        fn new(name: &str, content: &str) -> Self {
+            let path = env::temp_dir().join(format!("borsholder-{}-{}.toml", name, process::id()));
+            write(&path, content).unwrap();
+            TempConfig(path)
+        }
+    }
+
+    impl Drop for TempConfig {
+        fn drop(&mut self) {
+            let _ = remove_file(&self.0);
+        }
+    }
+
+    /// Loads the settings from the command line arguments, with `$GITHUB_TOKEN` and `$PORT` set
+    /// to the given values.
+    fn load(args: &[&str], github_token: Option<&str>, port: Option<&str>) -> Config {
+        let _lock = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        for &(name, value) in &[("GITHUB_TOKEN", github_token), ("PORT", port)] {
+            match value {
+                Some(value) => env::set_var(name, value),
+                None => env::remove_var(name),
+            }
+        }
+        let args = Args::from_iter(Some("borsholder").into_iter().chain(args.iter().cloned()));
+        let config = Config::load(&args);
+        env::remove_var("GITHUB_TOKEN");
+        env::remove_var("PORT");
+        config.unwrap()
+    }
+
+    #[test]
+    fn test_load_defaults() {
+        let config = load(&["--token", "cli-token"], None, None);
+        assert_eq!(config.token, "cli-token");
+        assert_eq!(config.address, DEFAULT_ADDRESS.parse().unwrap());
+        assert_eq!(config.refresh_interval, DEFAULT_REFRESH_INTERVAL);
+        assert_eq!(config.repos.len(), 1);
+        assert_eq!(config.repos[0].to_string(), "rust-lang/rust");
+        assert_eq!(config.repos[0].bot_name, "bors");
+    }
+
+    #[test]
+    fn test_load_precedence() {
+        let file = TempConfig::new(
+            "precedence",
+            "token = 'file-token'\n\
+             owner = 'file-owner'\n\
+             bot_name = 'file-bot'\n\
+             listen = '127.0.0.1:1000'\n\
+             refresh_interval = 60\n\
+             full_sync_interval = 600\n",
+        );
+        let path = file.0.to_str().unwrap();
+
+        let config = load(&["-c", path], None, None);
+        assert_eq!(config.token, "file-token");
+        assert_eq!(config.repos[0].to_string(), "file-owner/rust");
+        assert_eq!(config.repos[0].bot_name, "file-bot");
+        assert_eq!(config.address, "127.0.0.1:1000".parse().unwrap());
+        assert_eq!(config.refresh_interval, 60);
+        assert_eq!(config.full_sync_interval, 600);
+
+        let config = load(
+            &["-c", path, "--owner", "cli-owner", "--refresh-interval", "30"],
+            Some("env-token"),
+            Some("8080"),
+        );
+        assert_eq!(config.token, "env-token");
+        assert_eq!(config.repos[0].to_string(), "cli-owner/rust");
+        assert_eq!(config.address, "0.0.0.0:8080".parse().unwrap());
+        assert_eq!(config.refresh_interval, 30);
+        assert_eq!(config.full_sync_interval, 600);
+
+        let config = load(
+            &["-c", path, "-t", "cli-token", "-l", "127.0.0.1:2000"],
+            Some("env-token"),
+            Some("8080"),
+        );
+        assert_eq!(config.token, "cli-token");
+        assert_eq!(config.address, "127.0.0.1:2000".parse().unwrap());
+    }
+
+    #[test]
+    fn test_load_token_sources() {
+        let token_file = TempConfig::new("token-file", "file-token\n");
+        let token_path = token_file.0.to_str().unwrap();
+        let config = load(&["--token-file", token_path], Some("env-token"), None);
+        assert_eq!(config.token, "file-token");
+
+        let file = TempConfig::new(
+            "token-env",
+            &format!(
+                "token = 'config-token'\n\
+                 token_env = 'BORSHOLDER_TEST_TOKEN'\n\
+                 token_file = '{}'\n",
+                token_path
+            ),
+        );
+        let path = file.0.to_str().unwrap();
+        assert_eq!(load(&["-c", path], Some("env-token"), None).token, "env-token");
+        env::set_var("BORSHOLDER_TEST_TOKEN", "named-env-token");
+        assert_eq!(load(&["-c", path], None, None).token, "named-env-token");
+        env::remove_var("BORSHOLDER_TEST_TOKEN");
+        assert_eq!(load(&["-c", path], None, None).token, "file-token");
+
+        let file = TempConfig::new("token-missing", "owner = 'rust-lang'\n");
+        let args = Args::from_iter(&["borsholder", "-c", file.0.to_str().unwrap()]);
+        let _lock = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        env::remove_var("GITHUB_TOKEN");
+        assert!(Config::load(&args).is_err());
+    }
+
+    #[test]
+    fn test_load_rejects_zero_refresh_interval() {
+        let args = Args::from_iter(&["borsholder", "-t", "token", "--refresh-interval", "0"]);
+        assert!(Config::load(&args).is_err());
+    }
+
+    #[test]
+    fn test_load_rejects_invalid_port() {
+        let args = Args::from_iter(&["borsholder", "-t", "token"]);
+        let _lock = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        env::set_var("PORT", "http");
+        let config = Config::load(&args);
+        env::remove_var("PORT");
+        assert!(config.is_err());
+    }
+
+    #[test]
+    fn test_repository_from_str() {
+        let repo = "rust-lang/cargo=https://bors.rust-lang.org/queue/cargo"
+            .parse::<Repository>()
+            .unwrap();
+        assert_eq!(repo.owner, "rust-lang");
+        assert_eq!(repo.name, "cargo");
+        assert_eq!(
+            repo.queue_url.as_str(),
+            "https://bors.rust-lang.org/queue/cargo"
+        );
+        assert_eq!(repo.backend, Backend::Homu);
+        assert_eq!(repo.bot_name, "bors");
+
+        let repo = "rust-lang/rust=bors:https://bors.rust-lang.org/queue/rust"
+            .parse::<Repository>()
+            .unwrap();
+        assert_eq!(repo.backend, Backend::Bors);
+        assert_eq!(repo.queue_url.as_str(), "https://bors.rust-lang.org/queue/rust");
+
+        for invalid in &[
+            "rust-lang/rust",
+            "rust-lang=https://bors.rust-lang.org/",
+            "/rust=https://bors.rust-lang.org/",
+            "rust-lang/=https://bors.rust-lang.org/",
+            "rust-lang/rust=not a url",
+        ] {
+            assert!(invalid.parse::<Repository>().is_err(), "{}", invalid);
+        }
+    }
+}
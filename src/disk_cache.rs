@@ -0,0 +1,94 @@
+//! Persistent on-disk cache of responses from GitHub and the merge queue.
+//!
+//! Each response is stored in its own file. The first line of the file is the time the response
+//! was fetched in RFC 3339 format, and the rest is the raw response body. The cache survives
+//! restarts, so the server can show the last known data immediately while refreshing.
+
+use chrono::{DateTime, Utc};
+use failure::{Error, ResultExt};
+use std::fs::{create_dir_all, read, remove_file, rename, write};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::from_utf8;
+use std::sync::RwLock;
+
+lazy_static! {
+    static ref CACHE_DIR: RwLock<Option<PathBuf>> = RwLock::new(None);
+}
+
+/// Enables the on-disk cache, storing the responses into the directory.
+pub fn open(dir: PathBuf) -> Result<(), Error> {
+    create_dir_all(&dir)
+        .with_context(|_| format!("cannot create cache directory {}", dir.display()))?;
+    *CACHE_DIR.write().expect("poisoned") = Some(dir);
+    Ok(())
+}
+
+/// Reads the cached response at the relative path, together with the time it was fetched.
+///
+/// Returns `None` if the cache is disabled, or the response is missing or unreadable.
+pub fn load(path: &Path) -> Option<(DateTime<Utc>, Vec<u8>)> {
+    let path = CACHE_DIR.read().expect("poisoned").as_ref()?.join(path);
+    let mut content = match read(&path) {
+        Ok(content) => content,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return None,
+        Err(e) => {
+            warn!("cannot read cached response {}: {}", path.display(), e);
+            return None;
+        }
+    };
+    let newline = content.iter().position(|b| *b == b'\n')?;
+    let fetched_at = from_utf8(&content[..newline])
+        .ok()
+        .and_then(|s| s.parse().ok());
+    match fetched_at {
+        Some(fetched_at) => {
+            content.drain(..=newline);
+            debug!("Loaded cached response from {}", path.display());
+            Some((fetched_at, content))
+        }
+        None => {
+            warn!("corrupted cached response {}", path.display());
+            None
+        }
+    }
+}
+
+/// Saves the response to the relative path, fetched just now.
+///
+/// Does nothing if the cache is disabled. Failure to save the response is logged but otherwise
+/// ignored.
+pub fn store(path: &Path, body: &[u8]) {
+    let path = match *CACHE_DIR.read().expect("poisoned") {
+        Some(ref dir) => dir.join(path),
+        None => return,
+    };
+    let mut content = format!("{}\n", Utc::now().to_rfc3339()).into_bytes();
+    content.extend_from_slice(body);
+
+    // Write to a temporary file first, so a crash would never leave a truncated response behind.
+    let temp_path = path.with_extension("tmp");
+    let result = path
+        .parent()
+        .map_or(Ok(()), create_dir_all)
+        .and_then(|()| write(&temp_path, &content))
+        .and_then(|()| rename(&temp_path, &path));
+    if let Err(e) = result {
+        warn!("failed to cache response to {}: {}", path.display(), e);
+    }
+}
+
+/// Deletes the cached response at the relative path, so it will not be used again.
+///
+/// Does nothing if the cache is disabled or the response is missing.
+pub fn remove(path: &Path) {
+    let path = match *CACHE_DIR.read().expect("poisoned") {
+        Some(ref dir) => dir.join(path),
+        None => return,
+    };
+    match remove_file(&path) {
+        Ok(()) => debug!("Removed cached response {}", path.display()),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => warn!("cannot remove cached response {}: {}", path.display(), e),
+    }
+}
@@ -1,16 +1,23 @@
 //! GitHub API access.
 
+use chrono::{DateTime, Utc};
+use disk_cache;
 use failure::Error;
-use futures::future::{Future, IntoFuture};
-use futures::stream::{unfold, Stream};
+use futures::future::{ok, Future, IntoFuture};
+use futures::stream::{iter_ok, unfold, Stream};
 use lru_time_cache::LruCache;
+use metrics::{self, Upstream};
+use recording::{self, sanitize};
 use reqwest::async::{Chunk, Client};
 use reqwest::header::{HeaderMap, ACCEPT, AUTHORIZATION};
 use serde::de::DeserializeOwned;
 use serde::ser::Serialize;
 use serde_json;
+use std::collections::HashMap;
+use std::mem::replace;
+use std::path::PathBuf;
 use std::sync::Mutex;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 /// Types related to the main GraphQL query.
 ///
@@ -23,7 +30,7 @@ pub mod graphql {
     use chrono::{DateTime, Utc};
 
     /// A generic GraphQL connection, which is the same as a vector in our use case.
-    #[derive(Deserialize, Default)]
+    #[derive(Deserialize, Default, Clone)]
     #[serde(rename_all = "camelCase")]
     pub struct Connection<T> {
         /// List of nodes in this connection.
@@ -33,14 +40,40 @@ pub mod graphql {
         pub page_info: PageInfo,
     }
 
+    impl<T> Connection<T> {
+        /// Appends the next page of this connection.
+        pub fn extend(&mut self, page: Self) {
+            self.nodes.extend(page.nodes);
+            self.page_info = page.page_info;
+        }
+    }
+
     /// Paging information about a GraphQL connection.
-    #[derive(Deserialize, Default)]
-    #[serde(rename_all = "camelCase")]
+    ///
+    /// Fields not requested by the query are left as default.
+    #[derive(Deserialize, Default, Clone)]
+    #[serde(rename_all = "camelCase", default)]
     pub struct PageInfo {
-        /// The cursor beyond the end of all data presented in this connection.
-        pub end_cursor: String,
+        /// The cursor beyond the end of all data presented in this connection. `None` if the
+        /// connection is empty.
+        pub end_cursor: Option<String>,
         /// Whether a new page exists.
         pub has_next_page: bool,
+        /// The cursor before the start of all data presented in this connection.
+        pub start_cursor: Option<String>,
+        /// Whether an earlier page exists.
+        pub has_previous_page: bool,
+    }
+
+    impl PageInfo {
+        /// The cursor to load the next page, if a new page exists.
+        pub fn next_cursor(&self) -> Option<String> {
+            if self.has_next_page {
+                self.end_cursor.clone()
+            } else {
+                None
+            }
+        }
     }
 
     /// The reply of a GraphQL query.
@@ -62,13 +95,15 @@ pub mod graphql {
         pub pull_requests: Connection<PullRequest>,
     }
 
-    #[derive(Deserialize)]
+    #[derive(Deserialize, Clone)]
     #[serde(rename_all = "camelCase")]
     pub struct PullRequest {
         pub author: Actor,
         pub created_at: DateTime<Utc>,
         pub updated_at: DateTime<Utc>,
         pub mergeable: MergeableState,
+        pub state: PullRequestState,
+        pub is_draft: bool,
         pub number: u32,
         pub title: String,
         pub additions: u32,
@@ -77,42 +112,89 @@ pub mod graphql {
         pub head_ref_name: String,
         pub body: String,
         pub labels: Connection<Label>,
+        /// The last few label events. Missing from responses cached by older versions.
+        pub timeline_items: Option<Connection<LabeledEvent>>,
+        pub assignees: Connection<Actor>,
+        pub review_requests: Connection<ReviewRequest>,
+        pub review_decision: Option<PullRequestReviewDecision>,
+        pub latest_reviews: Connection<PullRequestReview>,
         pub commits: Connection<PullRequestCommit>,
     }
 
-    #[derive(Deserialize)]
+    #[derive(Deserialize, Clone)]
     #[serde(rename_all = "camelCase")]
     pub struct Actor {
         pub login: String,
     }
 
-    #[derive(Deserialize, Serialize)]
+    #[derive(Deserialize, Serialize, Clone)]
     #[serde(rename_all = "camelCase")]
     pub struct Label {
         pub name: String,
         pub color: String,
+        /// When the label was last applied, if found among the last few label events.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub applied_at: Option<DateTime<Utc>>,
     }
 
-    #[derive(Deserialize)]
+    #[derive(Deserialize, Clone)]
+    #[serde(rename_all = "camelCase")]
+    pub struct LabeledEvent {
+        pub created_at: DateTime<Utc>,
+        pub label: LabelName,
+    }
+
+    #[derive(Deserialize, Clone)]
+    pub struct LabelName {
+        pub name: String,
+    }
+
+    #[derive(Deserialize, Clone)]
+    #[serde(rename_all = "camelCase")]
+    pub struct ReviewRequest {
+        pub requested_reviewer: Option<RequestedReviewer>,
+    }
+
+    /// The user or team requested to review. Users have a login, and teams have a combined slug
+    /// like `rust-lang/compiler`.
+    #[derive(Deserialize, Clone)]
+    #[serde(rename_all = "camelCase")]
+    pub struct RequestedReviewer {
+        pub login: Option<String>,
+        pub combined_slug: Option<String>,
+    }
+
+    #[derive(Deserialize, Clone)]
+    #[serde(rename_all = "camelCase")]
+    pub struct PullRequestReview {
+        pub author: Option<Actor>,
+        pub state: PullRequestReviewState,
+    }
+
+    #[derive(Deserialize, Clone)]
     #[serde(rename_all = "camelCase")]
     pub struct PullRequestCommit {
         pub commit: Commit,
     }
 
-    #[derive(Deserialize)]
+    #[derive(Deserialize, Clone)]
     #[serde(rename_all = "camelCase")]
     pub struct Commit {
+        pub id: String,
+        /// The commit hash. Missing from responses cached by older versions.
+        #[serde(default)]
+        pub oid: String,
         pub status: Option<Status>,
         pub check_suites: Connection<CheckSuite>,
     }
 
-    #[derive(Deserialize)]
+    #[derive(Deserialize, Clone)]
     #[serde(rename_all = "camelCase")]
     pub struct Status {
         pub contexts: Vec<StatusContext>,
     }
 
-    #[derive(Deserialize, Serialize)]
+    #[derive(Deserialize, Serialize, Clone)]
     #[serde(rename_all = "camelCase")]
     pub struct StatusContext {
         pub context: String,
@@ -121,13 +203,14 @@ pub mod graphql {
         pub state: StatusState,
     }
 
-    #[derive(Deserialize)]
+    #[derive(Deserialize, Clone)]
     #[serde(rename_all = "camelCase")]
     pub struct CheckSuite {
+        pub id: String,
         pub check_runs: Connection<CheckRun>,
     }
 
-    #[derive(Deserialize)]
+    #[derive(Deserialize, Clone)]
     #[serde(rename_all = "camelCase")]
     pub struct CheckRun {
         pub name: String,
@@ -144,7 +227,53 @@ pub mod graphql {
         Conflicting,
     }
 
-    #[derive(Deserialize, Serialize, Clone, Copy)]
+    #[derive(Deserialize, Serialize, PartialEq, Eq, Clone, Copy)]
+    #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+    pub enum PullRequestState {
+        Open,
+        Closed,
+        Merged,
+    }
+
+    #[derive(Serialize)]
+    pub struct IssueOrder {
+        pub field: IssueOrderField,
+        pub direction: OrderDirection,
+    }
+
+    #[derive(Serialize, Clone, Copy)]
+    #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+    pub enum IssueOrderField {
+        CreatedAt,
+        UpdatedAt,
+    }
+
+    #[derive(Serialize, Clone, Copy)]
+    #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+    pub enum OrderDirection {
+        Asc,
+        Desc,
+    }
+
+    #[derive(Deserialize, Serialize, PartialEq, Eq, Clone, Copy)]
+    #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+    pub enum PullRequestReviewDecision {
+        ChangesRequested,
+        Approved,
+        ReviewRequired,
+    }
+
+    #[derive(Deserialize, Serialize, PartialEq, Eq, Clone, Copy)]
+    #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+    pub enum PullRequestReviewState {
+        Pending,
+        Commented,
+        Approved,
+        ChangesRequested,
+        Dismissed,
+    }
+
+    #[derive(Deserialize, Serialize, PartialEq, Eq, Clone, Copy)]
     #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
     pub enum StatusState {
         Expected,
@@ -154,34 +283,163 @@ pub mod graphql {
         Success,
     }
 
-    #[derive(Deserialize, Clone, Copy)]
+    #[derive(Deserialize, Serialize, Clone, Copy)]
     #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
     pub enum CheckConclusionState {
         ActionRequired,
         Cancelled,
         Failure,
         Neutral,
+        Skipped,
+        Stale,
+        StartupFailure,
         Success,
         TimedOut,
     }
 
+    /// The reply of the query for the remaining check suites or check runs, `CHECKS_QUERY`.
+    #[derive(Deserialize)]
+    pub struct ChecksReply {
+        pub data: ChecksData,
+    }
+
+    #[derive(Deserialize)]
+    pub struct ChecksData {
+        pub node: ChecksNode,
+    }
+
+    /// A page of check suites of a commit, or a page of check runs of a check suite.
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct ChecksNode {
+        pub check_suites: Option<Connection<CheckSuite>>,
+        pub check_runs: Option<Connection<CheckRun>>,
+    }
+
+    impl ChecksNode {
+        /// The cursor to load the next page, if a new page exists.
+        pub fn next_cursor(&self) -> Option<String> {
+            self.check_suites
+                .as_ref()
+                .map(|suites| &suites.page_info)
+                .or_else(|| self.check_runs.as_ref().map(|runs| &runs.page_info))
+                .and_then(PageInfo::next_cursor)
+        }
+    }
+
     impl From<CheckRun> for StatusContext {
         fn from(run: CheckRun) -> Self {
             Self {
                 context: run.name,
                 description: run.title,
                 target_url: run.permalink,
+                // Keep in sync with `render::CiSummary::new()`. Cancelled and stale runs have no
+                // status state of their own, and become errors.
                 state: match run.conclusion {
                     None => StatusState::Pending,
-                    Some(CheckConclusionState::Failure) => StatusState::Failure,
-                    Some(CheckConclusionState::Success) => StatusState::Success,
-                    Some(_) => StatusState::Error,
+                    Some(CheckConclusionState::Success)
+                    | Some(CheckConclusionState::Neutral)
+                    | Some(CheckConclusionState::Skipped) => StatusState::Success,
+                    Some(CheckConclusionState::ActionRequired)
+                    | Some(CheckConclusionState::Failure)
+                    | Some(CheckConclusionState::StartupFailure)
+                    | Some(CheckConclusionState::TimedOut) => StatusState::Failure,
+                    Some(CheckConclusionState::Cancelled) | Some(CheckConclusionState::Stale) => {
+                        StatusState::Error
+                    }
                 },
             }
         }
     }
 }
 
+/// The GraphQL rate limit status of the token.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug)]
+pub struct RateLimit {
+    /// Maximum number of points which can be spent per hour.
+    pub limit: u32,
+    /// Number of points spent by the last query.
+    pub cost: u32,
+    /// Number of points remaining until `reset_at`.
+    pub remaining: u32,
+    /// When the remaining points will be restored to `limit`.
+    #[serde(rename(deserialize = "resetAt"))]
+    pub reset_at: DateTime<Utc>,
+}
+
+/// Just enough of any GraphQL reply to extract the rate limit status.
+#[derive(Deserialize)]
+struct RateLimitReply {
+    /// The data of the reply, missing if the query failed.
+    data: Option<RateLimitData>,
+}
+
+/// The data part of [`RateLimitReply`].
+///
+/// [`RateLimitReply`]: struct.RateLimitReply.html
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RateLimitData {
+    /// The rate limit status, if requested by the query.
+    rate_limit: Option<RateLimit>,
+}
+
+/// A non-essential GitHub query refused because the rate limit budget is running low.
+///
+/// The server responds to this error with "429 Too Many Requests".
+#[derive(Debug, Clone, Fail)]
+#[fail(
+    display = "GitHub rate limit is running low ({} points left), please retry after {}",
+    remaining,
+    reset_at
+)]
+pub struct BudgetExhausted {
+    /// Number of points remaining.
+    pub remaining: u32,
+    /// When the budget will be restored.
+    pub reset_at: DateTime<Utc>,
+}
+
+lazy_static! {
+    static ref RATE_LIMIT: Mutex<Option<RateLimit>> = Mutex::new(None);
+}
+
+/// Obtains the rate limit status reported by the last GitHub query.
+pub fn rate_limit() -> Option<RateLimit> {
+    *RATE_LIMIT.lock().expect("poisoned")
+}
+
+/// Checks whether a non-essential query may be sent, keeping `reserve` points of the rate limit
+/// budget for essential queries such as the background refresh.
+pub fn check_budget(reserve: u32) -> Result<(), BudgetExhausted> {
+    match rate_limit() {
+        Some(rate_limit) if rate_limit.remaining < reserve && rate_limit.reset_at > Utc::now() => {
+            Err(BudgetExhausted {
+                remaining: rate_limit.remaining,
+                reset_at: rate_limit.reset_at,
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Records the rate limit status from the raw body of a GraphQL reply.
+fn update_rate_limit(body: &[u8]) {
+    let reply = serde_json::from_slice::<RateLimitReply>(body);
+    if let Ok(RateLimitReply {
+        data: Some(RateLimitData {
+            rate_limit: Some(rate_limit),
+        }),
+    }) = reply
+    {
+        info!(
+            "GitHub GraphQL rate limit: cost {}, {}/{} remaining until {}",
+            rate_limit.cost, rate_limit.remaining, rate_limit.limit, rate_limit.reset_at
+        );
+        *RATE_LIMIT.lock().expect("poisoned") = Some(rate_limit);
+    }
+}
+
 /// A GraphQL request.
 #[derive(Serialize)]
 struct Request<'variables> {
@@ -189,6 +447,10 @@ struct Request<'variables> {
     query: &'static str,
     /// Variables of the query.
     variables: Variables<'variables>,
+    /// Whether this request is part of an incremental sync. Not sent to GitHub, but used to keep
+    /// the cached pages of incremental and full syncs apart.
+    #[serde(skip)]
+    incremental: bool,
 }
 
 /// Variables in a GraphQL request.
@@ -202,32 +464,330 @@ struct Variables<'variables> {
     repo: &'variables str,
     /// Only read the content after
     after: Option<&'variables str>,
+    /// Only read pull requests in these states.
+    states: &'variables [graphql::PullRequestState],
+    /// The order of the pull requests.
+    #[serde(rename = "orderBy")]
+    order_by: &'variables graphql::IssueOrder,
 }
 
+/// A GraphQL request for the remaining check suites or check runs, `CHECKS_QUERY`.
+#[derive(Serialize)]
+struct ChecksRequest<'variables> {
+    /// The query string.
+    query: &'static str,
+    /// Variables of the query.
+    variables: ChecksVariables<'variables>,
+    /// Owner of the repository. Not sent to GitHub, but used to store the response.
+    #[serde(skip)]
+    owner: &'variables str,
+    /// Name of the repository. Not sent to GitHub, but used to store the response.
+    #[serde(skip)]
+    repo: &'variables str,
+}
+
+/// Variables in a `CHECKS_QUERY` request.
+#[derive(Serialize)]
+struct ChecksVariables<'variables> {
+    /// Node ID of the commit or the check suite.
+    id: &'variables str,
+    /// Only read the check suites or check runs after this cursor.
+    after: &'variables str,
+}
+
+/// States of the pull requests read during a full sync.
+const FULL_SYNC_STATES: &[graphql::PullRequestState] = &[graphql::PullRequestState::Open];
+
+/// States of the pull requests read during an incremental sync.
+const INCREMENTAL_SYNC_STATES: &[graphql::PullRequestState] = &[
+    graphql::PullRequestState::Open,
+    graphql::PullRequestState::Closed,
+    graphql::PullRequestState::Merged,
+];
+
+/// Order of the pull requests read during a full sync.
+///
+/// The creation time never changes, so pull requests updated while paging through them are neither
+/// skipped nor read twice.
+const FULL_SYNC_ORDER: &graphql::IssueOrder = &graphql::IssueOrder {
+    field: graphql::IssueOrderField::CreatedAt,
+    direction: graphql::OrderDirection::Asc,
+};
+
+/// Order of the pull requests read during an incremental sync.
+///
+/// The latest updated pull requests come first, so paging can stop once it reaches those updated
+/// before the last sync.
+const INCREMENTAL_SYNC_ORDER: &graphql::IssueOrder = &graphql::IssueOrder {
+    field: graphql::IssueOrderField::UpdatedAt,
+    direction: graphql::OrderDirection::Desc,
+};
+
 /// URL to send the GraphQL requests.
 const GITHUB_ENDPOINT: &str = "https://api.github.com/graphql";
 
 /// The main GraphQL query.
 const QUERY: &str = include!("github.gql");
 
+/// The GraphQL query of the check suites and check runs not covered by `QUERY`.
+const CHECKS_QUERY: &str = include!("checks.gql");
+
+/// Maximum number of follow-up queries of check suites or check runs sent at a time, for the pull
+/// requests of a repository or the check suites of a commit.
+const MAX_CONCURRENT_CHECK_QUERIES: usize = 8;
+
 /// The key to look up a cached GitHub request.
 #[derive(PartialEq, Eq, PartialOrd, Ord, Clone)]
 pub(super) enum CacheKey {
     /// The key for fetching the PR list.
-    List(Option<Box<str>>),
+    List {
+        /// Owner of the repository.
+        owner: Box<str>,
+        /// Name of the repository.
+        repo: Box<str>,
+        /// The cursor of the page.
+        after: Option<Box<str>>,
+        /// Whether the page is fetched during an incremental sync.
+        incremental: bool,
+    },
     /// The key for fetching the timeline of a PR.
-    Timeline(u32),
+    Timeline {
+        /// Owner of the repository.
+        owner: Box<str>,
+        /// Name of the repository.
+        repo: Box<str>,
+        /// PR number.
+        number: u32,
+        /// The cursor of the page, `None` for the latest page.
+        before: Option<Box<str>>,
+        /// Whether the page is part of a walk through the whole history, which uses larger pages.
+        history: bool,
+        /// The logged-in visitor whose token fetched the page, `None` for the server token.
+        viewer: Option<Box<str>>,
+    },
+    /// The key for fetching the supplementary timeline events of a PR.
+    TimelineExtras {
+        /// Owner of the repository.
+        owner: Box<str>,
+        /// Name of the repository.
+        repo: Box<str>,
+        /// PR number.
+        number: u32,
+        /// The cursor of the page, `None` for the latest page.
+        before: Option<Box<str>>,
+        /// The logged-in visitor whose token fetched the events, `None` for the server token.
+        viewer: Option<Box<str>>,
+    },
+    /// The key for fetching the remaining check suites of a commit, or check runs of a check
+    /// suite.
+    Checks {
+        /// Owner of the repository.
+        owner: Box<str>,
+        /// Name of the repository.
+        repo: Box<str>,
+        /// Node ID of the commit or the check suite.
+        id: Box<str>,
+        /// The cursor of the page.
+        after: Box<str>,
+    },
+}
+
+impl CacheKey {
+    /// The relative path to store the response of this request, when recording or caching on disk.
+    pub(super) fn storage_path(&self) -> PathBuf {
+        let (owner, repo, file_name) = match *self {
+            CacheKey::List {
+                ref owner,
+                ref repo,
+                ref after,
+                incremental,
+            } => {
+                let page = after.as_ref().map_or_else(|| "first".to_owned(), |a| sanitize(a));
+                let kind = if incremental { "incremental" } else { "list" };
+                (owner, repo, format!("{}-{}.json", kind, page))
+            }
+            CacheKey::Timeline {
+                ref owner,
+                ref repo,
+                number,
+                ref before,
+                history,
+                ref viewer,
+            } => {
+                let kind = if history { "history" } else { "timeline" };
+                let file_name = match *before {
+                    Some(ref before) => format!(
+                        "{}-{}-before-{}{}.json",
+                        kind,
+                        number,
+                        sanitize(before),
+                        viewer_suffix(viewer)
+                    ),
+                    None => format!("{}-{}{}.json", kind, number, viewer_suffix(viewer)),
+                };
+                (owner, repo, file_name)
+            }
+            CacheKey::TimelineExtras {
+                ref owner,
+                ref repo,
+                number,
+                ref before,
+                ref viewer,
+            } => {
+                let file_name = match *before {
+                    Some(ref before) => format!(
+                        "timeline-{}-extras-before-{}{}.json",
+                        number,
+                        sanitize(before),
+                        viewer_suffix(viewer)
+                    ),
+                    None => format!("timeline-{}-extras{}.json", number, viewer_suffix(viewer)),
+                };
+                (owner, repo, file_name)
+            }
+            CacheKey::Checks {
+                ref owner,
+                ref repo,
+                ref id,
+                ref after,
+            } => {
+                let file_name = format!("checks-{}-{}.json", sanitize(id), sanitize(after));
+                (owner, repo, file_name)
+            }
+        };
+        let mut path = PathBuf::from(sanitize(owner));
+        path.push(sanitize(repo));
+        path.push(file_name);
+        path
+    }
+
+    /// Whether the response may be stored in the on-disk cache.
+    ///
+    /// Responses fetched with a visitor's token may contain data only that visitor can see, so
+    /// they are only kept in memory, under a key of their own. Earlier timeline pages can be
+    /// requested with arbitrary cursors, so only the latest pages are stored on disk.
+    fn is_persistent(&self) -> bool {
+        match *self {
+            CacheKey::List { .. } | CacheKey::Checks { .. } => true,
+            CacheKey::Timeline {
+                ref before,
+                ref viewer,
+                ..
+            }
+            | CacheKey::TimelineExtras {
+                ref before,
+                ref viewer,
+                ..
+            } => before.is_none() && viewer.is_none(),
+        }
+    }
+}
+
+/// The suffix of the file name of a response fetched with a visitor's token.
+fn viewer_suffix(viewer: &Option<Box<str>>) -> String {
+    viewer
+        .as_ref()
+        .map_or_else(String::new, |viewer| format!("-as-{}", sanitize(viewer)))
 }
 
 impl<'a, 'v: 'a> From<&'a Request<'v>> for CacheKey {
     fn from(req: &'a Request<'v>) -> Self {
-        CacheKey::List(req.variables.after.map(|v| v.to_owned().into_boxed_str()))
+        CacheKey::List {
+            owner: req.variables.owner.into(),
+            repo: req.variables.repo.into(),
+            after: req.variables.after.map(Into::into),
+            incremental: req.incremental,
+        }
+    }
+}
+
+impl<'a, 'v: 'a> From<&'a ChecksRequest<'v>> for CacheKey {
+    fn from(req: &'a ChecksRequest<'v>) -> Self {
+        CacheKey::Checks {
+            owner: req.owner.into(),
+            repo: req.repo.into(),
+            id: req.variables.id.into(),
+            after: req.variables.after.into(),
+        }
     }
 }
 
+/// Number of seconds a cached response is considered fresh.
+const CACHE_EXPIRY: u64 = 120;
+
 lazy_static! {
     static ref CACHE: Mutex<LruCache<CacheKey, Chunk>> =
-        Mutex::new(LruCache::with_expiry_duration(Duration::from_secs(120)));
+        Mutex::new(LruCache::with_expiry_duration(Duration::from_secs(CACHE_EXPIRY)));
+}
+
+/// Evicts the cached timelines of the PRs `numbers` of a repository, which may be outdated by a
+/// change on GitHub, so they will be fetched again.
+pub fn invalidate(owner: &str, repo: &str, numbers: &[u32]) {
+    evict_timelines(owner, repo, |number| numbers.contains(&number));
+    // Latest timeline pages evicted from memory earlier may still be fresh on disk.
+    for &number in numbers {
+        for &history in &[false, true] {
+            let key = CacheKey::Timeline {
+                owner: owner.into(),
+                repo: repo.into(),
+                number,
+                before: None,
+                history,
+                viewer: None,
+            };
+            disk_cache::remove(&key.storage_path());
+        }
+        let key = CacheKey::TimelineExtras {
+            owner: owner.into(),
+            repo: repo.into(),
+            number,
+            before: None,
+            viewer: None,
+        };
+        disk_cache::remove(&key.storage_path());
+    }
+}
+
+/// Evicts every cached timeline of a repository.
+pub fn flush(owner: &str, repo: &str) {
+    evict_timelines(owner, repo, |_| true);
+}
+
+/// Evicts the cached timelines of a repository whose PR number satisfies the predicate, both in
+/// memory and on disk.
+///
+/// The pages of the PR list and the check runs are left alone. They are never read from the cache
+/// except when restoring the snapshot at startup, which needs every page on disk.
+fn evict_timelines<F: Fn(u32) -> bool>(owner: &str, repo: &str, predicate: F) {
+    ::timeline::evict_extras(owner, repo, &predicate);
+    let mut cache = CACHE.lock().expect("poisoned");
+    let keys = cache
+        .peek_iter()
+        .map(|(key, _)| key)
+        .filter(|key| match **key {
+            CacheKey::Timeline {
+                owner: ref o,
+                repo: ref r,
+                number,
+                ..
+            }
+            | CacheKey::TimelineExtras {
+                owner: ref o,
+                repo: ref r,
+                number,
+                ..
+            } => o.eq_ignore_ascii_case(owner) && r.eq_ignore_ascii_case(repo) && predicate(number),
+            CacheKey::List { .. } | CacheKey::Checks { .. } => false,
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+    info!("Evicting {} cached timelines of {}/{}", keys.len(), owner, repo);
+    for key in keys {
+        cache.remove(&key);
+        if key.is_persistent() {
+            disk_cache::remove(&key.storage_path());
+        }
+    }
 }
 
 /// Pagination status for multi-page results (pull request list).
@@ -259,12 +819,41 @@ impl PaginationState {
 }
 
 /// Obtains the list of open pull requests and associated information from GitHub.
+///
+/// If `since` is given, this performs an incremental sync, which reads the pull requests ordered
+/// by the last update time, latest first, and stops paging once it reaches pull requests last
+/// updated before `since`. An incremental sync also returns the pull requests closed or merged
+/// recently, so they can be removed from the [`PullRequestSet`]. Otherwise, every open pull request
+/// is read in the order of creation.
+///
+/// [`PullRequestSet`]: struct.PullRequestSet.html
+///
+/// GitHub does not bump `updatedAt` when only the CI status or the mergeability of a pull request
+/// changes, so an incremental sync misses these changes. They are picked up by the next full sync.
+///
+/// The main query only reads the first few check suites and check runs of each pull request. The
+/// rest are fetched by follow-up queries, so the CI status of every open pull request is complete.
+/// Pull requests returned as closed or merged are only removed, so they are not followed up. At
+/// most `MAX_CONCURRENT_CHECK_QUERIES` follow-up queries are sent at a time, and they are skipped
+/// once the rate limit budget drops below `reserve`, leaving the CI status incomplete.
+///
+/// The cached responses are ignored and always refetched, since the result is stored in the
+/// server's snapshot anyway.
 pub fn query(
     client: Client,
     token: String,
     owner: String,
     repo: String,
+    since: Option<DateTime<Utc>>,
+    reserve: u32,
 ) -> Box<Future<Item = Vec<graphql::PullRequest>, Error = Error>> {
+    let context = ChecksContext {
+        client: client.clone(),
+        token: token.clone(),
+        owner: owner.clone(),
+        repo: repo.clone(),
+        reserve,
+    };
     Box::new(
         unfold(PaginationState::First, move |next_page| {
             if next_page.is_done() {
@@ -276,18 +865,305 @@ pub fn query(
                     &owner,
                     &repo,
                     next_page.as_after(),
+                    since,
                 ))
             }
         })
-        .concat2(),
+        .concat2()
+        .and_then(move |prs| {
+            iter_ok(prs)
+                .map(move |pr| {
+                    if pr.state == graphql::PullRequestState::Open {
+                        complete_checks(context.clone(), pr)
+                    } else {
+                        Box::new(ok(pr))
+                    }
+                })
+                .buffer_unordered(MAX_CONCURRENT_CHECK_QUERIES)
+                .collect()
+        }),
+    )
+}
+
+/// Everything needed to send the follow-up queries of the check suites and check runs.
+#[derive(Clone)]
+struct ChecksContext {
+    /// The HTTP client.
+    client: Client,
+    /// The GitHub token.
+    token: String,
+    /// Owner of the repository.
+    owner: String,
+    /// Name of the repository.
+    repo: String,
+    /// Number of rate limit points to keep, below which no more follow-up queries are sent.
+    reserve: u32,
+}
+
+/// Fetches the remaining check suites and check runs of the last commit of a pull request.
+fn complete_checks(
+    context: ChecksContext,
+    mut pr: graphql::PullRequest,
+) -> Box<Future<Item = graphql::PullRequest, Error = Error>> {
+    let mut commit = match pr.commits.nodes.pop() {
+        Some(commit) => commit.commit,
+        None => return Box::new(ok(pr)),
+    };
+    let after = commit.check_suites.page_info.next_cursor();
+    Box::new(
+        query_check_pages(context.clone(), commit.id.clone(), after).and_then(move |pages| {
+            for page in pages.into_iter().filter_map(|node| node.check_suites) {
+                commit.check_suites.extend(page);
+            }
+            let suites = replace(&mut commit.check_suites.nodes, Vec::new());
+            iter_ok(suites)
+                .map(move |mut suite| {
+                    let after = suite.check_runs.page_info.next_cursor();
+                    query_check_pages(context.clone(), suite.id.clone(), after).map(move |pages| {
+                        for page in pages.into_iter().filter_map(|node| node.check_runs) {
+                            suite.check_runs.extend(page);
+                        }
+                        suite
+                    })
+                })
+                .buffered(MAX_CONCURRENT_CHECK_QUERIES)
+                .collect()
+                .map(move |suites| {
+                    commit.check_suites.nodes = suites;
+                    pr.commits.nodes.push(graphql::PullRequestCommit { commit });
+                    pr
+                })
+        }),
+    )
+}
+
+/// Fetches the pages of check suites or check runs of the node `id`, starting from the cursor
+/// `after`. Nothing is fetched if `after` is `None`, and no more pages are fetched once the rate
+/// limit budget drops below `context.reserve`.
+fn query_check_pages(
+    context: ChecksContext,
+    id: String,
+    after: Option<String>,
+) -> Box<Future<Item = Vec<graphql::ChecksNode>, Error = Error>> {
+    Box::new(
+        unfold(after, move |after| {
+            let after = after?;
+            if let Err(e) = check_budget(context.reserve) {
+                warn!("Skipping the remaining checks of {}: {}", id, e);
+                return None;
+            }
+            let request = ChecksRequest {
+                query: CHECKS_QUERY,
+                variables: ChecksVariables {
+                    id: &id,
+                    after: &after,
+                },
+                owner: &context.owner,
+                repo: &context.repo,
+            };
+            Some(
+                send_github_query(&context.client, &context.token, &request, false, true).map(
+                    |reply: graphql::ChecksReply| {
+                        let node = reply.data.node;
+                        let next_cursor = node.next_cursor();
+                        (node, next_cursor)
+                    },
+                ),
+            )
+        })
+        .collect(),
     )
 }
 
+/// The open pull requests of a repository, kept up-to-date by incremental syncs.
+#[derive(Default)]
+pub struct PullRequestSet {
+    /// The open pull requests, keyed by PR number.
+    prs: HashMap<u32, graphql::PullRequest>,
+    /// The latest update time among all pull requests seen. An incremental sync fetches pull
+    /// requests updated since this time.
+    ///
+    /// This uses the timestamps reported by GitHub rather than the local clock, so clock skew
+    /// between the server and GitHub would not cause updates to be missed.
+    last_updated_at: Option<DateTime<Utc>>,
+    /// When the last full sync was started.
+    full_synced_at: Option<Instant>,
+}
+
+impl PullRequestSet {
+    /// Decides whether the next sync can be incremental.
+    ///
+    /// Returns the time to pass as `since` to [`query`], or `None` if a full sync is needed, either
+    /// because no full sync has been done, or the last one is older than `full_sync_interval`.
+    ///
+    /// [`query`]: fn.query.html
+    pub fn incremental_since(&self, full_sync_interval: Duration) -> Option<DateTime<Utc>> {
+        match self.full_synced_at {
+            Some(full_synced_at) if full_synced_at.elapsed() < full_sync_interval => {
+                self.last_updated_at
+            }
+            _ => None,
+        }
+    }
+
+    /// Forces the next sync to be a full sync.
+    pub fn request_full_sync(&mut self) {
+        self.full_synced_at = None;
+    }
+
+    /// Replaces every pull request with the result of a full sync started at `started_at`.
+    ///
+    /// A full sync only reads open pull requests, so it cannot tell whether the pull requests which
+    /// disappeared have been closed or merged.
+    pub fn replace(&mut self, prs: Vec<graphql::PullRequest>, started_at: Instant) {
+        self.prs.clear();
+        self.last_updated_at = None;
+        self.full_synced_at = Some(started_at);
+        self.merge(prs);
+    }
+
+    /// Merges the result of an incremental sync into the set.
+    ///
+    /// Pull requests which have been closed or merged are removed from the set. Returns the final
+    /// state of these pull requests, keyed by PR number.
+    pub fn merge(
+        &mut self,
+        prs: Vec<graphql::PullRequest>,
+    ) -> HashMap<u32, graphql::PullRequestState> {
+        info!("Merging {} updated PRs", prs.len());
+        let mut closed = HashMap::new();
+        for pr in prs {
+            if self.last_updated_at.map_or(true, |t| t < pr.updated_at) {
+                self.last_updated_at = Some(pr.updated_at);
+            }
+            if pr.state == graphql::PullRequestState::Open {
+                self.prs.insert(pr.number, pr);
+            } else {
+                closed.insert(pr.number, pr.state);
+                self.prs.remove(&pr.number);
+            }
+        }
+        closed
+    }
+
+    /// Finds the pull requests whose last commit has the hash `oid`.
+    pub fn numbers_with_head(&self, oid: &str) -> Vec<u32> {
+        self.prs
+            .values()
+            .filter(|pr| pr.commits.nodes.iter().any(|c| c.commit.oid == oid))
+            .map(|pr| pr.number)
+            .collect()
+    }
+
+    /// Copies all pull requests out of the set.
+    pub fn to_vec(&self) -> Vec<graphql::PullRequest> {
+        self.prs.values().cloned().collect()
+    }
+}
+
+/// Obtains the list of open pull requests from the on-disk cache, without contacting GitHub.
+///
+/// Returns `None` if any page is missing from the cache. Otherwise, returns the pull requests
+/// together with the time the oldest page was fetched.
+pub fn query_cached(
+    owner: &str,
+    repo: &str,
+) -> Option<(DateTime<Utc>, Vec<graphql::PullRequest>)> {
+    let mut prs = Vec::new();
+    let mut oldest_fetched_at = Utc::now();
+    let mut after = None;
+    loop {
+        let cache_key = CacheKey::List {
+            owner: owner.into(),
+            repo: repo.into(),
+            after,
+            incremental: false,
+        };
+        let (fetched_at, body) = disk_cache::load(&cache_key.storage_path())?;
+        let reply = match serde_json::from_slice::<graphql::Reply>(&body) {
+            Ok(reply) => reply,
+            Err(e) => {
+                warn!("cannot parse cached GitHub response: {}", e);
+                return None;
+            }
+        };
+        oldest_fetched_at = oldest_fetched_at.min(fetched_at);
+        let pull_requests = reply.data.repository.pull_requests;
+        for mut pr in pull_requests.nodes {
+            for commit in &mut pr.commits.nodes {
+                load_cached_checks(owner, repo, &mut commit.commit)?;
+            }
+            prs.push(pr);
+        }
+        match pull_requests.page_info.next_cursor() {
+            Some(cursor) => after = Some(cursor.into()),
+            None => return Some((oldest_fetched_at, prs)),
+        }
+    }
+}
+
+/// Completes the check suites and check runs of a commit from the on-disk cache.
+///
+/// Returns `None` if any page is missing from the cache.
+fn load_cached_checks(owner: &str, repo: &str, commit: &mut graphql::Commit) -> Option<()> {
+    let after = commit.check_suites.page_info.next_cursor();
+    for page in load_cached_check_pages(owner, repo, &commit.id, after)? {
+        commit.check_suites.extend(page.check_suites?);
+    }
+    for suite in &mut commit.check_suites.nodes {
+        let after = suite.check_runs.page_info.next_cursor();
+        for page in load_cached_check_pages(owner, repo, &suite.id, after)? {
+            suite.check_runs.extend(page.check_runs?);
+        }
+    }
+    Some(())
+}
+
+/// Reads the pages of check suites or check runs of the node `id` from the on-disk cache, starting
+/// from the cursor `after`.
+fn load_cached_check_pages(
+    owner: &str,
+    repo: &str,
+    id: &str,
+    mut after: Option<String>,
+) -> Option<Vec<graphql::ChecksNode>> {
+    let mut pages = Vec::new();
+    while let Some(cursor) = after {
+        let cache_key = CacheKey::Checks {
+            owner: owner.into(),
+            repo: repo.into(),
+            id: id.into(),
+            after: cursor.into(),
+        };
+        let (_, body) = disk_cache::load(&cache_key.storage_path())?;
+        let node = match serde_json::from_slice::<graphql::ChecksReply>(&body) {
+            Ok(reply) => reply.data.node,
+            Err(e) => {
+                warn!("cannot parse cached GitHub response: {}", e);
+                return None;
+            }
+        };
+        after = node.next_cursor();
+        pages.push(node);
+    }
+    Some(pages)
+}
+
 /// Sends a generic GitHub GraphQL query.
+///
+/// If `use_cache` is true, a fresh cached response, either in memory or on disk, will be returned
+/// without contacting GitHub if available. The response is always stored into the caches
+/// regardless of `use_cache`, though responses fetched with a visitor's token are never stored
+/// on disk.
+///
+/// If `track_rate_limit` is true, the rate limit status in the response is recorded as the budget
+/// of the server. This should be false when querying with a visitor's own token.
 pub(super) fn send_github_query<'a, R, T>(
     client: &Client,
     token: &str,
     request: &'a T,
+    use_cache: bool,
+    track_rate_limit: bool,
 ) -> Box<Future<Item = R, Error = Error>>
 where
     R: DeserializeOwned + 'static,
@@ -300,20 +1176,48 @@ where
         serde_json::to_string(request).unwrap()
     );
 
-    let cache_key = request.into();
-    {
+    let cache_key: CacheKey = request.into();
+    let storage_path = cache_key.storage_path();
+    if let Some(body) = recording::replay(&storage_path) {
+        return Box::new(
+            body.and_then(|body| serde_json::from_slice(&body).map_err(Error::from))
+                .into_future(),
+        );
+    }
+
+    if use_cache {
         let cache_read_guard = CACHE.lock().expect("poisoned");
         if let Some(body) = cache_read_guard.peek(&cache_key) {
             info!("Obtained cached response");
+            metrics::record_cache_lookup(true);
             return Box::new(
                 serde_json::from_slice(body)
                     .map_err(Error::from)
                     .into_future(),
             );
         }
+        let on_disk = if cache_key.is_persistent() {
+            disk_cache::load(&storage_path)
+        } else {
+            None
+        };
+        if let Some((fetched_at, body)) = on_disk {
+            let age = Utc::now().signed_duration_since(fetched_at).to_std();
+            if age.map_or(false, |age| age < Duration::from_secs(CACHE_EXPIRY)) {
+                info!("Obtained cached response from disk");
+                metrics::record_cache_lookup(true);
+                return Box::new(
+                    serde_json::from_slice(&body)
+                        .map_err(Error::from)
+                        .into_future(),
+                );
+            }
+        }
+        metrics::record_cache_lookup(false);
     }
 
-    Box::new(
+    let fetch = metrics::instrument(
+        Upstream::GitHub,
         client
             .post(GITHUB_ENDPOINT)
             .header(AUTHORIZATION, format!("Bearer {}", token))
@@ -331,14 +1235,21 @@ where
                 );
             })
             .and_then(|response| response.into_body().concat2())
-            .map_err(Error::from)
-            .and_then(|body| {
-                let mut cache_write_guard = CACHE.lock().expect("poisoned");
-                let ret = serde_json::from_slice(&body).map_err(Error::from);
-                cache_write_guard.insert(cache_key, body);
-                ret.map_err(Error::from)
-            }),
-    )
+            .map_err(Error::from),
+    );
+    Box::new(fetch.and_then(move |body| {
+        recording::record(&storage_path, &body);
+        if cache_key.is_persistent() {
+            disk_cache::store(&storage_path, &body);
+        }
+        if track_rate_limit {
+            update_rate_limit(&body);
+        }
+        let mut cache_write_guard = CACHE.lock().expect("poisoned");
+        let ret = serde_json::from_slice(&body).map_err(Error::from);
+        cache_write_guard.insert(cache_key, body);
+        ret.map_err(Error::from)
+    }))
 }
 
 /// Obtains a single page of open pull requests and associated information from GitHub.
@@ -348,6 +1259,7 @@ fn query_single_page(
     owner: &str,
     repo: &str,
     after: Option<&str>,
+    since: Option<DateTime<Utc>>,
 ) -> Box<Future<Item = (Vec<graphql::PullRequest>, PaginationState), Error = Error>> {
     Box::new(
         send_github_query(
@@ -355,20 +1267,40 @@ fn query_single_page(
             token,
             &Request {
                 query: QUERY,
-                variables: Variables { owner, repo, after },
+                variables: Variables {
+                    owner,
+                    repo,
+                    after,
+                    states: if since.is_some() {
+                        INCREMENTAL_SYNC_STATES
+                    } else {
+                        FULL_SYNC_STATES
+                    },
+                    order_by: if since.is_some() {
+                        INCREMENTAL_SYNC_ORDER
+                    } else {
+                        FULL_SYNC_ORDER
+                    },
+                },
+                incremental: since.is_some(),
             },
+            false,
+            true,
         )
-        .map(|reply: graphql::Reply| {
+        .map(move |reply: graphql::Reply| {
             let prs = reply.data.repository.pull_requests.nodes;
-            let next_page = match reply.data.repository.pull_requests.page_info {
-                graphql::PageInfo {
-                    has_next_page: true,
-                    end_cursor,
-                } => PaginationState::HasNext(end_cursor),
-                graphql::PageInfo {
-                    has_next_page: false,
-                    ..
-                } => PaginationState::Done,
+            let next_page = match reply.data.repository.pull_requests.page_info.next_cursor() {
+                Some(end_cursor) => PaginationState::HasNext(end_cursor),
+                None => PaginationState::Done,
+            };
+            let reached_since = match (since, prs.last()) {
+                (Some(since), Some(last)) => last.updated_at < since,
+                _ => false,
+            };
+            let next_page = if reached_since {
+                PaginationState::Done
+            } else {
+                next_page
             };
             info!(
                 "Obtained {} PRs from GitHub, has next page = {}",
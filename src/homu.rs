@@ -1,114 +1,77 @@
 //! Home queue web scraper.
 
+use chrono::{DateTime, Utc};
 use failure::{err_msg, Error, ResultExt};
-use futures::{Future, Stream};
-use kuchiki::parse_html;
-use kuchiki::traits::TendrilSink;
+use futures::Future;
+use kuchiki::NodeRef;
+use queue::{cached_html, fetch_html, row_cells, Entry, QueueSource, Status};
 use reqwest::async::Client;
 use reqwest::Url;
-use tendril::Tendril;
 
-/// An entry in the Homu queue.
-pub struct Entry {
-    /// Pull request number.
-    pub number: u32,
-    /// Pull request title.
-    pub title: String,
-    /// Approval status.
-    pub status: Status,
-    /// Whether the approval status applies to a "try" run.
-    pub is_trying: bool,
-    /// Priority. Rollups are always assigned a priority of `-1`.
-    pub priority: i32,
-    /// Name of approver
-    pub approver: String,
+/// The Homu queue of a repository.
+pub struct Homu {
+    /// URL to access the Homu queue.
+    url: Url,
 }
 
-/// The approval status of a pull request in the Homu queue.
-#[derive(Serialize, PartialEq, Eq, Clone, Copy)]
-pub enum Status {
-    /// CI reported success, waiting for reviewer's further action.
-    Success,
-    /// The pull request is sent to the CI, and is waiting for test result.
-    Pending,
-    /// The pull request has been approved, waiting to be tested.
-    Approved,
-    /// The pull request is being actively reviewed.
-    Reviewing,
-    /// Error while testing the pull request, likely due to merge conflict.
-    Error,
-    /// CI reported failure.
-    Failure,
+impl Homu {
+    /// Creates a new Homu queue source reading from the given URL.
+    pub fn new(url: Url) -> Self {
+        Self { url }
+    }
 }
 
-impl Default for Status {
-    fn default() -> Self {
-        Status::Reviewing
+impl QueueSource for Homu {
+    /// Obtains the list of pull requests and associated information from Homu queue.
+    fn query(&self, client: &Client) -> Box<Future<Item = Vec<Entry>, Error = Error>> {
+        info!("Preparing to send Homu request");
+        Box::new(fetch_html(client, &self.url).and_then(parse_queue))
     }
-}
 
-/// Obtains the list of pull requests and associated information from Homu queue.
-pub fn query(client: &Client, url: &Url) -> Box<Future<Item = Vec<Entry>, Error = Error>> {
-    info!("Preparing to send Homu request");
+    /// Obtains the list of pull requests from the cached Homu queue page.
+    fn cached(&self) -> Option<(DateTime<Utc>, Vec<Entry>)> {
+        let (fetched_at, doc) = cached_html(&self.url)?;
+        match parse_queue(doc) {
+            Ok(entries) => Some((fetched_at, entries)),
+            Err(e) => {
+                warn!("cannot parse cached Homu queue: {}", e);
+                None
+            }
+        }
+    }
+}
 
-    Box::new(
-        client
-            .get(url.clone())
-            .send()
-            .and_then(|response| response.error_for_status())
-            .and_then(|response| {
-                response
-                    .into_body()
-                    .fold(parse_html().from_utf8(), |mut parser, chunk| {
-                        parser.process(Tendril::from_slice(&*chunk));
-                        Ok::<_, ::reqwest::Error>(parser)
-                    })
-            })
-            .map(|parser| parser.finish())
-            .map_err(Error::from)
-            .and_then(|doc| {
-                let mut res = Vec::new();
-                for tr in doc
-                    .select("#queue > tbody > tr")
-                    .expect("well-formed CSS query")
-                {
-                    let mut tds = tr
-                        .as_node()
-                        .children()
-                        .filter_map(|td| {
-                            if let Some(elem) = td.as_element() {
-                                if elem.name.expanded() == expanded_name!(html "td") {
-                                    return Some(td.text_contents().trim().to_owned());
-                                }
-                            }
-                            None
-                        })
-                        .collect::<Vec<_>>();
+/// Extracts the pull requests from the HTML document of the Homu queue.
+fn parse_queue(doc: NodeRef) -> Result<Vec<Entry>, Error> {
+    let mut res = Vec::new();
+    for tr in doc
+        .select("#queue > tbody > tr")
+        .expect("well-formed CSS query")
+    {
+        let mut tds = row_cells(tr.as_node());
 
-                    if tds.len() != 10 {
-                        return Err(err_msg("Homu queue structure probably changed. Aborting."));
-                    }
+        if tds.len() != 10 {
+            return Err(err_msg("Homu queue structure probably changed. Aborting."));
+        }
 
-                    let number = tds[2].parse::<u32>().context("invalid PR number")?;
-                    let (status, is_trying) = parse_status(&tds[3]);
-                    let priority = parse_priority(&tds[9]);
-                    let approver = tds.swap_remove(8);
-                    let title = tds.swap_remove(5);
+        let number = tds[2].parse::<u32>().context("invalid PR number")?;
+        let (status, is_trying) = parse_status(&tds[3]);
+        let priority = parse_priority(&tds[9]);
+        let approver = tds.swap_remove(8);
+        let title = tds.swap_remove(5);
 
-                    res.push(Entry {
-                        number,
-                        title,
-                        status,
-                        is_trying,
-                        priority,
-                        approver,
-                    });
-                }
+        res.push(Entry {
+            number,
+            title,
+            status,
+            is_trying,
+            priority,
+            approver,
+        });
+    }
 
-                info!("Obtained {} PRs from Homu", res.len());
-                Ok(res)
-            }),
-    )
+    info!("Obtained {} PRs from Homu", res.len());
+    Ok(res)
 }
 
 /// Parses the rendered approval status string into the status/is-try pair.
@@ -21,6 +21,8 @@ extern crate failure;
 extern crate failure_derive;
 extern crate flate2;
 extern crate futures;
+extern crate hex;
+extern crate hmac;
 extern crate hyper;
 extern crate kuchiki;
 #[macro_use]
@@ -33,12 +35,15 @@ extern crate maplit;
 #[macro_use]
 extern crate markup5ever;
 extern crate mime;
+extern crate rand;
 extern crate regex;
 extern crate reqwest;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde_json;
+extern crate serde_urlencoded;
+extern crate sha2;
 extern crate structopt;
 #[macro_use]
 extern crate structopt_derive;
@@ -46,17 +51,37 @@ extern crate tendril;
 #[macro_use]
 extern crate tera;
 extern crate tokio_core;
+#[cfg(unix)]
+extern crate tokio_signal;
+extern crate toml;
 
+mod api;
 mod args;
+mod bors;
+mod ci_log;
+mod commands;
+mod config;
+mod disk_cache;
 mod github;
 mod homu;
+mod metrics;
+mod notify;
+mod query;
+mod queue;
+mod recording;
 mod render;
+mod reviewers;
 mod server;
+mod session;
+mod spurious;
 mod timeline;
+mod webhook;
 
 use args::Args;
+use config::Config;
 use env_logger::{Builder, Env};
 use failure::Error;
+use recording::Mode;
 use server::serve;
 use std::io::Write;
 use structopt::StructOpt;
@@ -66,8 +91,17 @@ use structopt::StructOpt;
 pub fn run() -> Result<(), Error> {
     init_logger();
     let args = Args::from_args();
-    println!("Please open http://{}", args.address);
-    serve(args)
+    let config = Config::load(&args)?;
+    recording::set_mode(match (args.record.clone(), args.replay.clone()) {
+        (_, Some(dir)) => Mode::Replay(dir),
+        (Some(dir), None) => Mode::Record(dir),
+        (None, None) => Mode::Live,
+    })?;
+    if let Some(ref dir) = config.cache_dir {
+        disk_cache::open(dir.clone())?;
+    }
+    println!("Please open http://{}", config.address);
+    serve(args, config)
 }
 
 /// Initializes the logger via the `RUST_LOG` variable. See documentation of
@@ -0,0 +1,390 @@
+//! Prometheus metrics.
+//!
+//! The metrics are exported at `/metrics` in the [Prometheus text format].
+//!
+//! [Prometheus text format]: https://prometheus.io/docs/instrumenting/exposition_formats/
+
+use chrono::{DateTime, Utc};
+use config::Repository;
+use futures::Future;
+use github::graphql::StatusState;
+use github::rate_limit;
+use queue::Status;
+use render::{Pr, PrStats};
+use serde::Serialize;
+use serde_json::{to_value, Value};
+use spurious;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// All approval statuses, so every series is exported even when the count is zero.
+const ALL_STATUSES: [Status; 6] = [
+    Status::Success,
+    Status::Pending,
+    Status::Approved,
+    Status::Reviewing,
+    Status::Error,
+    Status::Failure,
+];
+
+/// All CI states, so every series is exported even when the count is zero.
+const ALL_CI_STATES: [StatusState; 5] = [
+    StatusState::Expected,
+    StatusState::Error,
+    StatusState::Failure,
+    StatusState::Pending,
+    StatusState::Success,
+];
+
+/// An upstream service which borsholder fetches data from.
+#[derive(Clone, Copy)]
+pub enum Upstream {
+    /// The GitHub GraphQL API.
+    GitHub,
+    /// The merge queue page of Homu or rust-lang/bors.
+    Queue,
+}
+
+/// Statistics about the requests sent to an upstream service.
+#[derive(Default)]
+struct UpstreamStats {
+    /// Number of requests completed, including failed ones.
+    requests: u64,
+    /// Number of failed requests.
+    errors: u64,
+    /// Total time spent on the requests, in seconds.
+    seconds: f64,
+}
+
+/// All metrics collected while the server runs.
+#[derive(Default)]
+struct Counters {
+    /// Statistics about the requests sent to GitHub.
+    github: UpstreamStats,
+    /// Statistics about the requests sent to the merge queues.
+    queue: UpstreamStats,
+    /// Number of GitHub queries answered from the cache.
+    cache_hits: u64,
+    /// Number of GitHub queries which could have used the cache but were not found in it.
+    cache_misses: u64,
+    /// Number of timeline events of a known type which could not be parsed, by type.
+    timeline_parse_errors: HashMap<String, u64>,
+}
+
+lazy_static! {
+    static ref COUNTERS: Mutex<Counters> = Mutex::default();
+}
+
+/// Wraps a request to an upstream service, recording its latency and whether it failed.
+#[cfg_attr(feature = "cargo-clippy", allow(cast_precision_loss))]
+pub fn instrument<F>(upstream: Upstream, future: F) -> Box<Future<Item = F::Item, Error = F::Error>>
+where
+    F: Future + 'static,
+{
+    let started_at = Instant::now();
+    Box::new(future.then(move |result| {
+        let elapsed = started_at.elapsed();
+        let mut counters = COUNTERS.lock().expect("poisoned");
+        let stats = match upstream {
+            Upstream::GitHub => &mut counters.github,
+            Upstream::Queue => &mut counters.queue,
+        };
+        stats.requests += 1;
+        stats.seconds += elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) * 1e-9;
+        if result.is_err() {
+            stats.errors += 1;
+        }
+        result
+    }))
+}
+
+/// Records whether a GitHub query was answered from the cache.
+pub fn record_cache_lookup(hit: bool) {
+    let mut counters = COUNTERS.lock().expect("poisoned");
+    if hit {
+        counters.cache_hits += 1;
+    } else {
+        counters.cache_misses += 1;
+    }
+}
+
+/// Records a timeline event of a known type which could not be parsed.
+pub fn record_timeline_parse_error(typename: &str) {
+    let mut counters = COUNTERS.lock().expect("poisoned");
+    *counters.timeline_parse_errors.entry(typename.to_owned()).or_insert(0) += 1;
+}
+
+/// The PR information of a repository to export.
+pub struct RepoMetrics<'a> {
+    /// The repository.
+    pub repo: &'a Repository,
+    /// The list of PRs.
+    pub prs: &'a HashMap<u32, Pr>,
+    /// PR statistics.
+    pub stats: &'a PrStats,
+    /// When the PR information was fetched.
+    pub fetched_at: DateTime<Utc>,
+}
+
+/// Renders all metrics in the Prometheus text format.
+pub fn render(repos: &[RepoMetrics]) -> String {
+    let mut out = String::new();
+
+    repo_gauge(
+        &mut out,
+        repos,
+        "borsholder_prs",
+        "Number of open PRs, excluding drafts.",
+        |r| r.stats.count.into(),
+    );
+    repo_gauge(
+        &mut out,
+        repos,
+        "borsholder_prs_drafts",
+        "Number of open draft PRs.",
+        |r| r.stats.drafts.into(),
+    );
+    repo_gauge(
+        &mut out,
+        repos,
+        "borsholder_prs_approved",
+        "Number of approved, mergeable PRs.",
+        |r| r.stats.approved.into(),
+    );
+    repo_gauge(
+        &mut out,
+        repos,
+        "borsholder_prs_rollups",
+        "Number of approved, mergeable PRs with rollup priority.",
+        |r| r.stats.rollups.into(),
+    );
+    repo_gauge(
+        &mut out,
+        repos,
+        "borsholder_fetched_timestamp_seconds",
+        "When the PR information was last fetched.",
+        |r| r.fetched_at.timestamp(),
+    );
+
+    header(
+        &mut out,
+        "borsholder_prs_by_status",
+        "gauge",
+        "Number of open PRs by approval status in the merge queue.",
+    );
+    for r in repos {
+        let repo = r.repo.to_string();
+        for status in &ALL_STATUSES {
+            let count = r.prs.values().filter(|pr| pr.status == *status).count();
+            let status = variant_name(status);
+            sample(
+                &mut out,
+                "borsholder_prs_by_status",
+                &[("repo", &repo), ("status", &status)],
+                count,
+            );
+        }
+    }
+
+    header(
+        &mut out,
+        "borsholder_ci_statuses",
+        "gauge",
+        "Number of CI statuses reported on the last commit of open PRs, by state.",
+    );
+    for r in repos {
+        let repo = r.repo.to_string();
+        let mut counts = HashMap::new();
+        for context in r.prs.values().flat_map(|pr| &pr.ci_status) {
+            *counts.entry(variant_name(&context.state)).or_insert(0) += 1;
+        }
+        for state in &ALL_CI_STATES {
+            let state = variant_name(state);
+            let count = counts.get(&state).cloned().unwrap_or(0);
+            sample(
+                &mut out,
+                "borsholder_ci_statuses",
+                &[("repo", &repo), ("state", &state)],
+                count,
+            );
+        }
+    }
+
+    {
+        let counters = COUNTERS.lock().expect("poisoned");
+        let upstreams = [("github", &counters.github), ("queue", &counters.queue)];
+        header(
+            &mut out,
+            "borsholder_upstream_requests_total",
+            "counter",
+            "Number of requests sent to upstream services.",
+        );
+        for &(name, stats) in &upstreams {
+            let labels = [("upstream", name)];
+            sample(&mut out, "borsholder_upstream_requests_total", &labels, stats.requests);
+        }
+        header(
+            &mut out,
+            "borsholder_upstream_errors_total",
+            "counter",
+            "Number of failed requests sent to upstream services.",
+        );
+        for &(name, stats) in &upstreams {
+            let labels = [("upstream", name)];
+            sample(&mut out, "borsholder_upstream_errors_total", &labels, stats.errors);
+        }
+        header(
+            &mut out,
+            "borsholder_upstream_request_duration_seconds",
+            "summary",
+            "Time spent on requests sent to upstream services.",
+        );
+        for &(name, stats) in &upstreams {
+            let labels = [("upstream", name)];
+            sample(
+                &mut out,
+                "borsholder_upstream_request_duration_seconds_sum",
+                &labels,
+                stats.seconds,
+            );
+            sample(
+                &mut out,
+                "borsholder_upstream_request_duration_seconds_count",
+                &labels,
+                stats.requests,
+            );
+        }
+
+        header(
+            &mut out,
+            "borsholder_github_cache_hits_total",
+            "counter",
+            "Number of GitHub queries answered from the cache.",
+        );
+        sample(&mut out, "borsholder_github_cache_hits_total", &[], counters.cache_hits);
+        header(
+            &mut out,
+            "borsholder_github_cache_misses_total",
+            "counter",
+            "Number of GitHub queries not found in the cache.",
+        );
+        sample(&mut out, "borsholder_github_cache_misses_total", &[], counters.cache_misses);
+
+        header(
+            &mut out,
+            "borsholder_timeline_parse_errors_total",
+            "counter",
+            "Number of timeline events of a known type which could not be parsed.",
+        );
+        let mut parse_errors = counters.timeline_parse_errors.iter().collect::<Vec<_>>();
+        parse_errors.sort();
+        for (typename, count) in parse_errors {
+            sample(
+                &mut out,
+                "borsholder_timeline_parse_errors_total",
+                &[("typename", typename)],
+                count,
+            );
+        }
+    }
+
+    header(
+        &mut out,
+        "borsholder_failure_signature_hits_total",
+        "counter",
+        "Number of CI failures matching each known failure signature.",
+    );
+    for (name, count) in spurious::hits() {
+        sample(
+            &mut out,
+            "borsholder_failure_signature_hits_total",
+            &[("signature", &name)],
+            count,
+        );
+    }
+
+    if let Some(rate_limit) = rate_limit() {
+        header(
+            &mut out,
+            "borsholder_github_rate_limit_limit",
+            "gauge",
+            "Maximum number of GitHub GraphQL points per hour.",
+        );
+        sample(&mut out, "borsholder_github_rate_limit_limit", &[], rate_limit.limit);
+        header(
+            &mut out,
+            "borsholder_github_rate_limit_remaining",
+            "gauge",
+            "Number of GitHub GraphQL points remaining.",
+        );
+        sample(&mut out, "borsholder_github_rate_limit_remaining", &[], rate_limit.remaining);
+        header(
+            &mut out,
+            "borsholder_github_rate_limit_cost",
+            "gauge",
+            "Number of GitHub GraphQL points spent by the last query.",
+        );
+        sample(&mut out, "borsholder_github_rate_limit_cost", &[], rate_limit.cost);
+        header(
+            &mut out,
+            "borsholder_github_rate_limit_reset_timestamp_seconds",
+            "gauge",
+            "When the GitHub GraphQL points will be restored.",
+        );
+        sample(
+            &mut out,
+            "borsholder_github_rate_limit_reset_timestamp_seconds",
+            &[],
+            rate_limit.reset_at.timestamp(),
+        );
+    }
+
+    out
+}
+
+/// Writes a gauge metric with one sample for each repository.
+fn repo_gauge<F>(out: &mut String, repos: &[RepoMetrics], name: &str, help: &str, value: F)
+where
+    F: Fn(&RepoMetrics) -> i64,
+{
+    header(out, name, "gauge", help);
+    for r in repos {
+        sample(out, name, &[("repo", &r.repo.to_string())], value(r));
+    }
+}
+
+/// Writes the `HELP` and `TYPE` lines of a metric.
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).expect("writing to string");
+    writeln!(out, "# TYPE {} {}", name, kind).expect("writing to string");
+}
+
+/// Writes a single sample of a metric.
+fn sample<V: ToString>(out: &mut String, name: &str, labels: &[(&str, &str)], value: V) {
+    out.push_str(name);
+    if !labels.is_empty() {
+        out.push('{');
+        for (i, &(key, label)) in labels.iter().enumerate() {
+            if i != 0 {
+                out.push(',');
+            }
+            let label = label
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            write!(out, "{}=\"{}\"", key, label).expect("writing to string");
+        }
+        out.push('}');
+    }
+    writeln!(out, " {}", value.to_string()).expect("writing to string");
+}
+
+/// Obtains the lowercase serialized name of an enum variant, for use as a label value.
+fn variant_name<T: Serialize>(value: &T) -> String {
+    match to_value(value) {
+        Ok(Value::String(s)) => s.to_lowercase(),
+        _ => String::new(),
+    }
+}
@@ -0,0 +1,521 @@
+//! Outgoing notifications when watched PRs change state.
+//!
+//! After each refresh, the new PR information is compared with the previous snapshot, and every
+//! change is sent to the webhooks subscribing to the PR.
+
+use config::{deserialize_url, Repository};
+use futures::future::{join_all, Future};
+use github::graphql::{MergeableState, PullRequestState, StatusState};
+use queue::Status;
+use render::Pr;
+use reqwest::async::{Client, RequestBuilder};
+use reqwest::Url;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// Payload format of a notification webhook.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// A JSON object describing the change.
+    Generic,
+    /// A Slack incoming webhook message.
+    Slack,
+    /// A message sent through the Zulip REST API.
+    Zulip,
+}
+
+impl Default for Format {
+    fn default() -> Self {
+        Format::Generic
+    }
+}
+
+/// A webhook receiving notifications about the changes of watched PRs.
+///
+/// A PR is watched if it matches any of `numbers`, `authors`, `labels` or `approvers`. If all of
+/// them are empty, every PR is watched.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Subscription {
+    /// URL to send the notifications to. For Zulip, the bot's email and API key should be given
+    /// as the user name and password of the URL.
+    #[serde(deserialize_with = "deserialize_url")]
+    pub url: Url,
+    /// Payload format of the webhook.
+    #[serde(default)]
+    pub format: Format,
+    /// Zulip stream to send the messages to.
+    pub stream: Option<String>,
+    /// Zulip topic to send the messages to. Defaults to the PR name, e.g. `rust-lang/rust#12345`.
+    pub topic: Option<String>,
+    /// Only watch PRs of these repositories, in the form `owner/name`. Empty means every
+    /// repository.
+    #[serde(default)]
+    pub repos: Vec<String>,
+    /// Watch PRs with these numbers.
+    #[serde(default)]
+    pub numbers: Vec<u32>,
+    /// Watch PRs authored by these users.
+    #[serde(default)]
+    pub authors: Vec<String>,
+    /// Watch PRs with any of these labels.
+    #[serde(default)]
+    pub labels: Vec<String>,
+    /// Watch PRs approved by these users.
+    #[serde(default)]
+    pub approvers: Vec<String>,
+}
+
+impl Subscription {
+    /// Checks whether this subscription watches the PR.
+    fn watches(&self, repo: &Repository, number: u32, pr: &Pr) -> bool {
+        let full_name = repo.to_string();
+        if !self.repos.is_empty()
+            && !self.repos.iter().any(|r| r.eq_ignore_ascii_case(&full_name))
+        {
+            return false;
+        }
+        if self.numbers.is_empty()
+            && self.authors.is_empty()
+            && self.labels.is_empty()
+            && self.approvers.is_empty()
+        {
+            return true;
+        }
+        self.numbers.contains(&number)
+            || self.authors.iter().any(|a| a.eq_ignore_ascii_case(&pr.author))
+            || self.approvers.iter().any(|a| a.eq_ignore_ascii_case(&pr.approver))
+            || self.labels.iter().any(|name| {
+                pr.labels
+                    .iter()
+                    .any(|label| label.name.eq_ignore_ascii_case(name))
+            })
+    }
+}
+
+/// A change of a PR between two snapshots.
+#[derive(Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Change {
+    /// The approval status in the merge queue changed.
+    Status {
+        /// The previous status.
+        from: Status,
+        /// The new status.
+        to: Status,
+    },
+    /// Some CI statuses started failing.
+    CiFailure {
+        /// Names of the newly failed CI statuses.
+        contexts: Vec<String>,
+    },
+    /// The PR started to have merge conflicts.
+    Conflict,
+    /// The PR has been merged.
+    Merged,
+    /// The PR has been closed without merging.
+    Closed,
+    /// The PR is no longer open, but it is unknown whether it was merged or closed, because it
+    /// disappeared during a full sync.
+    Removed,
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Change::Status { from, to } => write!(f, "status changed from {} to {}", from, to),
+            Change::CiFailure { ref contexts } => write!(f, "CI failed: {}", contexts.join(", ")),
+            Change::Conflict => f.write_str("has merge conflicts"),
+            Change::Merged => f.write_str("has been merged"),
+            Change::Closed => f.write_str("has been closed"),
+            Change::Removed => f.write_str("is no longer open"),
+        }
+    }
+}
+
+/// A change of a particular PR.
+pub struct Event<'a> {
+    /// The PR number.
+    pub number: u32,
+    /// The PR information. For removed PRs, this is the last known information.
+    pub pr: &'a Pr,
+    /// What has changed.
+    pub change: Change,
+}
+
+/// Compares two snapshots of PRs, and finds out all changes worth notifying.
+///
+/// `closed` is the final state of the PRs which GitHub reported as closed or merged since the old
+/// snapshot.
+pub fn diff<'a>(
+    old: &'a HashMap<u32, Pr>,
+    new: &'a HashMap<u32, Pr>,
+    closed: &HashMap<u32, PullRequestState>,
+) -> Vec<Event<'a>> {
+    let mut events = Vec::new();
+    for (&number, pr) in new {
+        let old_pr = match old.get(&number) {
+            Some(old_pr) => old_pr,
+            None => continue,
+        };
+        let mut push = |change| events.push(Event { number, pr, change });
+        if old_pr.status != pr.status {
+            push(Change::Status {
+                from: old_pr.status,
+                to: pr.status,
+            });
+        }
+        let old_failures = failed_contexts(old_pr);
+        let mut contexts = failed_contexts(pr)
+            .difference(&old_failures)
+            .map(|c| (*c).to_owned())
+            .collect::<Vec<_>>();
+        if !contexts.is_empty() {
+            contexts.sort();
+            push(Change::CiFailure { contexts });
+        }
+        if old_pr.mergeable != MergeableState::Conflicting
+            && pr.mergeable == MergeableState::Conflicting
+        {
+            push(Change::Conflict);
+        }
+    }
+    for (&number, pr) in old {
+        if !new.contains_key(&number) {
+            let change = match closed.get(&number) {
+                Some(PullRequestState::Merged) => Change::Merged,
+                Some(PullRequestState::Closed) => Change::Closed,
+                Some(PullRequestState::Open) | None => Change::Removed,
+            };
+            events.push(Event { number, pr, change });
+        }
+    }
+    events
+}
+
+/// Sends the events to every subscription watching the PRs.
+///
+/// The returned future never fails. Failure to send any notification is logged.
+pub fn send(
+    client: &Client,
+    subscriptions: &[Subscription],
+    repo: &Repository,
+    events: &[Event],
+) -> Box<Future<Item = (), Error = ()>> {
+    let mut requests = Vec::new();
+    for subscription in subscriptions {
+        for event in events {
+            if !subscription.watches(repo, event.number, event.pr) {
+                continue;
+            }
+            match build_request(client, subscription, repo, event) {
+                Ok(request) => requests.push(request),
+                Err(e) => warn!("cannot notify {}: {}", redacted(&subscription.url), e),
+            }
+        }
+    }
+    info!("Sending {} notifications about {}", requests.len(), repo);
+
+    let futures = requests.into_iter().map(|(url, request)| {
+        request
+            .send()
+            .and_then(|response| response.error_for_status())
+            .then(move |result| {
+                if let Err(e) = result {
+                    warn!("failed to notify {}: {}", url, e);
+                }
+                Ok::<_, ()>(())
+            })
+    });
+    Box::new(join_all(futures.collect::<Vec<_>>()).map(|_| ()))
+}
+
+/// Payload of the generic JSON webhook.
+#[derive(Serialize)]
+struct GenericPayload<'a> {
+    /// The repository, in the form `owner/name`.
+    repository: String,
+    /// The PR number.
+    number: u32,
+    /// The PR title.
+    title: &'a str,
+    /// The PR author.
+    author: &'a str,
+    /// URL to the PR.
+    url: &'a str,
+    /// What has changed.
+    change: &'a Change,
+    /// Human-readable description of the change.
+    message: &'a str,
+}
+
+/// Payload of the Slack incoming webhook.
+#[derive(Serialize)]
+struct SlackPayload<'a> {
+    /// The message text.
+    text: &'a str,
+}
+
+/// Builds the request to notify a subscription of an event. Returns the URL for logging, and the
+/// request itself.
+fn build_request(
+    client: &Client,
+    subscription: &Subscription,
+    repo: &Repository,
+    event: &Event,
+) -> Result<(String, RequestBuilder), String> {
+    let log_url = redacted(&subscription.url);
+    let pr = event.pr;
+    let name = format!("{}#{}", repo, event.number);
+    let pr_url = format!(
+        "https://github.com/{}/{}/pull/{}",
+        repo.owner, repo.name, event.number
+    );
+    let mut url = subscription.url.clone();
+
+    let request = match subscription.format {
+        Format::Generic => {
+            let message = format!("{} \"{}\" {}", name, pr.title, event.change);
+            client.post(url).json(&GenericPayload {
+                repository: repo.to_string(),
+                number: event.number,
+                title: &pr.title,
+                author: &pr.author,
+                url: &pr_url,
+                change: &event.change,
+                message: &message,
+            })
+        }
+        Format::Slack => {
+            let text = format!(
+                "<{}|{}> {} by {}: {}",
+                pr_url,
+                name,
+                slack_escape(&pr.title),
+                slack_escape(&pr.author),
+                event.change
+            );
+            client.post(url).json(&SlackPayload { text: &text })
+        }
+        Format::Zulip => {
+            let stream = subscription
+                .stream
+                .as_ref()
+                .ok_or_else(|| "missing `stream` for Zulip notification".to_owned())?;
+            let topic = subscription.topic.as_ref().unwrap_or(&name);
+            let content = format!(
+                "[{}]({}) **{}** by @**{}**: {}",
+                name, pr_url, pr.title, pr.author, event.change
+            );
+            let user = url.username().to_owned();
+            let password = url.password().map(str::to_owned);
+            let _ = url.set_username("");
+            let _ = url.set_password(None);
+            let mut builder = client.post(url);
+            if !user.is_empty() {
+                builder = builder.basic_auth(user, password);
+            }
+            builder.form(&[
+                ("type", "stream"),
+                ("to", stream),
+                ("topic", topic),
+                ("content", &content),
+            ])
+        }
+    };
+    Ok((log_url, request))
+}
+
+/// Removes the password from a URL, so it can be logged.
+fn redacted(url: &Url) -> String {
+    let mut url = url.clone();
+    let _ = url.set_password(None);
+    url.to_string()
+}
+
+/// Escapes the control characters of a Slack message.
+fn slack_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Obtains the names of all failed CI statuses of a PR.
+fn failed_contexts(pr: &Pr) -> HashSet<&str> {
+    pr.ci_status
+        .iter()
+        .filter(|ci| ci.state == StatusState::Failure || ci.state == StatusState::Error)
+        .map(|ci| &*ci.context)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{diff, Format, Subscription};
+    use github::graphql::{Label, MergeableState, PullRequestState, StatusContext, StatusState};
+    use queue::Status;
+    use render::Pr;
+    use std::collections::HashMap;
+
+    /// Creates a PR with the given approval status, mergeability and CI statuses.
+    fn pr(status: Status, mergeable: MergeableState, ci: &[(&str, StatusState)]) -> Pr {
+        Pr {
+            status,
+            mergeable,
+            ci_status: ci
+                .iter()
+                .map(|&(context, state)| StatusContext {
+                    context: context.to_owned(),
+                    description: String::new(),
+                    target_url: String::new(),
+                    state,
+                })
+                .collect(),
+            ..Pr::default()
+        }
+    }
+
+    /// Compares two snapshots, returning the PR number and description of each change in order.
+    fn changes(
+        old: &HashMap<u32, Pr>,
+        new: &HashMap<u32, Pr>,
+        closed: &HashMap<u32, PullRequestState>,
+    ) -> Vec<(u32, String)> {
+        let mut changes = diff(old, new, closed)
+            .into_iter()
+            .map(|event| (event.number, event.change.to_string()))
+            .collect::<Vec<_>>();
+        changes.sort();
+        changes
+    }
+
+    #[test]
+    fn test_diff() {
+        let ok = MergeableState::Mergeable;
+        let old = hashmap![
+            1 => pr(Status::Reviewing, ok, &[]),
+            2 => pr(Status::Pending, ok, &[
+                ("a", StatusState::Failure),
+                ("b", StatusState::Pending),
+            ]),
+            3 => pr(Status::Approved, ok, &[]),
+            4 => pr(Status::Approved, MergeableState::Conflicting, &[]),
+            5 => pr(Status::Approved, ok, &[]),
+            6 => pr(Status::Approved, ok, &[]),
+            7 => pr(Status::Approved, ok, &[]),
+            8 => pr(Status::Approved, ok, &[]),
+        ];
+        let new = hashmap![
+            1 => pr(Status::Approved, ok, &[]),
+            2 => pr(Status::Pending, ok, &[
+                ("a", StatusState::Failure),
+                ("b", StatusState::Error),
+                ("c", StatusState::Failure),
+                ("d", StatusState::Success),
+            ]),
+            3 => pr(Status::Approved, MergeableState::Conflicting, &[]),
+            4 => pr(Status::Approved, MergeableState::Conflicting, &[]),
+            5 => pr(Status::Approved, ok, &[]),
+            9 => pr(Status::Reviewing, ok, &[("a", StatusState::Failure)]),
+        ];
+        let closed = hashmap![
+            6 => PullRequestState::Merged,
+            7 => PullRequestState::Closed,
+        ];
+        assert_eq!(
+            changes(&old, &new, &closed),
+            vec![
+                (1, "status changed from reviewing to approved".to_owned()),
+                (2, "CI failed: b, c".to_owned()),
+                (3, "has merge conflicts".to_owned()),
+                (6, "has been merged".to_owned()),
+                (7, "has been closed".to_owned()),
+                (8, "is no longer open".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_diff_unchanged() {
+        let failed = || {
+            let ci = [("a", StatusState::Error)];
+            pr(Status::Failure, MergeableState::Conflicting, &ci)
+        };
+        let old = hashmap![1 => failed()];
+        let new = hashmap![1 => failed()];
+        assert!(changes(&old, &new, &HashMap::new()).is_empty());
+    }
+
+    /// Creates a subscription to the generic webhook.
+    fn subscription() -> Subscription {
+        Subscription {
+            url: "https://example.com/hook".parse().unwrap(),
+            format: Format::Generic,
+            stream: None,
+            topic: None,
+            repos: Vec::new(),
+            numbers: Vec::new(),
+            authors: Vec::new(),
+            labels: Vec::new(),
+            approvers: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_watches() {
+        let rust = "rust-lang/rust=https://bors.rust-lang.org/queue/rust".parse().unwrap();
+        let cargo = "rust-lang/cargo=https://bors.rust-lang.org/queue/cargo".parse().unwrap();
+        let pr = Pr {
+            author: "Alice".to_owned(),
+            approver: "bob".to_owned(),
+            labels: vec![Label {
+                name: "T-compiler".to_owned(),
+                color: "ffffff".to_owned(),
+                applied_at: None,
+            }],
+            ..Pr::default()
+        };
+
+        let everything = subscription();
+        assert!(everything.watches(&rust, 1, &pr));
+        assert!(everything.watches(&cargo, 1, &pr));
+
+        let only_rust = Subscription {
+            repos: vec!["Rust-Lang/Rust".to_owned()],
+            ..subscription()
+        };
+        assert!(only_rust.watches(&rust, 1, &pr));
+        assert!(!only_rust.watches(&cargo, 1, &pr));
+
+        let filtered = [
+            Subscription {
+                numbers: vec![1],
+                ..subscription()
+            },
+            Subscription {
+                authors: vec!["alice".to_owned()],
+                ..subscription()
+            },
+            Subscription {
+                labels: vec!["t-compiler".to_owned()],
+                ..subscription()
+            },
+            Subscription {
+                approvers: vec!["BOB".to_owned()],
+                ..subscription()
+            },
+        ];
+        for subscription in &filtered {
+            assert!(subscription.watches(&rust, 1, &pr));
+            assert!(!subscription.watches(&rust, 2, &Pr::default()));
+        }
+
+        let other_repo = Subscription {
+            repos: vec!["rust-lang/cargo".to_owned()],
+            authors: vec!["alice".to_owned()],
+            ..subscription()
+        };
+        assert!(!other_repo.watches(&rust, 1, &pr));
+    }
+}
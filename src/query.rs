@@ -0,0 +1,566 @@
+//! Structured query language to filter PRs.
+//!
+//! See the "Query language" section of the README for the syntax.
+
+use api::BadRequest;
+use chrono::{DateTime, Duration, Utc};
+use github::graphql::{MergeableState, PullRequestReviewDecision, StatusState};
+use queue::Status;
+use render::Pr;
+use std::iter::Peekable;
+use std::vec::IntoIter;
+
+/// Maximum number of nested `NOT` and parentheses in a query, so a malicious query cannot overflow
+/// the stack while being parsed.
+const MAX_DEPTH: usize = 64;
+
+/// A parsed query, which can be evaluated against a PR.
+pub enum Query {
+    /// Matches if both sub-queries match.
+    And(Box<Query>, Box<Query>),
+    /// Matches if any sub-query matches.
+    Or(Box<Query>, Box<Query>),
+    /// Matches if the sub-query does not match.
+    Not(Box<Query>),
+    /// Matches PRs authored by this user (lowercase).
+    Author(String),
+    /// Matches PRs approved by this user (lowercase).
+    Approver(String),
+    /// Matches PRs assigned to this user (lowercase).
+    Assignee(String),
+    /// Matches PRs whose review is requested from this user or team (`«org»/«team»`, lowercase).
+    Reviewer(String),
+    /// Matches PRs having this review decision.
+    Review(PullRequestReviewDecision),
+    /// Matches PRs having this label (lowercase).
+    Label(String),
+    /// Matches PRs having this approval status.
+    Status(Status),
+    /// Matches PRs with a CI status in this state.
+    Ci {
+        /// If given, the name of the CI status must contain this string (lowercase).
+        context: Option<String>,
+        /// The state of the CI status.
+        state: StatusState,
+    },
+    /// Matches the PR with this number.
+    Number(u32),
+    /// Matches PRs whose title contains this string (lowercase).
+    Title(String),
+    /// Matches PRs having merge conflict.
+    Conflict,
+    /// Matches PRs with rollup priority.
+    Rollup,
+    /// Matches PRs whose approval status applies to a "try" run.
+    Trying,
+    /// Matches draft PRs.
+    Draft,
+    /// Matches PRs whose priority compares with the number.
+    Priority(Comparison, i32),
+    /// Matches PRs whose time since creation compares with the duration.
+    Age(Comparison, Duration),
+}
+
+/// A comparison operator.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    /// `<`
+    Less,
+    /// `<=`
+    LessOrEqual,
+    /// `=` or `:`
+    Equal,
+    /// `>=`
+    GreaterOrEqual,
+    /// `>`
+    Greater,
+}
+
+impl Comparison {
+    /// Compares the two values using this operator.
+    fn test<T: PartialOrd>(self, lhs: T, rhs: T) -> bool {
+        match self {
+            Comparison::Less => lhs < rhs,
+            Comparison::LessOrEqual => lhs <= rhs,
+            Comparison::Equal => lhs == rhs,
+            Comparison::GreaterOrEqual => lhs >= rhs,
+            Comparison::Greater => lhs > rhs,
+        }
+    }
+}
+
+/// A token of the query string.
+#[derive(Debug, PartialEq, Eq)]
+enum Token {
+    /// `(`
+    Open,
+    /// `)`
+    Close,
+    /// `AND`
+    And,
+    /// `OR`
+    Or,
+    /// `NOT`
+    Not,
+    /// A search term, e.g. `label:T-compiler` or `p>=1`.
+    Term(String),
+    /// A quoted search term, which always matches the title.
+    Quoted(String),
+}
+
+impl Query {
+    /// Parses a query string. Returns `None` if the query string is empty.
+    pub fn parse(s: &str) -> Result<Option<Self>, BadRequest> {
+        let mut tokens = tokenize(s)?.into_iter().peekable();
+        if tokens.peek().is_none() {
+            return Ok(None);
+        }
+        let query = parse_or(&mut tokens, 0)?;
+        match tokens.next() {
+            None => Ok(Some(query)),
+            Some(token) => Err(unexpected(Some(token))),
+        }
+    }
+
+    /// Checks whether the PR satisfies this query.
+    pub fn matches(&self, number: u32, pr: &Pr, now: DateTime<Utc>) -> bool {
+        match *self {
+            Query::And(ref lhs, ref rhs) => {
+                lhs.matches(number, pr, now) && rhs.matches(number, pr, now)
+            }
+            Query::Or(ref lhs, ref rhs) => {
+                lhs.matches(number, pr, now) || rhs.matches(number, pr, now)
+            }
+            Query::Not(ref query) => !query.matches(number, pr, now),
+            Query::Author(ref author) => pr.author.to_lowercase() == *author,
+            Query::Approver(ref approver) => pr.approver.to_lowercase() == *approver,
+            Query::Assignee(ref assignee) => pr
+                .assignees
+                .iter()
+                .any(|login| login.to_lowercase() == *assignee),
+            Query::Reviewer(ref reviewer) => pr
+                .requested_reviewers
+                .iter()
+                .chain(&pr.requested_teams)
+                .any(|name| name.to_lowercase() == *reviewer),
+            Query::Review(decision) => pr.review_decision == Some(decision),
+            Query::Label(ref name) => pr
+                .labels
+                .iter()
+                .any(|label| label.name.to_lowercase() == *name),
+            Query::Status(status) => pr.status == status,
+            Query::Ci { ref context, state } => pr.ci_status.iter().any(|ci| {
+                ci.state == state && context
+                    .as_ref()
+                    .map_or(true, |name| ci.context.to_lowercase().contains(name))
+            }),
+            Query::Number(n) => number == n,
+            Query::Title(ref title) => pr.title.to_lowercase().contains(title),
+            Query::Conflict => pr.mergeable == MergeableState::Conflicting,
+            Query::Rollup => pr.priority < 0,
+            Query::Trying => pr.is_trying,
+            Query::Draft => pr.is_draft,
+            Query::Priority(cmp, priority) => cmp.test(pr.priority, priority),
+            Query::Age(cmp, age) => cmp.test(now.signed_duration_since(pr.created_at), age),
+        }
+    }
+}
+
+/// Splits the query string into tokens.
+fn tokenize(s: &str) -> Result<Vec<Token>, BadRequest> {
+    let mut tokens = Vec::new();
+    let mut chars = s.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            '(' => {
+                chars.next();
+                tokens.push(Token::Open);
+            }
+            ')' => {
+                chars.next();
+                tokens.push(Token::Close);
+            }
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            _ => {
+                let is_quoted = c == '"';
+                let mut in_quotes = false;
+                let mut term = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c == '"' {
+                        in_quotes = !in_quotes;
+                    } else if !in_quotes && (c.is_whitespace() || c == '(' || c == ')') {
+                        break;
+                    } else {
+                        term.push(c);
+                    }
+                    chars.next();
+                }
+                if in_quotes {
+                    return Err(BadRequest("unterminated quote in query".to_owned()));
+                }
+                tokens.push(if is_quoted {
+                    Token::Quoted(term)
+                } else if term.eq_ignore_ascii_case("and") {
+                    Token::And
+                } else if term.eq_ignore_ascii_case("or") {
+                    Token::Or
+                } else if term.eq_ignore_ascii_case("not") {
+                    Token::Not
+                } else {
+                    Token::Term(term)
+                });
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// The stream of tokens being parsed.
+type Tokens = Peekable<IntoIter<Token>>;
+
+/// Parses `a OR b OR …`. The `depth` is the number of enclosing `NOT` and parentheses.
+fn parse_or(tokens: &mut Tokens, depth: usize) -> Result<Query, BadRequest> {
+    let mut query = parse_and(tokens, depth)?;
+    while tokens.peek() == Some(&Token::Or) {
+        tokens.next();
+        let rhs = parse_and(tokens, depth)?;
+        query = Query::Or(Box::new(query), Box::new(rhs));
+    }
+    Ok(query)
+}
+
+/// Parses `a AND b AND …`. The `AND` keyword may be omitted.
+fn parse_and(tokens: &mut Tokens, depth: usize) -> Result<Query, BadRequest> {
+    let mut query = parse_not(tokens, depth)?;
+    loop {
+        match tokens.peek() {
+            Some(&Token::And) => {
+                tokens.next();
+            }
+            Some(&Token::Open) | Some(&Token::Not) | Some(&Token::Term(_))
+            | Some(&Token::Quoted(_)) => {}
+            _ => break,
+        }
+        let rhs = parse_not(tokens, depth)?;
+        query = Query::And(Box::new(query), Box::new(rhs));
+    }
+    Ok(query)
+}
+
+/// Parses `NOT a`, `(a)` or a single term.
+fn parse_not(tokens: &mut Tokens, depth: usize) -> Result<Query, BadRequest> {
+    match tokens.next() {
+        Some(Token::Not) | Some(Token::Open) if depth >= MAX_DEPTH => Err(BadRequest(format!(
+            "query is nested deeper than {} levels",
+            MAX_DEPTH
+        ))),
+        Some(Token::Not) => Ok(Query::Not(Box::new(parse_not(tokens, depth + 1)?))),
+        Some(Token::Open) => {
+            let query = parse_or(tokens, depth + 1)?;
+            match tokens.next() {
+                Some(Token::Close) => Ok(query),
+                token => Err(unexpected(token)),
+            }
+        }
+        Some(Token::Term(term)) => parse_term(&term),
+        Some(Token::Quoted(title)) => Ok(Query::Title(title.to_lowercase())),
+        token => Err(unexpected(token)),
+    }
+}
+
+/// Creates the error for an unexpected token.
+fn unexpected(token: Option<Token>) -> BadRequest {
+    BadRequest(match token {
+        None => "unexpected end of query".to_owned(),
+        Some(Token::Open) => "unexpected `(` in query".to_owned(),
+        Some(Token::Close) => "unexpected `)` in query".to_owned(),
+        Some(Token::And) => "unexpected `AND` in query".to_owned(),
+        Some(Token::Or) => "unexpected `OR` in query".to_owned(),
+        Some(Token::Not) => "unexpected `NOT` in query".to_owned(),
+        Some(Token::Term(term)) | Some(Token::Quoted(term)) => {
+            format!("unexpected {:?} in query", term)
+        }
+    })
+}
+
+/// Parses a single search term such as `label:T-compiler`, `p>=1` or `conflict`.
+fn parse_term(term: &str) -> Result<Query, BadRequest> {
+    let op_start = match term.find(|c| c == ':' || c == '<' || c == '>' || c == '=') {
+        Some(i) => i,
+        None => return Ok(parse_bare_term(term)),
+    };
+    let field = term[..op_start].to_ascii_lowercase();
+    let rest = &term[op_start..];
+    let (cmp, op_len) = if rest.starts_with("<=") {
+        (Comparison::LessOrEqual, 2)
+    } else if rest.starts_with(">=") {
+        (Comparison::GreaterOrEqual, 2)
+    } else if rest.starts_with('<') {
+        (Comparison::Less, 1)
+    } else if rest.starts_with('>') {
+        (Comparison::Greater, 1)
+    } else {
+        (Comparison::Equal, 1)
+    };
+    let value = &rest[op_len..];
+
+    match &*field {
+        "p" | "priority" => {
+            let priority = value
+                .parse()
+                .map_err(|_| BadRequest(format!("invalid priority {:?} in query", value)))?;
+            return Ok(Query::Priority(cmp, priority));
+        }
+        "age" => return Ok(Query::Age(cmp, parse_age(value)?)),
+        _ => {}
+    }
+
+    if cmp != Comparison::Equal {
+        return Err(BadRequest(format!("cannot compare {:?} in query", field)));
+    }
+    let lowercase = value.to_lowercase();
+    Ok(match &*field {
+        "author" => Query::Author(lowercase),
+        "approver" => Query::Approver(lowercase),
+        "assignee" => Query::Assignee(lowercase),
+        "reviewer" => Query::Reviewer(lowercase),
+        "review" => Query::Review(parse_review_decision(&lowercase)?),
+        "label" => Query::Label(lowercase),
+        "title" => Query::Title(lowercase),
+        "status" => Query::Status(
+            value
+                .parse()
+                .map_err(|()| BadRequest(format!("unknown status {:?} in query", value)))?,
+        ),
+        "ci" => {
+            let mut parts = lowercase.rsplitn(2, ':');
+            let state = parts.next().unwrap_or("");
+            Query::Ci {
+                state: parse_ci_state(state)?,
+                context: parts.next().map(str::to_owned),
+            }
+        }
+        "number" => Query::Number(
+            value
+                .trim_left_matches('#')
+                .parse()
+                .map_err(|_| BadRequest(format!("invalid PR number {:?} in query", value)))?,
+        ),
+        _ => return Err(BadRequest(format!("unknown field {:?} in query", field))),
+    })
+}
+
+/// Parses a search term without any field.
+fn parse_bare_term(term: &str) -> Query {
+    let lowercase = term.to_lowercase();
+    match &*lowercase {
+        "conflict" => return Query::Conflict,
+        "rollup" => return Query::Rollup,
+        "trying" => return Query::Trying,
+        "draft" => return Query::Draft,
+        _ => {}
+    }
+    if let Ok(status) = lowercase.parse() {
+        return Query::Status(status);
+    }
+    if let Ok(number) = lowercase.trim_left_matches('#').parse() {
+        return Query::Number(number);
+    }
+    Query::Title(lowercase)
+}
+
+/// Parses the state of a CI status.
+fn parse_ci_state(state: &str) -> Result<StatusState, BadRequest> {
+    Ok(match state {
+        "expected" => StatusState::Expected,
+        "error" => StatusState::Error,
+        "failure" => StatusState::Failure,
+        "pending" => StatusState::Pending,
+        "success" => StatusState::Success,
+        _ => return Err(BadRequest(format!("unknown CI state {:?} in query", state))),
+    })
+}
+
+/// Parses a review decision.
+fn parse_review_decision(decision: &str) -> Result<PullRequestReviewDecision, BadRequest> {
+    Ok(match decision {
+        "approved" => PullRequestReviewDecision::Approved,
+        "changes_requested" => PullRequestReviewDecision::ChangesRequested,
+        "required" | "review_required" => PullRequestReviewDecision::ReviewRequired,
+        _ => {
+            return Err(BadRequest(format!(
+                "unknown review decision {:?} in query",
+                decision
+            )))
+        }
+    })
+}
+
+/// Parses an age like `3d`. The unit can be `m` (minutes), `h` (hours), `d` (days, default) or
+/// `w` (weeks).
+fn parse_age(age: &str) -> Result<Duration, BadRequest> {
+    let invalid = || BadRequest(format!("invalid age {:?} in query", age));
+    let unit_start = age
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or_else(|| age.len());
+    let amount = age[..unit_start].parse::<i64>().map_err(|_| invalid())?;
+    let unit_secs = match &age[unit_start..] {
+        "m" => 60,
+        "h" => 3600,
+        "" | "d" => 86400,
+        "w" => 604_800,
+        _ => return Err(invalid()),
+    };
+    // `Duration::seconds()` panics when the duration cannot be represented in milliseconds.
+    match amount.checked_mul(unit_secs) {
+        Some(secs) if secs <= Duration::max_value().num_seconds() => Ok(Duration::seconds(secs)),
+        _ => Err(invalid()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(s: &str) -> Vec<Token> {
+        tokenize(s).unwrap_or_else(|e| panic!("cannot tokenize {:?}: {}", s, e.0))
+    }
+
+    fn error(s: &str) -> String {
+        match Query::parse(s) {
+            Ok(_) => panic!("{:?} should be rejected", s),
+            Err(e) => e.0,
+        }
+    }
+
+    /// Checks whether the query matches a PR with the given title.
+    fn matches_title(query: &str, title: &str) -> bool {
+        let pr = Pr {
+            title: title.to_owned(),
+            ..Pr::default()
+        };
+        match Query::parse(query) {
+            Ok(Some(query)) => query.matches(1, &pr, Utc::now()),
+            _ => panic!("cannot parse {:?}", query),
+        }
+    }
+
+    #[test]
+    fn test_tokenize() {
+        assert_eq!(
+            tokens(r#"label:T-compiler and (foo OR "bar baz") not"#),
+            vec![
+                Token::Term("label:T-compiler".to_owned()),
+                Token::And,
+                Token::Open,
+                Token::Term("foo".to_owned()),
+                Token::Or,
+                Token::Quoted("bar baz".to_owned()),
+                Token::Close,
+                Token::Not,
+            ]
+        );
+        assert_eq!(
+            tokens(r#"title:"a (b) c"  "or""#),
+            vec![
+                Token::Term("title:a (b) c".to_owned()),
+                Token::Quoted("or".to_owned()),
+            ]
+        );
+        assert!(tokens("   ").is_empty());
+    }
+
+    #[test]
+    fn test_empty_query() {
+        assert!(Query::parse("").unwrap().is_none());
+        assert!(Query::parse(" \t").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_and_binds_tighter_than_or() {
+        assert!(matches_title("foo OR bar baz", "foo"));
+        assert!(matches_title("foo OR bar baz", "bar baz"));
+        assert!(!matches_title("foo OR bar baz", "bar"));
+        assert!(!matches_title("foo OR bar AND baz", "baz"));
+        assert!(!matches_title("(foo OR bar) baz", "foo"));
+        assert!(matches_title("(foo OR bar) baz", "bar baz"));
+    }
+
+    #[test]
+    fn test_not() {
+        assert!(matches_title("NOT foo bar", "bar"));
+        assert!(!matches_title("NOT foo bar", "foo bar"));
+        assert!(!matches_title("NOT foo bar", "foo"));
+        assert!(matches_title("NOT (foo bar)", "foo"));
+        assert!(matches_title("not not foo", "foo"));
+    }
+
+    #[test]
+    fn test_quoted_terms() {
+        assert!(matches_title(r#""or""#, "Fix error"));
+        assert!(matches_title(r#""Foo Bar""#, "the foo bar fix"));
+        assert!(!matches_title(r#""foo bar""#, "bar foo"));
+        assert!(matches_title(r#""conflict""#, "resolve conflict"));
+    }
+
+    #[test]
+    fn test_age() {
+        let pr = Pr {
+            created_at: Utc::now() - Duration::days(2),
+            ..Pr::default()
+        };
+        let now = Utc::now();
+        let query = Query::parse("age>1d").unwrap().unwrap();
+        assert!(query.matches(1, &pr, now));
+        let query = Query::parse("age<1w").unwrap().unwrap();
+        assert!(query.matches(1, &pr, now));
+        let query = Query::parse("age<=47h").unwrap().unwrap();
+        assert!(!query.matches(1, &pr, now));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(error(r#""foo"#), "unterminated quote in query");
+        assert_eq!(error("(foo"), "unexpected end of query");
+        assert_eq!(error("foo)"), "unexpected `)` in query");
+        assert_eq!(error("OR foo"), "unexpected `OR` in query");
+        assert_eq!(error("foo AND"), "unexpected end of query");
+        assert_eq!(error("p>high"), r#"invalid priority "high" in query"#);
+        assert_eq!(error("label>x"), r#"cannot compare "label" in query"#);
+        assert_eq!(error("colour:red"), r#"unknown field "colour" in query"#);
+        assert_eq!(error("ci:done"), r#"unknown CI state "done" in query"#);
+        assert_eq!(error("age>3y"), r#"invalid age "3y" in query"#);
+    }
+
+    #[test]
+    fn test_age_overflow() {
+        assert_eq!(
+            error("age>99999999999999d"),
+            r#"invalid age "99999999999999d" in query"#
+        );
+        assert_eq!(
+            error("age>1537228672809129w"),
+            r#"invalid age "1537228672809129w" in query"#
+        );
+        assert_eq!(
+            error("age>99999999999999999999m"),
+            r#"invalid age "99999999999999999999m" in query"#
+        );
+        assert!(Query::parse("age>9999999999h").unwrap().is_some());
+    }
+
+    #[test]
+    fn test_depth_limit() {
+        let nested = |n| format!("{}foo{}", "(".repeat(n), ")".repeat(n));
+        assert!(Query::parse(&nested(MAX_DEPTH)).unwrap().is_some());
+        assert_eq!(error(&nested(MAX_DEPTH + 1)), "query is nested deeper than 64 levels");
+        assert_eq!(error(&"(".repeat(100_000)), "query is nested deeper than 64 levels");
+        assert_eq!(
+            error(&"NOT ".repeat(100_000)),
+            "query is nested deeper than 64 levels"
+        );
+        assert!(matches_title(&format!("{}foo", "NOT ".repeat(MAX_DEPTH)), "foo"));
+    }
+}
@@ -0,0 +1,200 @@
+//! Merge queue backends.
+//!
+//! The merge queue is maintained by a bot such as Homu or the new rust-lang/bors. Each bot exposes
+//! its queue differently, and is accessed through an implementation of [`QueueSource`].
+//!
+//! [`QueueSource`]: trait.QueueSource.html
+
+use bors::Bors;
+use chrono::{DateTime, Utc};
+use disk_cache;
+use failure::Error;
+use futures::{Future, IntoFuture, Stream};
+use homu::Homu;
+use kuchiki::traits::TendrilSink;
+use kuchiki::{parse_html, NodeRef};
+use metrics::{self, Upstream};
+use recording::{self, sanitize};
+use reqwest::async::Client;
+use reqwest::Url;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tendril::ByteTendril;
+
+/// An entry in the merge queue.
+pub struct Entry {
+    /// Pull request number.
+    pub number: u32,
+    /// Pull request title.
+    pub title: String,
+    /// Approval status.
+    pub status: Status,
+    /// Whether the approval status applies to a "try" run.
+    pub is_trying: bool,
+    /// Priority. Rollups are always assigned a priority of `-1`.
+    pub priority: i32,
+    /// Name of approver
+    pub approver: String,
+}
+
+/// The approval status of a pull request in the merge queue.
+#[derive(Serialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Status {
+    /// CI reported success, waiting for reviewer's further action.
+    Success,
+    /// The pull request is sent to the CI, and is waiting for test result.
+    Pending,
+    /// The pull request has been approved, waiting to be tested.
+    Approved,
+    /// The pull request is being actively reviewed.
+    Reviewing,
+    /// Error while testing the pull request, likely due to merge conflict.
+    Error,
+    /// CI reported failure.
+    Failure,
+}
+
+impl Default for Status {
+    fn default() -> Self {
+        Status::Reviewing
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Status::Success => "success",
+            Status::Pending => "pending",
+            Status::Approved => "approved",
+            Status::Reviewing => "reviewing",
+            Status::Error => "error",
+            Status::Failure => "failure",
+        })
+    }
+}
+
+impl FromStr for Status {
+    type Err = ();
+
+    /// Parses the status name, case-insensitively.
+    fn from_str(s: &str) -> Result<Self, ()> {
+        Ok(match &*s.to_ascii_lowercase() {
+            "success" => Status::Success,
+            "pending" => Status::Pending,
+            "approved" => Status::Approved,
+            "reviewing" => Status::Reviewing,
+            "error" => Status::Error,
+            "failure" => Status::Failure,
+            _ => return Err(()),
+        })
+    }
+}
+
+/// A source of merge queue entries.
+pub trait QueueSource {
+    /// Obtains the list of pull requests and associated information from the queue.
+    fn query(&self, client: &Client) -> Box<Future<Item = Vec<Entry>, Error = Error>>;
+
+    /// Obtains the list of pull requests from the on-disk cache, without accessing the network.
+    ///
+    /// Returns `None` if the queue is not cached, or the cached page cannot be parsed.
+    fn cached(&self) -> Option<(DateTime<Utc>, Vec<Entry>)>;
+}
+
+/// The kind of bot maintaining the merge queue of a repository.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// The original Homu bot, which renders the queue as an HTML table with fixed columns.
+    Homu,
+    /// The rewritten rust-lang/bors bot.
+    Bors,
+}
+
+impl Default for Backend {
+    fn default() -> Self {
+        Backend::Homu
+    }
+}
+
+impl FromStr for Backend {
+    type Err = ();
+
+    /// Parses the backend name, case-insensitively.
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match &*s.to_ascii_lowercase() {
+            "homu" => Ok(Backend::Homu),
+            "bors" => Ok(Backend::Bors),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Backend {
+    /// Creates a queue source of this backend reading from the given URL.
+    pub fn source(self, url: Url) -> Box<QueueSource> {
+        match self {
+            Backend::Homu => Box::new(Homu::new(url)),
+            Backend::Bors => Box::new(Bors::new(url)),
+        }
+    }
+}
+
+/// Downloads and parses an HTML page.
+///
+/// The raw page is recorded or replayed according to the current [`recording::Mode`].
+///
+/// [`recording::Mode`]: ../recording/enum.Mode.html
+pub fn fetch_html(client: &Client, url: &Url) -> Box<Future<Item = NodeRef, Error = Error>> {
+    let storage_path = html_storage_path(url);
+    if let Some(body) = recording::replay(&storage_path) {
+        return Box::new(body.map(|body| parse_html_bytes(&body)).into_future());
+    }
+
+    let fetch = metrics::instrument(
+        Upstream::Queue,
+        client
+            .get(url.clone())
+            .send()
+            .and_then(|response| response.error_for_status())
+            .and_then(|response| response.into_body().concat2())
+            .map_err(Error::from),
+    );
+    Box::new(fetch.map(move |body| {
+        recording::record(&storage_path, &body);
+        disk_cache::store(&storage_path, &body);
+        parse_html_bytes(&body)
+    }))
+}
+
+/// Obtains a previously downloaded HTML page from the on-disk cache, together with the time it was
+/// fetched.
+pub fn cached_html(url: &Url) -> Option<(DateTime<Utc>, NodeRef)> {
+    disk_cache::load(&html_storage_path(url))
+        .map(|(fetched_at, body)| (fetched_at, parse_html_bytes(&body)))
+}
+
+/// The relative path to store an HTML page, when recording or caching on disk.
+fn html_storage_path(url: &Url) -> PathBuf {
+    Path::new("queue").join(format!("{}.html", sanitize(url.as_str())))
+}
+
+/// Parses an HTML page from its raw UTF-8 bytes.
+fn parse_html_bytes(body: &[u8]) -> NodeRef {
+    parse_html().from_utf8().one(ByteTendril::from_slice(body))
+}
+
+/// Extracts the trimmed text content of every `<td>` cell in a table row.
+pub fn row_cells(tr: &NodeRef) -> Vec<String> {
+    tr.children()
+        .filter_map(|td| {
+            if let Some(elem) = td.as_element() {
+                if elem.name.expanded() == expanded_name!(html "td") {
+                    return Some(td.text_contents().trim().to_owned());
+                }
+            }
+            None
+        })
+        .collect()
+}
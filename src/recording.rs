@@ -0,0 +1,91 @@
+//! Recording and replaying raw responses from GitHub and the merge queue.
+//!
+//! In record mode, every raw response body is saved into a directory. In replay mode, the network
+//! is never accessed, and the saved bodies are read back instead. This allows reproducing rendering
+//! issues and demonstrating the dashboard offline.
+
+use failure::{Error, ResultExt};
+use std::fs::{create_dir_all, read, write};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+/// What to do with the raw responses from GitHub and the merge queue.
+pub enum Mode {
+    /// Fetch from the network without saving the responses.
+    Live,
+    /// Fetch from the network, and save every response into the directory.
+    Record(PathBuf),
+    /// Never access the network, and read every response from the directory instead.
+    Replay(PathBuf),
+}
+
+lazy_static! {
+    static ref MODE: RwLock<Mode> = RwLock::new(Mode::Live);
+}
+
+/// Changes the recording mode.
+pub fn set_mode(mode: Mode) -> Result<(), Error> {
+    if let Mode::Record(ref dir) = mode {
+        create_dir_all(dir)
+            .with_context(|_| format!("cannot create recording directory {}", dir.display()))?;
+    }
+    *MODE.write().expect("poisoned") = mode;
+    Ok(())
+}
+
+/// Checks whether the responses are being replayed instead of fetched from the network.
+pub fn is_replaying() -> bool {
+    match *MODE.read().expect("poisoned") {
+        Mode::Replay(_) => true,
+        _ => false,
+    }
+}
+
+/// Reads the recorded response at the relative path, if in replay mode.
+///
+/// Returns `None` if not in replay mode, in which case the response should be fetched from the
+/// network.
+pub fn replay(path: &Path) -> Option<Result<Vec<u8>, Error>> {
+    match *MODE.read().expect("poisoned") {
+        Mode::Replay(ref dir) => {
+            let path = dir.join(path);
+            info!("Replaying response from {}", path.display());
+            Some(
+                read(&path)
+                    .with_context(|_| format!("no recorded response at {}", path.display()))
+                    .map_err(Error::from),
+            )
+        }
+        _ => None,
+    }
+}
+
+/// Saves the raw response to the relative path, if in record mode.
+///
+/// Failure to save the response is logged but otherwise ignored.
+pub fn record(path: &Path, body: &[u8]) {
+    if let Mode::Record(ref dir) = *MODE.read().expect("poisoned") {
+        let path = dir.join(path);
+        let result = path
+            .parent()
+            .map_or(Ok(()), create_dir_all)
+            .and_then(|()| write(&path, body));
+        match result {
+            Ok(()) => debug!("Recorded response to {}", path.display()),
+            Err(e) => warn!("failed to record response to {}: {}", path.display(), e),
+        }
+    }
+}
+
+/// Converts an arbitrary string into something safe to use as a file name.
+pub fn sanitize(s: &str) -> String {
+    s.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
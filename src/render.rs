@@ -1,9 +1,13 @@
 //! Utilities for rendering the page via Tera.
 
 use chrono::{DateTime, Local, Utc};
-use github::graphql::{Label, MergeableState, PullRequest, StatusContext};
-use homu::{Entry, Status};
+use github::graphql::{
+    CheckConclusionState, CheckRun, Label, MergeableState, PullRequest,
+    PullRequestReviewDecision, PullRequestReviewState, StatusContext, StatusState,
+};
+use queue::{Entry, Status};
 use reqwest::Url;
+use spurious::Classification;
 use std::collections::HashMap;
 use std::fmt::Display;
 use std::str::FromStr;
@@ -14,52 +18,130 @@ use tera::{self, Tera, Value};
 #[derive(Serialize)]
 pub struct Pr {
     /// The author of the PR (GitHub username).
-    author: String,
+    pub author: String,
     /// When the PR was opened.
-    created_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
     /// Last update time of the PR.
-    updated_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
     /// Whether the PR can be merged cleanly.
-    mergeable: MergeableState,
+    pub mergeable: MergeableState,
     /// PR title.
-    title: String,
+    pub title: String,
+    /// Whether the PR is a draft, not ready for review.
+    pub is_draft: bool,
     /// Labels applied to the PR.
-    labels: Vec<Label>,
+    pub labels: Vec<Label>,
+    /// Users assigned to the PR (GitHub usernames).
+    pub assignees: Vec<String>,
+    /// Users requested to review the PR (GitHub usernames).
+    pub requested_reviewers: Vec<String>,
+    /// Teams requested to review the PR, as `«org»/«team»`.
+    pub requested_teams: Vec<String>,
+    /// The review decision, if the repository requires reviews.
+    pub review_decision: Option<PullRequestReviewDecision>,
+    /// The latest review of each reviewer.
+    pub latest_reviews: Vec<Review>,
     /// When the last commit of this PR was committed.
-    committed_at: DateTime<Utc>,
+    pub committed_at: DateTime<Utc>,
     /// CI status of the last commit.
-    ci_status: Vec<StatusContext>,
+    pub ci_status: Vec<StatusContext>,
+    /// Number of CI jobs of the last commit by outcome.
+    pub ci_summary: CiSummary,
+    /// Whether any CI status of the last commit has failed.
+    pub has_ci_failure: bool,
+    /// Verdicts on the failed CI statuses, only filled in for PRs which failed in the merge queue.
+    pub failures: Vec<Classification>,
+    /// Whether every failure is known to be spurious, so the PR may just be retried.
+    pub is_retry_candidate: bool,
     /// Recent actions performed on the PR.
-    timeline: Vec<Value>,
+    pub timeline: Vec<Value>,
     /// Approval status.
-    status: Status,
+    pub status: Status,
     /// Whether the approval status applies to a "try" run.
-    is_trying: bool,
+    pub is_trying: bool,
     /// Priority. Rollups are always assigned a priority of `-1`.
-    priority: i32,
+    pub priority: i32,
     /// PR approver name.
-    approver: String,
+    pub approver: String,
     /// Number of additions to the PR.
-    additions: u32,
+    pub additions: u32,
     /// Number of deletions to the PR.
-    deletions: u32,
+    pub deletions: u32,
     /// Base branch name of the PR.
-    base_ref_name: String,
+    pub base_ref_name: String,
     /// Branch name of the PR in the author's repository.
-    head_ref_name: String,
+    pub head_ref_name: String,
     /// PR body text.
-    body: String,
+    pub body: String,
+}
+
+/// The latest review of a reviewer on a PR.
+#[derive(Serialize)]
+pub struct Review {
+    /// The reviewer (GitHub username).
+    pub reviewer: String,
+    /// Whether the reviewer approved, requested changes or just commented.
+    pub state: PullRequestReviewState,
+}
+
+/// Number of CI jobs of a commit by outcome, counting both commit statuses and check runs.
+#[derive(Serialize, Default, Clone, Copy)]
+pub struct CiSummary {
+    /// Total number of jobs.
+    pub total: u32,
+    /// Number of jobs which succeeded. Neutral and skipped check runs are counted as passed.
+    pub passed: u32,
+    /// Number of jobs which failed, errored or timed out.
+    pub failed: u32,
+    /// Number of jobs which have not finished.
+    pub pending: u32,
+    /// Number of check runs which were cancelled or superseded.
+    pub cancelled: u32,
+}
+
+impl CiSummary {
+    /// Counts the commit statuses and check runs.
+    pub fn new(statuses: &[StatusContext], check_runs: &[CheckRun]) -> Self {
+        let mut summary = Self::default();
+        for status in statuses {
+            match status.state {
+                StatusState::Success => summary.passed += 1,
+                StatusState::Error | StatusState::Failure => summary.failed += 1,
+                StatusState::Expected | StatusState::Pending => summary.pending += 1,
+            }
+        }
+        // Keep in sync with `From<CheckRun> for StatusContext`.
+        for run in check_runs {
+            match run.conclusion {
+                None => summary.pending += 1,
+                Some(CheckConclusionState::Success)
+                | Some(CheckConclusionState::Neutral)
+                | Some(CheckConclusionState::Skipped) => summary.passed += 1,
+                Some(CheckConclusionState::Cancelled) | Some(CheckConclusionState::Stale) => {
+                    summary.cancelled += 1
+                }
+                Some(CheckConclusionState::ActionRequired)
+                | Some(CheckConclusionState::Failure)
+                | Some(CheckConclusionState::StartupFailure)
+                | Some(CheckConclusionState::TimedOut) => summary.failed += 1,
+            }
+        }
+        summary.total = summary.passed + summary.failed + summary.pending + summary.cancelled;
+        summary
+    }
 }
 
 /// Statistics about all the pull requests in the queue.
 #[derive(Serialize, Default)]
 pub struct PrStats {
-    /// Total number of pull requests.
-    count: u32,
+    /// Total number of pull requests, excluding drafts.
+    pub count: u32,
+    /// Total number of draft pull requests.
+    pub drafts: u32,
     /// Total number of approved, mergeable PRs.
-    approved: u32,
+    pub approved: u32,
     /// Total number of approved, mergeable PRs with rollup priority.
-    rollups: u32,
+    pub rollups: u32,
 }
 
 // Cannot derive default since it is not implemented for DateTime.
@@ -71,9 +153,19 @@ impl Default for Pr {
             updated_at: UNIX_EPOCH.into(),
             mergeable: MergeableState::Unknown,
             title: String::new(),
+            is_draft: false,
             labels: Vec::new(),
+            assignees: Vec::new(),
+            requested_reviewers: Vec::new(),
+            requested_teams: Vec::new(),
+            review_decision: None,
+            latest_reviews: Vec::new(),
             committed_at: UNIX_EPOCH.into(),
             ci_status: Vec::new(),
+            ci_summary: CiSummary::default(),
+            has_ci_failure: false,
+            failures: Vec::new(),
+            is_retry_candidate: false,
             timeline: Vec::new(),
             status: Status::Reviewing,
             is_trying: false,
@@ -88,25 +180,43 @@ impl Default for Pr {
     }
 }
 
-/// Combines information from GitHub and Homu to get a list of pull request information.
-pub fn parse_prs(github_entries: Vec<PullRequest>, homu_entries: Vec<Entry>) -> HashMap<u32, Pr> {
+/// Combines information from GitHub and the merge queue to get a list of pull request information.
+pub fn parse_prs(github_entries: Vec<PullRequest>, queue_entries: Vec<Entry>) -> HashMap<u32, Pr> {
     let mut prs = HashMap::new();
 
     for mut gh in github_entries {
         let commit = gh.commits.nodes.swap_remove(0).commit;
-        let ci_status = commit
-            .status
+        let statuses = commit.status.map_or_else(Vec::new, |status| status.contexts);
+        let check_runs = commit
+            .check_suites
+            .nodes
             .into_iter()
-            .flat_map(|status| status.contexts)
-            .chain(
-                commit
-                    .check_suites
-                    .nodes
-                    .into_iter()
-                    .flat_map(|suite| suite.check_runs.nodes)
-                    .map(Into::into),
-            )
+            .flat_map(|suite| suite.check_runs.nodes)
+            .collect::<Vec<_>>();
+        let ci_summary = CiSummary::new(&statuses, &check_runs);
+        let ci_status = statuses
+            .into_iter()
+            .chain(check_runs.into_iter().map(Into::into))
             .collect::<Vec<_>>();
+        let has_ci_failure = ci_status
+            .iter()
+            .any(|status| status.state == StatusState::Failure);
+        let mut labels = gh.labels.nodes;
+        // The events are in chronological order, so the last application of a label wins.
+        for event in gh.timeline_items.into_iter().flat_map(|items| items.nodes) {
+            if let Some(label) = labels.iter_mut().find(|l| l.name == event.label.name) {
+                label.applied_at = Some(event.created_at);
+            }
+        }
+        let mut requested_reviewers = Vec::new();
+        let mut requested_teams = Vec::new();
+        for reviewer in gh.review_requests.nodes.into_iter().filter_map(|r| r.requested_reviewer) {
+            match (reviewer.login, reviewer.combined_slug) {
+                (Some(login), _) => requested_reviewers.push(login),
+                (None, Some(slug)) => requested_teams.push(slug),
+                (None, None) => {}
+            }
+        }
 
         prs.insert(
             gh.number,
@@ -116,8 +226,26 @@ pub fn parse_prs(github_entries: Vec<PullRequest>, homu_entries: Vec<Entry>) ->
                 updated_at: gh.updated_at,
                 mergeable: gh.mergeable,
                 title: gh.title,
-                labels: gh.labels.nodes,
+                is_draft: gh.is_draft,
+                labels,
+                assignees: gh.assignees.nodes.into_iter().map(|a| a.login).collect(),
+                requested_reviewers,
+                requested_teams,
+                review_decision: gh.review_decision,
+                latest_reviews: gh
+                    .latest_reviews
+                    .nodes
+                    .into_iter()
+                    .filter_map(|review| {
+                        Some(Review {
+                            reviewer: review.author?.login,
+                            state: review.state,
+                        })
+                    })
+                    .collect(),
                 ci_status,
+                ci_summary,
+                has_ci_failure,
                 additions: gh.additions,
                 deletions: gh.deletions,
                 base_ref_name: gh.base_ref_name,
@@ -128,7 +256,7 @@ pub fn parse_prs(github_entries: Vec<PullRequest>, homu_entries: Vec<Entry>) ->
         );
     }
 
-    for h in homu_entries {
+    for h in queue_entries {
         let title = h.title;
         let pr = prs.entry(h.number).or_insert_with(move || Pr {
             title,
@@ -147,6 +275,10 @@ pub fn parse_prs(github_entries: Vec<PullRequest>, homu_entries: Vec<Entry>) ->
 pub fn summarize_prs<'b, I: IntoIterator<Item = &'b Pr>>(prs: I) -> PrStats {
     let mut stats = PrStats::default();
     for pr in prs {
+        if pr.is_draft {
+            stats.drafts += 1;
+            continue;
+        }
         stats.count += 1;
         if pr.status == Status::Approved && pr.mergeable != MergeableState::Conflicting {
             stats.approved += 1;
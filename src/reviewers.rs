@@ -0,0 +1,242 @@
+//! Review workload of each reviewer, aggregated from the open PRs.
+//!
+//! A PR is on a reviewer's plate if it is assigned to them (the `r?` reviewer on rust-lang/rust)
+//! or their review is requested. Team review requests are aggregated under the team.
+
+use chrono::{DateTime, Utc};
+use render::Pr;
+use serde_urlencoded;
+use std::collections::{BTreeMap, HashMap};
+
+/// The label of PRs waiting on the reviewer.
+const WAITING_ON_REVIEW_LABEL: &str = "S-waiting-on-review";
+
+/// The review workload of a user or a team.
+#[derive(Serialize)]
+pub struct Workload {
+    /// GitHub username of the reviewer, or `«org»/«team»` for a team.
+    pub reviewer: String,
+    /// Whether the reviewer is a team.
+    pub is_team: bool,
+    /// Number of open PRs assigned to or requested from the reviewer, excluding drafts.
+    pub assigned: u32,
+    /// Number of those PRs waiting on review.
+    pub waiting: u32,
+    /// When the PR which has been waiting on review the longest was labeled as such. If the label
+    /// was applied before the last few label events of the PR, its creation time is used instead.
+    pub oldest_wait: Option<DateTime<Utc>>,
+    /// Number of PRs in the merge queue approved by the reviewer.
+    pub approved: u32,
+    /// Query string of the dashboard showing the PRs assigned to or requested from the reviewer.
+    pub queue_link: String,
+    /// Query string of the dashboard showing the PRs approved by the reviewer.
+    pub approved_link: String,
+}
+
+impl Workload {
+    /// Creates an empty workload of the reviewer.
+    fn new(reviewer: &str, is_team: bool) -> Self {
+        let query = if is_team {
+            format!("reviewer:{}", reviewer)
+        } else {
+            format!("assignee:{0} OR reviewer:{0}", reviewer)
+        };
+        Self {
+            reviewer: reviewer.to_owned(),
+            is_team,
+            assigned: 0,
+            waiting: 0,
+            oldest_wait: None,
+            approved: 0,
+            queue_link: query_link(&query),
+            approved_link: query_link(&format!("approver:{}", reviewer)),
+        }
+    }
+}
+
+/// Formats the query string to filter the dashboard with the query.
+fn query_link(query: &str) -> String {
+    serde_urlencoded::to_string(&[("q", query)]).expect("query string")
+}
+
+/// Aggregates the workload of every reviewer of the PRs. The reviewers with the most PRs waiting
+/// on review come first.
+pub fn aggregate(prs: &HashMap<u32, Pr>) -> Vec<Workload> {
+    // Keyed by the lowercase name, since GitHub usernames are case-insensitive.
+    let mut workloads = BTreeMap::new();
+
+    for pr in prs.values() {
+        if !pr.approver.is_empty() {
+            workloads
+                .entry(pr.approver.to_lowercase())
+                .or_insert_with(|| Workload::new(&pr.approver, false))
+                .approved += 1;
+        }
+        if pr.is_draft {
+            continue;
+        }
+
+        let waiting_since = pr
+            .labels
+            .iter()
+            .find(|label| label.name.eq_ignore_ascii_case(WAITING_ON_REVIEW_LABEL))
+            .map(|label| label.applied_at.unwrap_or(pr.created_at));
+        let mut reviewers = pr
+            .assignees
+            .iter()
+            .chain(&pr.requested_reviewers)
+            .map(|login| (login, false))
+            .chain(pr.requested_teams.iter().map(|team| (team, true)))
+            .map(|(name, is_team)| (name.to_lowercase(), name, is_team))
+            .collect::<Vec<_>>();
+        // A PR both assigned to and requested from the same user is counted once.
+        reviewers.sort_by(|a, b| a.0.cmp(&b.0));
+        reviewers.dedup_by(|a, b| a.0 == b.0);
+
+        for (key, name, is_team) in reviewers {
+            let workload = workloads
+                .entry(key)
+                .or_insert_with(|| Workload::new(name, is_team));
+            workload.assigned += 1;
+            if let Some(since) = waiting_since {
+                workload.waiting += 1;
+                if workload.oldest_wait.map_or(true, |t| since < t) {
+                    workload.oldest_wait = Some(since);
+                }
+            }
+        }
+    }
+
+    let mut workloads = workloads.into_iter().map(|(_, w)| w).collect::<Vec<_>>();
+    workloads.sort_by(|a, b| {
+        b.waiting
+            .cmp(&a.waiting)
+            .then(b.assigned.cmp(&a.assigned))
+            .then(b.approved.cmp(&a.approved))
+    });
+    workloads
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{aggregate, Workload, WAITING_ON_REVIEW_LABEL};
+    use chrono::{DateTime, Utc};
+    use github::graphql::Label;
+    use render::Pr;
+    use std::collections::HashMap;
+
+    /// Parses an RFC 3339 time.
+    fn time(s: &str) -> DateTime<Utc> {
+        s.parse().unwrap()
+    }
+
+    /// Creates a PR assigned to and requested from the given users.
+    fn pr(assignees: &[&str], requested_reviewers: &[&str]) -> Pr {
+        Pr {
+            created_at: time("2019-01-01T00:00:00Z"),
+            assignees: assignees.iter().map(|s| (*s).to_owned()).collect(),
+            requested_reviewers: requested_reviewers.iter().map(|s| (*s).to_owned()).collect(),
+            ..Pr::default()
+        }
+    }
+
+    /// The waiting-on-review label, applied at the given time if known.
+    fn waiting_label(applied_at: Option<&str>) -> Label {
+        Label {
+            name: WAITING_ON_REVIEW_LABEL.to_owned(),
+            color: "ffffff".to_owned(),
+            applied_at: applied_at.map(time),
+        }
+    }
+
+    /// Finds the workload of a reviewer.
+    fn find<'a>(workloads: &'a [Workload], reviewer: &str) -> &'a Workload {
+        workloads
+            .iter()
+            .find(|w| w.reviewer.eq_ignore_ascii_case(reviewer))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_aggregate_dedup() {
+        let mut prs = HashMap::new();
+        prs.insert(
+            1,
+            Pr {
+                requested_teams: vec!["rust-lang/compiler".to_owned()],
+                labels: vec![waiting_label(None)],
+                ..pr(&["Alice"], &["alice", "bob"])
+            },
+        );
+        prs.insert(
+            2,
+            Pr {
+                is_draft: true,
+                ..pr(&["alice"], &[])
+            },
+        );
+        prs.insert(
+            3,
+            Pr {
+                approver: "ALICE".to_owned(),
+                ..pr(&["bob"], &[])
+            },
+        );
+
+        let workloads = aggregate(&prs);
+        assert_eq!(workloads.len(), 3);
+
+        let alice = find(&workloads, "alice");
+        assert!(!alice.is_team);
+        assert_eq!((alice.assigned, alice.waiting, alice.approved), (1, 1, 1));
+
+        let bob = find(&workloads, "bob");
+        assert_eq!((bob.assigned, bob.waiting, bob.approved), (2, 1, 0));
+        assert_eq!(bob.queue_link, "q=assignee%3Abob+OR+reviewer%3Abob");
+
+        let team = find(&workloads, "rust-lang/compiler");
+        assert!(team.is_team);
+        assert_eq!((team.assigned, team.waiting, team.approved), (1, 1, 0));
+        assert_eq!(team.queue_link, "q=reviewer%3Arust-lang%2Fcompiler");
+
+        // Ordered by PRs waiting on review, then by PRs assigned, then by PRs approved.
+        let order = workloads.iter().map(|w| &*w.reviewer).collect::<Vec<_>>();
+        assert_eq!(order, ["bob", "Alice", "rust-lang/compiler"]);
+    }
+
+    #[test]
+    fn test_aggregate_oldest_wait() {
+        let mut prs = HashMap::new();
+        prs.insert(
+            1,
+            Pr {
+                labels: vec![waiting_label(Some("2019-03-01T00:00:00Z"))],
+                ..pr(&["alice", "bob"], &[])
+            },
+        );
+        prs.insert(
+            2,
+            Pr {
+                created_at: time("2019-02-01T00:00:00Z"),
+                labels: vec![waiting_label(None)],
+                ..pr(&["alice"], &[])
+            },
+        );
+        prs.insert(3, pr(&["carol"], &[]));
+
+        let workloads = aggregate(&prs);
+
+        // The label time wins over the creation time when known.
+        let bob = find(&workloads, "bob");
+        assert_eq!(bob.oldest_wait, Some(time("2019-03-01T00:00:00Z")));
+
+        // Otherwise the creation time is used, and the oldest of all waiting PRs is kept.
+        let alice = find(&workloads, "alice");
+        assert_eq!(alice.waiting, 2);
+        assert_eq!(alice.oldest_wait, Some(time("2019-02-01T00:00:00Z")));
+
+        let carol = find(&workloads, "carol");
+        assert_eq!((carol.assigned, carol.waiting), (1, 0));
+        assert_eq!(carol.oldest_wait, None);
+    }
+}
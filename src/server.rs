@@ -1,36 +1,63 @@
 //! Local server of borsholder.
 
+use api::{BadRequest, Filter, Forbidden, PayloadTooLarge, PrsReply, StatsReply, TooManyRequests};
 use args::Args;
-use failure::Error;
+use chrono::{DateTime, Utc};
+use config::{Config, Repository};
+use failure::{err_msg, Error};
 use flate2::{write::GzEncoder, Compression};
-use futures::future::{empty, result, Future};
+use futures::future::{empty, join_all, ok, result, Future, Shared, SharedError};
+use futures::unsync::mpsc::{unbounded, UnboundedSender};
 use futures::Stream;
+use github::graphql::PullRequestState;
+use github::{BudgetExhausted, PullRequestSet, RateLimit};
 use hyper::header::CacheDirective::{MaxAge, Public};
-use hyper::header::{AcceptEncoding, CacheControl, ContentEncoding, ContentType, Encoding};
+use hyper::header::{
+    AcceptEncoding, Allow, CacheControl, ContentEncoding, ContentLength, ContentType, Cookie,
+    Encoding, Location, SetCookie,
+};
 use hyper::server::{Http, Request, Response, Service};
-use hyper::{self, StatusCode};
+use hyper::{self, Method, StatusCode};
+use metrics::RepoMetrics;
 use mime::{Mime, IMAGE_PNG, TEXT_CSS, TEXT_HTML_UTF_8, TEXT_JAVASCRIPT};
+use notify;
+use queue::{QueueSource, Status};
 use regex::bytes::Regex;
 use render::{parse_prs, register_tera_filters, summarize_prs, Pr, PrStats, TeraFailure};
 use reqwest::async::Client;
 use reqwest::header::{HeaderMap, HeaderValue, CONNECTION};
 use reqwest::Proxy;
-use std::cell::RefCell;
+use serde::Serialize;
+use serde_json;
+use session::{self, Session, SESSION_COOKIE, SESSION_EXPIRY, STATE_COOKIE};
+use spurious::Classification;
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
 use std::ffi::OsStr;
+use std::fmt::Write;
 use std::fs::File;
 use std::io::{self, Read};
+use std::mem::replace;
 use std::rc::Rc;
 use std::str::from_utf8;
-use std::time::Duration;
-use tera::{Tera, Value};
-use tokio_core::reactor::Core;
+use std::time::{Duration, Instant};
+use tera::Tera;
+use tokio_core::reactor::{Core, Handle, Interval, Timeout};
+use webhook;
+
+/// Number of seconds to wait after a webhook delivery before refreshing the repository, so a burst
+/// of deliveries only causes a single refresh.
+const WEBHOOK_REFRESH_DELAY: u64 = 5;
+
+/// Minimum number of seconds between two syncs of a repository requested at `/sync`, since each
+/// of them spends the rate limit budget on a full sync.
+const MIN_SYNC_INTERVAL: u64 = 60;
 
 /// Serves the borsholder web page configured according to `args`.
 ///
 /// This method will not return until the server is shutdown.
-pub fn serve(mut args: Args) -> Result<(), Error> {
-    let tera_pattern_os = args.templates.join("*.html").into_os_string();
+pub fn serve(args: Args, config: Config) -> Result<(), Error> {
+    let tera_pattern_os = config.templates.join("*.html").into_os_string();
     let tera_pattern = tera_pattern_os.to_string_lossy();
     let mut tera = Tera::new(&tera_pattern).map_err(TeraFailure::from)?;
     register_tera_filters(&mut tera);
@@ -39,8 +66,8 @@ pub fn serve(mut args: Args) -> Result<(), Error> {
     let handle = core.handle();
 
     let mut builder = Client::builder();
-    if let Some(proxy) = args.proxy.take() {
-        builder = builder.proxy(Proxy::all(proxy)?);
+    if let Some(ref proxy) = config.proxy {
+        builder = builder.proxy(Proxy::all(proxy.clone())?);
     }
     let mut default_headers = HeaderMap::new();
     default_headers.insert(CONNECTION, HeaderValue::from_str("Close").unwrap());
@@ -49,13 +76,70 @@ pub fn serve(mut args: Args) -> Result<(), Error> {
         .default_headers(default_headers)
         .build()?;
 
-    let address = args.address;
+    let address = config.address;
+    let refresh_interval = Duration::from_secs(config.refresh_interval);
+    let repos = config.repos.iter().cloned().map(RepoState::new).collect();
+    let (refresh_requests, refresh_receiver) = unbounded();
     let handler = Rc::new(Handler {
         tera: Rc::new(RefCell::new(tera)),
         client,
-        args: Rc::new(args),
+        args,
+        config: RefCell::new(Rc::new(config)),
+        repos: RefCell::new(repos),
+        handle: handle.clone(),
+        refresh_requests,
     });
 
+    spawn_config_reloader(&handle, Rc::clone(&handler));
+
+    let poller = Rc::clone(&handler);
+    handle.spawn(
+        Interval::new_at(Instant::now(), refresh_interval, &handle)?
+            .map_err(Error::from)
+            .for_each(move |()| {
+                let refreshes = poller
+                    .repos
+                    .borrow()
+                    .iter()
+                    .map(|state| {
+                        let repo = state.repo.to_string();
+                        poller.refresh(state).then(move |res| {
+                            if let Err(e) = res {
+                                warn!("failed to refresh PRs of {}: {}", repo, e);
+                            }
+                            Ok::<_, Error>(())
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                join_all(refreshes).map(|_| ())
+            })
+            .map_err(|e| error!("background refresh stopped: {}", e)),
+    );
+
+    let refresher = Rc::clone(&handler);
+    let refresher_handle = handle.clone();
+    handle.spawn(refresh_receiver.for_each(move |state: Rc<RepoState>| {
+        let refresher = Rc::clone(&refresher);
+        let delay = Timeout::new(Duration::from_secs(WEBHOOK_REFRESH_DELAY), &refresher_handle)
+            .map_err(|e| error!("cannot schedule refresh: {}", e))?;
+        let repo = state.repo.to_string();
+        refresher_handle.spawn(
+            delay
+                .map_err(Error::from)
+                .and_then(move |()| {
+                    state.refresh_pending.set(false);
+                    refresher.refresh_again(&state)
+                })
+                .then(move |res| {
+                    if let Err(e) = res {
+                        warn!("failed to refresh PRs of {}: {}", repo, e);
+                    }
+                    Ok(())
+                }),
+        );
+        Ok(())
+    }));
+
     let serve =
         Http::new().serve_addr_handle(&address, &handle, move || Ok(Rc::clone(&handler)))?;
 
@@ -73,32 +157,215 @@ pub fn serve(mut args: Args) -> Result<(), Error> {
     Ok(())
 }
 
+/// Reloads the configuration whenever the process receives SIGHUP.
+#[cfg(unix)]
+fn spawn_config_reloader(handle: &Handle, handler: Rc<Handler>) {
+    use tokio_signal::unix::{Signal, SIGHUP};
+
+    handle.spawn(
+        Signal::new(SIGHUP)
+            .flatten_stream()
+            .for_each(move |_| {
+                info!("Received SIGHUP, reloading configuration");
+                if let Err(e) = handler.reload_config() {
+                    error!("failed to reload configuration: {}", e);
+                }
+                Ok(())
+            })
+            .map_err(|e| error!("cannot listen to SIGHUP: {}", e)),
+    );
+}
+
+/// Reloads the configuration whenever the process receives SIGHUP.
+///
+/// This is a no-op on platforms without signals.
+#[cfg(not(unix))]
+fn spawn_config_reloader(_: &Handle, _: Rc<Handler>) {}
+
 /// Request handler of the borsholder server.
 struct Handler {
     /// The Tera template engine.
     tera: Rc<RefCell<Tera>>,
     /// The reqwest client for making API requests.
     client: Client,
-    /// The command line arguments.
-    args: Rc<Args>,
+    /// The command line arguments, kept for reloading the configuration.
+    args: Args,
+    /// The current settings.
+    config: RefCell<Rc<Config>>,
+    /// State of every repository shown by this server.
+    repos: RefCell<Vec<Rc<RepoState>>>,
+    /// The reactor handle, for spawning background tasks.
+    handle: Handle,
+    /// Requests to refresh a repository soon, after it is changed on GitHub.
+    refresh_requests: UnboundedSender<Rc<RepoState>>,
 }
 
-/// Packaged JSON-like object to be sent to Tera for rendering the main page.
-#[derive(Serialize)]
-struct RenderData {
+/// State of a repository shown by the server.
+struct RepoState {
+    /// The repository.
+    repo: Repository,
+    /// The merge queue of the repository.
+    queue: Box<QueueSource>,
+    /// The open PRs on GitHub, kept for incremental syncs.
+    pulls: RefCell<PullRequestSet>,
+    /// The last successfully fetched PR information.
+    snapshot: RefCell<Option<Rc<Snapshot>>>,
+    /// Whether a refresh requested by a webhook delivery is waiting to start.
+    refresh_pending: Cell<bool>,
+    /// The refresh in progress, joined by every request which needs one while it runs.
+    refreshing: RefCell<Option<SharedRefresh>>,
+    /// Number of refreshes started so far, telling whether `refreshing` has been superseded.
+    refresh_count: Cell<u64>,
+    /// When a sync was last requested at `/sync`.
+    synced_at: Cell<Option<Instant>>,
+}
+
+/// PR information, and the final state of the PRs closed or merged since the last sync.
+type FetchedPrs = (HashMap<u32, Pr>, HashMap<u32, PullRequestState>);
+
+/// A refresh which can be awaited by multiple requests.
+type SharedRefresh = Shared<Box<Future<Item = Rc<Snapshot>, Error = Error>>>;
+
+impl RepoState {
+    /// Creates the state of a repository which has not been fetched yet.
+    ///
+    /// If the on-disk cache contains all responses of this repository, the snapshot is restored
+    /// from the cache, so the page can be served immediately while the background refresh runs.
+    fn new(repo: Repository) -> Rc<Self> {
+        let queue = repo.backend.source(repo.queue_url.clone());
+        let snapshot = Snapshot::from_cache(&repo, &*queue).map(Rc::new);
+        Rc::new(Self {
+            queue,
+            repo,
+            pulls: RefCell::default(),
+            snapshot: RefCell::new(snapshot),
+            refresh_pending: Cell::new(false),
+            refreshing: RefCell::new(None),
+            refresh_count: Cell::new(0),
+            synced_at: Cell::new(None),
+        })
+    }
+
+    /// The URL path where pages of this repository are mounted, e.g. `/rust-lang/rust`.
+    fn path_prefix(&self) -> String {
+        format!("/{}/{}", self.repo.owner, self.repo.name)
+    }
+}
+
+/// PR information fetched from GitHub and the merge queue at some point in time.
+struct Snapshot {
     /// The list of PRs.
     prs: HashMap<u32, Pr>,
     /// PR statistics.
     stats: PrStats,
-    /// The command line arguments.
-    args: Rc<Args>,
+    /// When the information was fetched.
+    fetched_at: DateTime<Utc>,
+}
+
+impl Snapshot {
+    /// Restores a snapshot of the repository from the on-disk cache.
+    ///
+    /// The fetch time of the snapshot is that of the oldest cached response.
+    fn from_cache(repo: &Repository, queue: &QueueSource) -> Option<Self> {
+        let (queue_fetched_at, queue_entries) = queue.cached()?;
+        let (github_fetched_at, github_entries) =
+            ::github::query_cached(&repo.owner, &repo.name)?;
+        let prs = parse_prs(github_entries, queue_entries);
+        let stats = summarize_prs(prs.values());
+        info!("Restored cached PRs of {}", repo);
+        Some(Self {
+            prs,
+            stats,
+            fetched_at: queue_fetched_at.min(github_fetched_at),
+        })
+    }
+}
+
+/// Packaged JSON-like object to be sent to Tera for rendering the main page.
+#[derive(Serialize)]
+struct RenderData<'a> {
+    /// The list of PRs matching the filter.
+    prs: HashMap<u32, &'a Pr>,
+    /// Statistics of the PRs matching the filter.
+    stats: PrStats,
+    /// The structured query filtering the PRs, empty if there is none.
+    query: &'a str,
+    /// When the PR information was fetched.
+    fetched_at: DateTime<Utc>,
+    /// The repository being rendered.
+    repo: &'a Repository,
+    /// The server settings.
+    config: &'a Config,
+    /// The GitHub rate limit status, if known.
+    rate_limit: Option<RateLimit>,
+    /// Whether visitors can log in with GitHub.
+    login_enabled: bool,
+    /// The logged-in visitor, if any.
+    user: Option<Session>,
+}
+
+/// Packaged JSON-like object to be sent to Tera for rendering the repository index.
+#[derive(Serialize)]
+struct IndexRenderData<'a> {
+    /// Summary of each repository.
+    repos: Vec<RepoSummary<'a>>,
+}
+
+/// Summary of a repository shown in the repository index.
+#[derive(Serialize)]
+struct RepoSummary<'a> {
+    /// The repository.
+    repo: &'a Repository,
+    /// The URL path to the dashboard of the repository.
+    path: String,
+    /// PR statistics, `None` if the PRs are not fetched yet.
+    stats: Option<&'a PrStats>,
+    /// When the PR information was fetched, `None` if the PRs are not fetched yet.
+    fetched_at: Option<DateTime<Utc>>,
+}
+
+/// Packaged JSON-like object to be sent to Tera for rendering the bors command history.
+#[derive(Serialize)]
+struct CommandsRenderData<'a> {
+    /// The repository.
+    repo: &'a Repository,
+    /// PR number.
+    number: u32,
+    /// Counts of approvals, retries and failures.
+    summary: ::commands::Summary,
+    /// The commands and bot replies, from the oldest to the newest.
+    commands: Vec<::commands::Command>,
+}
+
+/// Packaged JSON-like object to be sent to Tera for rendering the reviewer workload page.
+#[derive(Serialize)]
+struct ReviewersRenderData<'a> {
+    /// The repository.
+    repo: &'a Repository,
+    /// When the PR information was fetched.
+    fetched_at: DateTime<Utc>,
+    /// The workload of each reviewer.
+    workloads: Vec<::reviewers::Workload>,
+}
+
+/// Packaged JSON-like object to be sent to Tera for rendering the CI log excerpts.
+#[derive(Serialize)]
+struct CiLogRenderData {
+    /// The repository.
+    repo: Repository,
+    /// The excerpts of the failed statuses.
+    excerpts: Vec<::ci_log::Excerpt>,
 }
 
 /// Packaged JSON-like object to be sent to Tera for rendering timeline.
 #[derive(Serialize)]
 struct TimelineRenderData {
     /// The timeline itself.
-    timeline: Vec<Value>,
+    timeline: Vec<::timeline::graphql::Event>,
+    /// The cursor to load the events before this timeline, `None` if it starts at the beginning.
+    earlier: Option<String>,
+    /// The time of the oldest event in this timeline.
+    start: Option<DateTime<Utc>>,
 }
 
 impl Service for Handler {
@@ -108,17 +375,45 @@ impl Service for Handler {
     type Future = Box<Future<Item = Response, Error = hyper::Error>>;
 
     fn call(&self, request: Request) -> Self::Future {
-        let uri = request.uri();
-        debug!("Received request to {}", uri);
+        debug!("Received request to {}", request.uri());
 
-        let encodings = request.headers().get::<AcceptEncoding>();
-        let can_gzip = encodings.map_or(false, |ae| ae.iter().any(|q| q.item == Encoding::Gzip));
+        let response = if request.path() == "/webhook/github" {
+            self.receive_webhook(request)
+        } else if ["/login", "/login/callback", "/logout"].contains(&request.path()) {
+            self.serve_login(&request)
+        } else {
+            let uri = request.uri();
+            let encodings = request.headers().get::<AcceptEncoding>();
+            let can_gzip =
+                encodings.map_or(false, |ae| ae.iter().any(|q| q.item == Encoding::Gzip));
+            let session = session::find(request.headers().get::<Cookie>());
+            self.serve(
+                request.method(),
+                uri.path(),
+                uri.query(),
+                can_gzip,
+                session.as_ref(),
+            )
+        };
 
         Box::new(
-            self.serve(uri.path(), can_gzip)
+            response
                 .or_else(|e| {
+                    let status = if e.downcast_ref::<BadRequest>().is_some() {
+                        StatusCode::BadRequest
+                    } else if e.downcast_ref::<Forbidden>().is_some() {
+                        StatusCode::Forbidden
+                    } else if e.downcast_ref::<PayloadTooLarge>().is_some() {
+                        StatusCode::PayloadTooLarge
+                    } else if e.downcast_ref::<BudgetExhausted>().is_some()
+                        || e.downcast_ref::<TooManyRequests>().is_some()
+                    {
+                        StatusCode::TooManyRequests
+                    } else {
+                        StatusCode::InternalServerError
+                    };
                     let mut response = Response::new();
-                    response.set_status(StatusCode::InternalServerError);
+                    response.set_status(status);
                     response.headers_mut().set(ContentType::plaintext());
                     response.set_body(e.to_string());
                     Ok(response)
@@ -135,6 +430,12 @@ lazy_static! {
     /// The regex which represents the PR timeline path.
     static ref TIMELINE_PATH_RE: Regex = Regex::new(r"^/timeline/([0-9]+)$").expect("timeline path regex");
 
+    /// The regex which represents the PR bors command history path.
+    static ref COMMANDS_PATH_RE: Regex = Regex::new(r"^/commands/([0-9]+)$").expect("commands path regex");
+
+    /// The regex which represents the PR CI log excerpts path.
+    static ref CI_LOG_PATH_RE: Regex = Regex::new(r"^/ci-log/([0-9]+)$").expect("CI log path regex");
+
     /// A hash map of file extension to their media types.
     static ref KNOWN_CONTENT_TYPES: HashMap<&'static str, Mime> = hashmap![
         "css" => TEXT_CSS,
@@ -145,27 +446,258 @@ lazy_static! {
 
 impl Handler {
     /// Serves a response from the URL.
-    fn serve(&self, path: &str, can_gzip: bool) -> Box<Future<Item = Response, Error = Error>> {
-        match path {
-            "/" => Box::new(
-                self.render()
+    ///
+    /// The pages of each repository are mounted under `/owner/name/`. If there is only one
+    /// repository, its pages are also mounted at the root.
+    fn serve(
+        &self,
+        method: &Method,
+        path: &str,
+        query: Option<&str>,
+        can_gzip: bool,
+        session: Option<&Session>,
+    ) -> Box<Future<Item = Response, Error = Error>> {
+        let repos = self.repos.borrow().clone();
+        for state in &repos {
+            let prefix = state.path_prefix();
+            if !path.starts_with(&prefix) {
+                continue;
+            }
+            let sub_path = &path[prefix.len()..];
+            if sub_path.is_empty() {
+                return Box::new(ok(redirect_response(prefix + "/")));
+            }
+            if sub_path.starts_with('/') {
+                if let Some(response) =
+                    self.serve_repo(state, method, sub_path, query, can_gzip, session)
+                {
+                    return response;
+                }
+            }
+        }
+
+        if path == "/metrics" {
+            return Box::new(ok(self.render_metrics()));
+        }
+
+        if repos.len() == 1 {
+            if let Some(response) =
+                self.serve_repo(&repos[0], method, path, query, can_gzip, session)
+            {
+                return response;
+            }
+        } else if path == "/" {
+            return Box::new(result(
+                self.render_index()
+                    .and_then(|body| html_response(&body, can_gzip)),
+            ));
+        } else if path == "/sync" {
+            return self.sync(method, repos);
+        }
+
+        Box::new(result(self.serve_sync(path, can_gzip)))
+    }
+
+    /// Serves a page belonging to a repository.
+    ///
+    /// The `path` is relative to the mount point of the repository. Returns `None` if the path
+    /// does not refer to any repository page.
+    fn serve_repo(
+        &self,
+        state: &Rc<RepoState>,
+        method: &Method,
+        path: &str,
+        query: Option<&str>,
+        can_gzip: bool,
+        session: Option<&Session>,
+    ) -> Option<Box<Future<Item = Response, Error = Error>>> {
+        let response: Box<Future<Item = Response, Error = Error>> = match path {
+            "/" | "/api/prs" | "/api/stats" => {
+                let filter = match Filter::from_query(query) {
+                    Ok(filter) => filter,
+                    Err(e) => return Some(Box::new(result::<Response, Error>(Err(e.into())))),
+                };
+                if path == "/" {
+                    return Some(Box::new(
+                        self.render(state, filter, session)
+                            .and_then(move |body| html_response(&body, can_gzip)),
+                    ));
+                }
+                let is_stats = path == "/api/stats";
+                Box::new(self.snapshot(state).and_then(move |snapshot| {
+                    if is_stats {
+                        json_response(&StatsReply::new(&snapshot.prs, &filter), can_gzip)
+                    } else {
+                        json_response(&PrsReply::new(&snapshot.prs, &filter), can_gzip)
+                    }
+                }))
+            }
+            "/sync" => self.sync(method, vec![Rc::clone(state)]),
+            "/reviewers" => Box::new(
+                self.render_reviewers(state)
                     .and_then(move |body| html_response(&body, can_gzip)),
             ),
             _ => {
-                if let Some(captures) = TIMELINE_PATH_RE.captures(path.as_bytes()) {
-                    let number = from_utf8(captures.get(1).expect("PR number").as_bytes())
-                        .expect("PR number")
-                        .parse()
-                        .expect("PR number");
-                    Box::new(
-                        self.render_timeline(number)
+                if let Some(number) = pr_number_in_path(&CI_LOG_PATH_RE, path) {
+                    return Some(Box::new(
+                        self.render_ci_log(state, number, session)
                             .and_then(move |body| html_response(&body, can_gzip)),
-                    )
-                } else {
-                    Box::new(result(self.serve_sync(path, can_gzip)))
+                    ));
+                }
+                if let Some(number) = pr_number_in_path(&COMMANDS_PATH_RE, path) {
+                    return Some(Box::new(
+                        self.render_commands(&state.repo, number, session)
+                            .and_then(move |body| html_response(&body, can_gzip)),
+                    ));
                 }
+                let number = pr_number_in_path(&TIMELINE_PATH_RE, path)?;
+                let range = match ::timeline::Range::from_query(query) {
+                    Ok(range) => range,
+                    Err(e) => return Some(Box::new(result::<Response, Error>(Err(e.into())))),
+                };
+                Box::new(
+                    self.render_timeline(&state.repo, number, &range, session)
+                        .and_then(move |body| html_response(&body, can_gzip)),
+                )
             }
+        };
+        Some(response)
+    }
+
+    /// Receives a GitHub webhook delivery.
+    ///
+    /// The cached responses affected by the event are evicted, and the repository will be
+    /// refreshed shortly. The refresh is a full sync if the event may change the CI status or the
+    /// mergeability of the PRs.
+    fn receive_webhook(&self, request: Request) -> Box<Future<Item = Response, Error = Error>> {
+        let config = Rc::clone(&self.config.borrow());
+        let repos = self.repos.borrow().clone();
+        let refresh_requests = self.refresh_requests.clone();
+        let (event, signature) = {
+            let headers = request.headers();
+            if let Some(&ContentLength(length)) = headers.get() {
+                if let Err(e) = webhook::check_size(length) {
+                    return Box::new(result(Err(e.into())));
+                }
+            }
+            let header = |name| headers.get_raw(name).and_then(|raw| raw.one());
+            let event = header("X-GitHub-Event").map_or_else(String::new, |event| {
+                String::from_utf8_lossy(event).into_owned()
+            });
+            (event, header("X-Hub-Signature-256").map(<[u8]>::to_vec))
+        };
+
+        // The length header may be absent or lying, so the size is checked while buffering too.
+        let body = request
+            .body()
+            .map_err(Error::from)
+            .fold(Vec::new(), |mut body, chunk| {
+                webhook::check_size((body.len() + chunk.len()) as u64)?;
+                body.extend_from_slice(&chunk);
+                Ok::<_, Error>(body)
+            });
+        Box::new(body.and_then(
+            move |body| {
+                let secret = config
+                    .webhook_secret
+                    .as_ref()
+                    .ok_or(Forbidden("webhook secret is not configured"))?;
+                webhook::verify(secret, signature.as_ref().map(|s| &**s), &body)?;
+                let delivery = match webhook::parse(&event, &body)? {
+                    Some(delivery) => delivery,
+                    None => return Ok(plaintext_response("ignored")),
+                };
+                let state = repos.iter().find(|state| {
+                    state.repo.owner.eq_ignore_ascii_case(&delivery.owner)
+                        && state.repo.name.eq_ignore_ascii_case(&delivery.repo)
+                });
+                let state = match state {
+                    Some(state) => state,
+                    None => return Ok(plaintext_response("ignored")),
+                };
+                info!("Received {} event of {}", event, state.repo);
+                let mut numbers = delivery.numbers;
+                if let Some(ref head_sha) = delivery.head_sha {
+                    numbers.extend(state.pulls.borrow().numbers_with_head(head_sha));
+                }
+                ::github::invalidate(&state.repo.owner, &state.repo.name, &numbers);
+                if delivery.needs_full_sync {
+                    state.pulls.borrow_mut().request_full_sync();
+                }
+                if !state.refresh_pending.replace(true) {
+                    let _ = refresh_requests.unbounded_send(Rc::clone(state));
+                }
+                Ok(plaintext_response("ok"))
+            },
+        ))
+    }
+
+    /// Serves the GitHub OAuth login pages: `/login`, `/login/callback` and `/logout`.
+    ///
+    /// The cookies are marked `Secure` if the request was forwarded from HTTPS by a reverse proxy.
+    fn serve_login(&self, request: &Request) -> Box<Future<Item = Response, Error = Error>> {
+        let cookie = request.headers().get::<Cookie>();
+        let secure = request
+            .headers()
+            .get_raw("X-Forwarded-Proto")
+            .and_then(|raw| raw.one())
+            .map_or(false, |proto| proto.eq_ignore_ascii_case(b"https"));
+        if request.path() == "/logout" {
+            // Logging out with a GET request would let any page log the visitor out.
+            if *request.method() != Method::Post {
+                return Box::new(ok(post_only_response()));
+            }
+            session::remove(cookie);
+            let mut response = found_response("/".to_owned());
+            response
+                .headers_mut()
+                .set(SetCookie(vec![cookie_header(SESSION_COOKIE, "", 0, secure)]));
+            return Box::new(ok(response));
+        }
+
+        let config = self.config.borrow();
+        let (client_id, client_secret) = match config.oauth {
+            Some((ref client_id, ref client_secret)) => (client_id, client_secret),
+            None => {
+                let error = BadRequest("logging in with GitHub is not enabled".to_owned());
+                return Box::new(result::<Response, Error>(Err(error.into())));
+            }
+        };
+
+        if request.path() == "/login" {
+            return Box::new(result(
+                session::start(client_id, request.query())
+                    .map_err(Error::from)
+                    .map(|(url, state)| {
+                        let mut response = found_response(url);
+                        response.headers_mut().set(SetCookie(vec![cookie_header(
+                            STATE_COOKIE,
+                            &state,
+                            600,
+                            secure,
+                        )]));
+                        response
+                    }),
+            ));
         }
+
+        Box::new(
+            session::finish(
+                &self.client,
+                client_id,
+                client_secret,
+                request.query(),
+                cookie,
+            )
+            .map(move |(id, return_to)| {
+                let mut response = found_response(return_to);
+                response.headers_mut().set(SetCookie(vec![
+                    cookie_header(SESSION_COOKIE, &id, SESSION_EXPIRY, secure),
+                    cookie_header(STATE_COOKIE, "", 0, secure),
+                ]));
+                response
+            }),
+        )
     }
 
     /// Serves a response which doesn't require asynchronous requests.
@@ -178,15 +710,10 @@ impl Handler {
                 response.headers_mut().set(ContentType::plaintext());
                 response.set_body("reloaded");
             }
-            "/sync" => {
-                response.set_status(StatusCode::Ok);
-                response.headers_mut().set(ContentType::plaintext());
-                response.set_body("synchronization request sent, go back and refresh.");
-            }
             _ => {
                 response.set_status(StatusCode::NotFound);
                 if SAFE_PATH_RE.is_match(path.as_bytes()) {
-                    let path = self.args.templates.join(&path[1..]);
+                    let path = self.config.borrow().templates.join(&path[1..]);
                     if path.exists() {
                         let mime = path
                             .extension()
@@ -211,58 +738,408 @@ impl Handler {
         Ok(response)
     }
 
-    /// Renders the web page.
+    /// Fetches the PR information of a repository, and replaces its snapshot with it. Changes
+    /// since the previous snapshot are sent to the notification subscribers.
+    ///
+    /// If a refresh is already in progress, it is joined instead of starting another one, so
+    /// overlapping refreshes never race to replace the snapshot or notify the same change twice.
+    fn refresh(&self, state: &Rc<RepoState>) -> Box<Future<Item = Rc<Snapshot>, Error = Error>> {
+        let refreshing = state.refreshing.borrow().clone();
+        let refresh = match refreshing {
+            Some(refresh) => refresh,
+            None => track_refresh(state, self.start_refresh(state)),
+        };
+        join_refresh(refresh)
+    }
+
+    /// Refreshes a repository like `refresh()`, but if a refresh is already in progress, starts
+    /// another one after it completes, so the result reflects every change made before this call.
+    fn refresh_again(
+        &self,
+        state: &Rc<RepoState>,
+    ) -> Box<Future<Item = Rc<Snapshot>, Error = Error>> {
+        let refreshing = state.refreshing.borrow().clone();
+        let refresh = match refreshing {
+            Some(previous) => {
+                let client = self.client.clone();
+                let config = Rc::clone(&self.config.borrow());
+                let handle = self.handle.clone();
+                let state = Rc::clone(state);
+                Box::new(previous.then(move |_| start_refresh(&client, &config, &handle, &state)))
+            }
+            None => self.start_refresh(state),
+        };
+        join_refresh(track_refresh(state, refresh))
+    }
+
+    /// Starts fetching the PR information of a repository. See `refresh()`.
+    fn start_refresh(
+        &self,
+        state: &Rc<RepoState>,
+    ) -> Box<Future<Item = Rc<Snapshot>, Error = Error>> {
+        start_refresh(&self.client, &self.config.borrow(), &self.handle, state)
+    }
+    /// Flushes every cached timeline of the repositories, fully syncs them right away, and reports
+    /// what has changed as plain text.
+    ///
+    /// Only POST requests are accepted, so crawlers and link prefetchers cannot spend the rate
+    /// limit budget. A repository is synced at most once every `MIN_SYNC_INTERVAL` seconds.
+    fn sync(
+        &self,
+        method: &Method,
+        states: Vec<Rc<RepoState>>,
+    ) -> Box<Future<Item = Response, Error = Error>> {
+        if *method != Method::Post {
+            return Box::new(ok(post_only_response()));
+        }
+        let min_interval = Duration::from_secs(MIN_SYNC_INTERVAL);
+        for state in &states {
+            if let Some(elapsed) = state.synced_at.get().map(|t| t.elapsed()) {
+                if elapsed < min_interval {
+                    let error = TooManyRequests(format!(
+                        "{} was synced {} seconds ago, please wait for {} seconds",
+                        state.repo,
+                        elapsed.as_secs(),
+                        (min_interval - elapsed).as_secs() + 1
+                    ));
+                    return Box::new(result::<Response, Error>(Err(error.into())));
+                }
+            }
+        }
+        let syncs = states
+            .into_iter()
+            .map(|state| {
+                state.synced_at.set(Some(Instant::now()));
+                ::github::flush(&state.repo.owner, &state.repo.name);
+                state.pulls.borrow_mut().request_full_sync();
+                let old_snapshot = state.snapshot.borrow().clone();
+                self.refresh_again(&state).map(move |new_snapshot| {
+                    let old_prs = old_snapshot.as_ref().map(|s| &s.prs);
+                    sync_report(&state.repo, old_prs, &new_snapshot.prs)
+                })
+            })
+            .collect::<Vec<_>>();
+        Box::new(join_all(syncs).map(|reports| {
+            let mut response = Response::new();
+            response.headers_mut().set(ContentType::plaintext());
+            response.set_body(reports.join("\n"));
+            response
+        }))
+    }
+
+    /// Obtains the current snapshot of PR information of a repository.
     ///
-    /// This method will *asynchronously* download PR information from GitHub and Homu.
-    fn render(&self) -> Box<Future<Item = String, Error = Error>> {
-        let args = Rc::clone(&self.args);
+    /// If the background refresh has not completed yet, this method will *asynchronously* wait
+    /// for it, or download PR information from GitHub and Homu if no refresh is in progress.
+    fn snapshot(&self, state: &Rc<RepoState>) -> Box<Future<Item = Rc<Snapshot>, Error = Error>> {
+        if let Some(ref snapshot) = *state.snapshot.borrow() {
+            return Box::new(ok(Rc::clone(snapshot)));
+        }
+        self.refresh(state)
+    }
+
+    /// Renders the web page of a repository from its current snapshot, showing only the PRs
+    /// matching the filter.
+    fn render(
+        &self,
+        state: &Rc<RepoState>,
+        filter: Filter,
+        session: Option<&Session>,
+    ) -> Box<Future<Item = String, Error = Error>> {
+        let config = Rc::clone(&self.config.borrow());
         let tera = Rc::clone(&self.tera);
+        let repo_state = Rc::clone(state);
+        let user = session.cloned();
+        Box::new(self.snapshot(state).and_then(move |snapshot| {
+            let prs = filter.apply(&snapshot.prs);
+            let data = RenderData {
+                stats: summarize_prs(prs.values().cloned()),
+                prs,
+                query: &filter.query_text,
+                fetched_at: snapshot.fetched_at,
+                repo: &repo_state.repo,
+                config: &config,
+                rate_limit: ::github::rate_limit(),
+                login_enabled: config.oauth.is_some(),
+                user,
+            };
+            let body = tera
+                .borrow()
+                .render("index.html", &data)
+                .map_err(TeraFailure::from)?;
+            Ok(body)
+        }))
+    }
 
-        let homu_future = ::homu::query(&self.client, &args.homu_url);
-        let github_future = ::github::query(
-            self.client.clone(),
-            args.token.clone(),
-            args.owner.clone(),
-            args.repository.clone(),
-        );
-        Box::new(
-            homu_future
-                .join(github_future)
-                .and_then(move |(homu, github)| {
-                    let prs = parse_prs(github, homu);
-                    let stats = summarize_prs(prs.values());
-                    let data = RenderData { prs, stats, args };
-                    let body = tera
-                        .borrow()
-                        .render("index.html", &data)
-                        .map_err(TeraFailure::from)?;
-                    Ok(body)
-                }),
-        )
+    /// Renders the index page listing all repositories.
+    fn render_index(&self) -> Result<String, Error> {
+        let states = self.repos.borrow();
+        let snapshots = states
+            .iter()
+            .map(|state| state.snapshot.borrow().clone())
+            .collect::<Vec<_>>();
+        let repos = states
+            .iter()
+            .zip(&snapshots)
+            .map(|(state, snapshot)| RepoSummary {
+                repo: &state.repo,
+                path: state.path_prefix() + "/",
+                stats: snapshot.as_ref().map(|s| &s.stats),
+                fetched_at: snapshot.as_ref().map(|s| s.fetched_at),
+            })
+            .collect();
+        let body = self
+            .tera
+            .borrow()
+            .render("repos.html", &IndexRenderData { repos })
+            .map_err(TeraFailure::from)?;
+        Ok(body)
     }
 
-    /// Renders the timeline HTML fragment of a PR.
-    fn render_timeline(&self, number: u32) -> Box<Future<Item = String, Error = Error>> {
-        let args = &self.args;
+    /// Renders the Prometheus metrics of all repositories.
+    ///
+    /// Repositories which have not been fetched yet are omitted.
+    fn render_metrics(&self) -> Response {
+        let states = self.repos.borrow();
+        let snapshots = states
+            .iter()
+            .filter_map(|state| Some((state, state.snapshot.borrow().clone()?)))
+            .collect::<Vec<_>>();
+        let repos = snapshots
+            .iter()
+            .map(|&(state, ref snapshot)| RepoMetrics {
+                repo: &state.repo,
+                prs: &snapshot.prs,
+                stats: &snapshot.stats,
+                fetched_at: snapshot.fetched_at,
+            })
+            .collect::<Vec<_>>();
+        let body = ::metrics::render(&repos);
+
+        let mut response = Response::new();
+        response
+            .headers_mut()
+            .set(ContentType("text/plain; version=0.0.4".parse().unwrap()));
+        response.set_body(body);
+        response
+    }
+
+    /// Chooses the token to fetch a PR timeline with: the visitor's own token if logged in.
+    /// Otherwise the server token is used, unless its rate limit budget is running low.
+    ///
+    /// Returns the token, and the login of the visitor owning it.
+    fn timeline_token(
+        &self,
+        session: Option<&Session>,
+    ) -> Result<(String, Option<String>), Error> {
+        match session {
+            Some(session) => Ok((session.token.clone(), Some(session.login.clone()))),
+            None => {
+                let config = self.config.borrow();
+                ::github::check_budget(config.rate_limit_reserve)?;
+                Ok((config.token.clone(), None))
+            }
+        }
+    }
+
+    /// Renders the timeline HTML fragment of a PR, either a single page or the whole history
+    /// depending on `range`.
+    fn render_timeline(
+        &self,
+        repo: &Repository,
+        number: u32,
+        range: &::timeline::Range,
+        session: Option<&Session>,
+    ) -> Box<Future<Item = String, Error = Error>> {
+        let (token, viewer) = match self.timeline_token(session) {
+            Ok(token) => token,
+            Err(e) => return Box::new(result::<String, Error>(Err(e))),
+        };
         let tera = Rc::clone(&self.tera);
-        Box::new(
+        let page: Box<Future<Item = ::timeline::Page, Error = Error>> = if range.all {
+            Box::new(
+                ::timeline::query_all(
+                    self.client.clone(),
+                    token,
+                    viewer,
+                    self.config.borrow().rate_limit_reserve,
+                    repo.owner.clone(),
+                    repo.name.clone(),
+                    number,
+                )
+                .map(|events| ::timeline::Page {
+                    events,
+                    earlier: None,
+                    start: None,
+                }),
+            )
+        } else {
             ::timeline::query(
                 &self.client,
-                &args.token,
-                &args.owner,
-                &args.repository,
+                &token,
+                viewer.as_ref().map(|s| &**s),
+                &repo.owner,
+                &repo.name,
+                number,
+                range,
+            )
+        };
+        Box::new(page.and_then(move |page| {
+            let data = TimelineRenderData {
+                timeline: page.events,
+                earlier: page.earlier,
+                start: page.start,
+            };
+            let body = tera
+                .borrow()
+                .render("timeline.html", &data)
+                .map_err(TeraFailure::from)?;
+            Ok(body)
+        }))
+    }
+
+    /// Renders the review workload page of a repository from its current snapshot.
+    fn render_reviewers(&self, state: &Rc<RepoState>) -> Box<Future<Item = String, Error = Error>> {
+        let tera = Rc::clone(&self.tera);
+        let repo_state = Rc::clone(state);
+        Box::new(self.snapshot(state).and_then(move |snapshot| {
+            let data = ReviewersRenderData {
+                repo: &repo_state.repo,
+                fetched_at: snapshot.fetched_at,
+                workloads: ::reviewers::aggregate(&snapshot.prs),
+            };
+            let body = tera
+                .borrow()
+                .render("reviewers.html", &data)
+                .map_err(TeraFailure::from)?;
+            Ok(body)
+        }))
+    }
+
+    /// Renders the bors command history page of a PR, extracted from its whole timeline.
+    fn render_commands(
+        &self,
+        repo: &Repository,
+        number: u32,
+        session: Option<&Session>,
+    ) -> Box<Future<Item = String, Error = Error>> {
+        let (token, viewer) = match self.timeline_token(session) {
+            Ok(token) => token,
+            Err(e) => return Box::new(result::<String, Error>(Err(e))),
+        };
+        let tera = Rc::clone(&self.tera);
+        let repo = repo.clone();
+        Box::new(
+            ::timeline::query_all(
+                self.client.clone(),
+                token,
+                viewer,
+                self.config.borrow().rate_limit_reserve,
+                repo.owner.clone(),
+                repo.name.clone(),
                 number,
             )
-            .and_then(move |timeline| {
+            .and_then(move |events| {
+                let commands = ::commands::extract(&events, &repo.bot_name);
+                let data = CommandsRenderData {
+                    repo: &repo,
+                    number,
+                    summary: ::commands::Summary::new(&commands),
+                    commands,
+                };
                 let body = tera
                     .borrow()
-                    .render("timeline.html", &TimelineRenderData { timeline })
+                    .render("commands.html", &data)
                     .map_err(TeraFailure::from)?;
                 Ok(body)
             }),
         )
     }
 
+    /// Renders the HTML fragment of the log excerpts of the failed CI statuses of a PR.
+    ///
+    /// The logs on GitHub Actions are fetched with the visitor's own token if logged in, and the
+    /// server token otherwise.
+    fn render_ci_log(
+        &self,
+        state: &Rc<RepoState>,
+        number: u32,
+        session: Option<&Session>,
+    ) -> Box<Future<Item = String, Error = Error>> {
+        let config = Rc::clone(&self.config.borrow());
+        let (token, viewer) = match session {
+            Some(session) => (session.token.clone(), Some(session.login.clone())),
+            None => (config.token.clone(), None),
+        };
+        let client = self.client.clone();
+        let handle = self.handle.clone();
+        let tera = Rc::clone(&self.tera);
+        let repo = state.repo.clone();
+        Box::new(
+            self.snapshot(state)
+                .and_then(move |snapshot| {
+                    let statuses = snapshot
+                        .prs
+                        .get(&number)
+                        .map_or(&[][..], |pr| &pr.ci_status);
+                    ::ci_log::excerpts(
+                        &client,
+                        &handle,
+                        &token,
+                        viewer.as_ref().map(|s| &**s),
+                        statuses,
+                        &config.failure_signatures,
+                        &config.log_sources,
+                    )
+                })
+                .and_then(move |excerpts| {
+                    let body = tera
+                        .borrow()
+                        .render("ci_log.html", &CiLogRenderData { repo, excerpts })
+                        .map_err(TeraFailure::from)?;
+                    Ok(body)
+                }),
+        )
+    }
+
+    /// Reloads the configuration file, and applies the new settings.
+    ///
+    /// Repositories which still exist in the new configuration keep their snapshots. The listen
+    /// address, templates directory, proxy, refresh interval and cache directory cannot be changed
+    /// without restarting the server.
+    fn reload_config(&self) -> Result<(), Error> {
+        let config = Config::load(&self.args)?;
+        {
+            let old_config = self.config.borrow();
+            if config.address != old_config.address
+                || config.templates != old_config.templates
+                || config.proxy != old_config.proxy
+                || config.refresh_interval != old_config.refresh_interval
+                || config.cache_dir != old_config.cache_dir
+            {
+                warn!("Changes to listen address, templates, proxy, refresh interval and cache directory will only take effect after restart");
+            }
+        }
+
+        let mut repos = self.repos.borrow_mut();
+        let new_repos = config
+            .repos
+            .iter()
+            .map(|repo| {
+                repos
+                    .iter()
+                    .find(|state| state.repo == *repo)
+                    .map_or_else(|| RepoState::new(repo.clone()), Rc::clone)
+            })
+            .collect();
+        *repos = new_repos;
+        // The verdicts in the cached excerpts may be outdated by the new failure signatures.
+        ::ci_log::clear();
+        *self.config.borrow_mut() = Rc::new(config);
+        info!("Configuration reloaded");
+        Ok(())
+    }
+
     /// Reloads the Tera template.
     fn reload_templates(&self) -> Result<(), Error> {
         let mut tera = self.tera.borrow_mut();
@@ -271,6 +1148,288 @@ impl Handler {
     }
 }
 
+/// Downloads PR information of a repository from GitHub and its merge queue, and merges them
+/// together.
+///
+/// Only PRs updated since the last sync are downloaded from GitHub, unless a periodic full sync is
+/// due. Also returns the final state of the PRs closed or merged since the last sync.
+fn fetch_prs(
+    client: &Client,
+    config: &Config,
+    state: &Rc<RepoState>,
+) -> Box<Future<Item = FetchedPrs, Error = Error>> {
+    let full_sync_interval = Duration::from_secs(config.full_sync_interval);
+    let since = state.pulls.borrow().incremental_since(full_sync_interval);
+    let started_at = Instant::now();
+
+    let queue_future = state.queue.query(client);
+    let github_future = ::github::query(
+        client.clone(),
+        config.token.clone(),
+        state.repo.owner.clone(),
+        state.repo.name.clone(),
+        since,
+        config.rate_limit_reserve,
+    );
+    let state = Rc::clone(state);
+    Box::new(queue_future.join(github_future).map(move |(queue, github)| {
+        let mut pulls = state.pulls.borrow_mut();
+        let closed = if since.is_some() {
+            pulls.merge(github)
+        } else {
+            pulls.replace(github, started_at);
+            HashMap::new()
+        };
+        (parse_prs(pulls.to_vec(), queue), closed)
+    }))
+}
+
+/// Starts fetching the PR information of a repository, replacing its snapshot and notifying the
+/// subscribers when done. See `Handler::refresh()`.
+fn start_refresh(
+    client: &Client,
+    config: &Rc<Config>,
+    handle: &Handle,
+    state: &Rc<RepoState>,
+) -> Box<Future<Item = Rc<Snapshot>, Error = Error>> {
+    let state = Rc::clone(state);
+    let client = client.clone();
+    let config = Rc::clone(config);
+    let handle = handle.clone();
+    let prs = fetch_prs(&client, &config, &state);
+    let prs = {
+        let client = client.clone();
+        let config = Rc::clone(&config);
+        let handle = handle.clone();
+        prs.and_then(move |(prs, closed)| {
+            classify_failures(&client, &handle, &config, prs).map(|prs| (prs, closed))
+        })
+    };
+    Box::new(prs.map(move |(prs, closed)| {
+        let stats = summarize_prs(prs.values());
+        let new_snapshot = Rc::new(Snapshot {
+            prs,
+            stats,
+            fetched_at: Utc::now(),
+        });
+        let old_snapshot = replace(
+            &mut *state.snapshot.borrow_mut(),
+            Some(Rc::clone(&new_snapshot)),
+        );
+        if let Some(old_snapshot) = old_snapshot {
+            if !config.subscriptions.is_empty() && !::recording::is_replaying() {
+                let events = notify::diff(&old_snapshot.prs, &new_snapshot.prs, &closed);
+                if !events.is_empty() {
+                    handle.spawn(notify::send(
+                        &client,
+                        &config.subscriptions,
+                        &state.repo,
+                        &events,
+                    ));
+                }
+            }
+        }
+        new_snapshot
+    }))
+}
+
+/// Records a refresh of a repository as in progress, so later requests can join it. The record is
+/// cleared once the refresh completes, unless another refresh has been recorded since.
+fn track_refresh(
+    state: &Rc<RepoState>,
+    refresh: Box<Future<Item = Rc<Snapshot>, Error = Error>>,
+) -> SharedRefresh {
+    let count = state.refresh_count.get() + 1;
+    state.refresh_count.set(count);
+    let tracked_state = Rc::clone(state);
+    let refresh: Box<Future<Item = Rc<Snapshot>, Error = Error>> =
+        Box::new(refresh.then(move |res| {
+            if tracked_state.refresh_count.get() == count {
+                tracked_state.refreshing.borrow_mut().take();
+            }
+            res
+        }));
+    let refresh = refresh.shared();
+    *state.refreshing.borrow_mut() = Some(refresh.clone());
+    refresh
+}
+
+/// Extracts the PR number from a path matching one of the `*_PATH_RE` regexes.
+///
+/// Returns `None` if the path does not match, or if the number is too large to be a PR number, so
+/// the path is not found.
+fn pr_number_in_path(regex: &Regex, path: &str) -> Option<u32> {
+    let captures = regex.captures(path.as_bytes())?;
+    from_utf8(captures.get(1)?.as_bytes()).ok()?.parse().ok()
+}
+
+/// Waits for a shared refresh of a repository.
+fn join_refresh(refresh: SharedRefresh) -> Box<Future<Item = Rc<Snapshot>, Error = Error>> {
+    Box::new(refresh.then(|res| match res {
+        Ok(snapshot) => Ok(Rc::clone(&snapshot)),
+        Err(e) => Err(unshare_error(&e)),
+    }))
+}
+
+/// Recovers the error of a shared future, keeping those which map to a specific HTTP status.
+fn unshare_error(e: &SharedError<Error>) -> Error {
+    match e.downcast_ref::<BudgetExhausted>() {
+        Some(e) => e.clone().into(),
+        None => err_msg(e.to_string()),
+    }
+}
+
+/// Classifies the CI failures of the PRs which failed in the merge queue against the known failure
+/// signatures, and marks those failing only spuriously as retry candidates.
+///
+/// Nothing is fetched if there are no signatures.
+fn classify_failures(
+    client: &Client,
+    handle: &Handle,
+    config: &Config,
+    mut prs: HashMap<u32, Pr>,
+) -> Box<Future<Item = HashMap<u32, Pr>, Error = Error>> {
+    if config.failure_signatures.is_empty() {
+        return Box::new(ok(prs));
+    }
+    let classifications = prs
+        .iter()
+        .filter(|&(_, pr)| pr.status == Status::Failure && pr.has_ci_failure)
+        .map(|(&number, pr)| {
+            let excerpts = ::ci_log::excerpts(
+                client,
+                handle,
+                &config.token,
+                None,
+                &pr.ci_status,
+                &config.failure_signatures,
+                &config.log_sources,
+            );
+            excerpts.map(move |excerpts| (number, excerpts))
+        })
+        .collect::<Vec<_>>();
+    Box::new(join_all(classifications).map(move |classifications| {
+        for (number, excerpts) in classifications {
+            if let Some(pr) = prs.get_mut(&number) {
+                pr.failures = excerpts
+                    .into_iter()
+                    .map(|excerpt| Classification {
+                        context: excerpt.context,
+                        url: excerpt.url,
+                        verdict: excerpt.verdict,
+                    })
+                    .collect();
+                pr.is_retry_candidate = !pr.failures.is_empty()
+                    && pr.failures.iter().all(|f| f.verdict.is_spurious());
+            }
+        }
+        prs
+    }))
+}
+
+/// Describes the PRs added, removed and changed status between two snapshots of a repository.
+fn sync_report(repo: &Repository, old: Option<&HashMap<u32, Pr>>, new: &HashMap<u32, Pr>) -> String {
+    let old = match old {
+        Some(old) => old,
+        None => return format!("Synchronized {}: fetched {} PRs.\n", repo, new.len()),
+    };
+
+    let mut added = new
+        .iter()
+        .filter(|&(number, _)| !old.contains_key(number))
+        .collect::<Vec<_>>();
+    let mut removed = old
+        .iter()
+        .filter(|&(number, _)| !new.contains_key(number))
+        .collect::<Vec<_>>();
+    let mut changed = new
+        .iter()
+        .filter_map(|(number, pr)| {
+            let old_pr = old.get(number)?;
+            if old_pr.status == pr.status {
+                None
+            } else {
+                Some((number, old_pr, pr))
+            }
+        })
+        .collect::<Vec<_>>();
+    added.sort_by_key(|&(number, _)| number);
+    removed.sort_by_key(|&(number, _)| number);
+    changed.sort_by_key(|&(number, _, _)| number);
+
+    let mut out = format!(
+        "Synchronized {}: {} added, {} removed, {} changed status.\n",
+        repo,
+        added.len(),
+        removed.len(),
+        changed.len()
+    );
+    for &(title, ref prs) in &[("Added", &added), ("Removed", &removed)] {
+        if !prs.is_empty() {
+            writeln!(out, "\n{}:", title).expect("writing to string");
+            for &(number, pr) in prs.iter() {
+                writeln!(out, "  #{} {}", number, pr.title).expect("writing to string");
+            }
+        }
+    }
+    if !changed.is_empty() {
+        out.push_str("\nChanged status:\n");
+        for &(number, old_pr, pr) in &changed {
+            writeln!(
+                out,
+                "  #{} {}: {} → {}",
+                number, pr.title, old_pr.status, pr.status
+            )
+            .expect("writing to string");
+        }
+    }
+    out
+}
+
+/// Creates a hyper response with a short plain text body.
+fn plaintext_response(body: &'static str) -> Response {
+    let mut response = Response::new();
+    response.headers_mut().set(ContentType::plaintext());
+    response.set_body(body);
+    response
+}
+
+/// Creates a hyper response refusing a request which is not sent with the POST method.
+fn post_only_response() -> Response {
+    let mut response = Response::new();
+    response.set_status(StatusCode::MethodNotAllowed);
+    response.headers_mut().set(Allow(vec![Method::Post]));
+    response
+}
+
+/// Creates a hyper response which temporarily redirects to another location.
+fn found_response(location: String) -> Response {
+    let mut response = Response::new();
+    response.set_status(StatusCode::Found);
+    response.headers_mut().set(Location::new(location));
+    response
+}
+
+/// Creates the value of a `Set-Cookie` header for a cookie only readable by the server. A
+/// `max_age` of 0 deletes the cookie. A `secure` cookie is only sent back over HTTPS.
+fn cookie_header(name: &str, value: &str, max_age: u64, secure: bool) -> String {
+    format!(
+        "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax{}",
+        name,
+        value,
+        max_age,
+        if secure { "; Secure" } else { "" }
+    )
+}
+
+/// Creates a hyper response which redirects to another location.
+fn redirect_response(location: String) -> Response {
+    let mut response = Response::new();
+    response.set_status(StatusCode::MovedPermanently);
+    response.headers_mut().set(Location::new(location));
+    response
+}
+
 /// Converts an HTML body string into a hyper response.
 fn html_response(body: &str, can_gzip: bool) -> Result<Response, Error> {
     let mut response = Response::new();
@@ -280,6 +1439,16 @@ fn html_response(body: &str, can_gzip: bool) -> Result<Response, Error> {
     Ok(response)
 }
 
+/// Serializes a value into a JSON hyper response.
+fn json_response<T: Serialize>(value: &T, can_gzip: bool) -> Result<Response, Error> {
+    let body = serde_json::to_vec(value)?;
+    let mut response = Response::new();
+    response.set_status(StatusCode::Ok);
+    response.headers_mut().set(ContentType::json());
+    set_response_body(&mut response, &*body, can_gzip)?;
+    Ok(response)
+}
+
 /// Sets the response's body with optional compression.
 ///
 /// If `can_gzip` is true, the body will be gzip-compressed, and the corresponding
@@ -303,3 +1472,20 @@ fn set_response_body<R: Read>(
     response.set_body(compressed);
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{pr_number_in_path, CI_LOG_PATH_RE, COMMANDS_PATH_RE, TIMELINE_PATH_RE};
+
+    #[test]
+    fn test_pr_number_in_path() {
+        assert_eq!(pr_number_in_path(&COMMANDS_PATH_RE, "/commands/45678"), Some(45678));
+        assert_eq!(pr_number_in_path(&TIMELINE_PATH_RE, "/timeline/4294967295"), Some(!0));
+        assert_eq!(pr_number_in_path(&TIMELINE_PATH_RE, "/timeline/4294967296"), None);
+        assert_eq!(pr_number_in_path(&COMMANDS_PATH_RE, "/commands/99999999999"), None);
+        assert_eq!(pr_number_in_path(&COMMANDS_PATH_RE, "/timeline/45678"), None);
+        assert_eq!(pr_number_in_path(&COMMANDS_PATH_RE, "/commands/"), None);
+        assert_eq!(pr_number_in_path(&CI_LOG_PATH_RE, "/ci-log/12345"), Some(12345));
+        assert_eq!(pr_number_in_path(&CI_LOG_PATH_RE, "/ci-log/123456789012345"), None);
+    }
+}
@@ -0,0 +1,277 @@
+//! GitHub OAuth login of the dashboard visitors.
+//!
+//! A logged-in visitor is identified by a session cookie. Queries made on behalf of the visitor,
+//! such as fetching a PR timeline, use the visitor's own GitHub token instead of the server token.
+//! Sessions are only kept in memory, so everyone is logged out when the server restarts.
+
+use api::{BadRequest, Forbidden};
+use failure::{err_msg, Error};
+use futures::{Future, IntoFuture, Stream};
+use hex;
+use hyper::header::Cookie;
+use lru_time_cache::LruCache;
+use rand::{thread_rng, Rng};
+use reqwest::async::Client;
+use reqwest::header::{ACCEPT, AUTHORIZATION, USER_AGENT};
+use serde_json;
+use serde_urlencoded;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Name of the cookie holding the session ID.
+pub const SESSION_COOKIE: &str = "borsholder_session";
+
+/// Name of the cookie holding the OAuth state while the visitor is logging in.
+pub const STATE_COOKIE: &str = "borsholder_oauth_state";
+
+/// Number of seconds a session is kept after the visitor's last request.
+pub const SESSION_EXPIRY: u64 = 30 * 86400;
+
+/// URL of the GitHub OAuth authorization page.
+const AUTHORIZE_ENDPOINT: &str = "https://github.com/login/oauth/authorize";
+
+/// URL to exchange the OAuth code for an access token.
+const ACCESS_TOKEN_ENDPOINT: &str = "https://github.com/login/oauth/access_token";
+
+/// URL to find out the user owning an access token.
+const USER_ENDPOINT: &str = "https://api.github.com/user";
+
+/// A logged-in visitor.
+#[derive(Clone, Serialize)]
+pub struct Session {
+    /// GitHub user name of the visitor.
+    pub login: String,
+    /// The visitor's GitHub token.
+    #[serde(skip)]
+    pub token: String,
+}
+
+lazy_static! {
+    static ref SESSIONS: Mutex<LruCache<String, Session>> =
+        Mutex::new(LruCache::with_expiry_duration(Duration::from_secs(SESSION_EXPIRY)));
+}
+
+/// Finds the session of a visitor from the request cookies.
+pub fn find(cookie: Option<&Cookie>) -> Option<Session> {
+    let id = cookie?.get(SESSION_COOKIE)?;
+    SESSIONS.lock().expect("poisoned").get(id).cloned()
+}
+
+/// Forgets the session of a visitor.
+pub fn remove(cookie: Option<&Cookie>) {
+    if let Some(id) = cookie.and_then(|c| c.get(SESSION_COOKIE)) {
+        SESSIONS.lock().expect("poisoned").remove(id);
+    }
+}
+
+/// Generates a random, unguessable ID.
+fn random_id() -> String {
+    let bytes: [u8; 20] = thread_rng().gen();
+    hex::encode(bytes)
+}
+
+/// Query parameters of `/login`.
+#[derive(Deserialize, Default)]
+struct LoginQuery {
+    /// The local path to return to after logging in.
+    return_to: Option<String>,
+}
+
+/// Starts logging in. Returns the URL of the GitHub authorization page to redirect to, and the
+/// value of the state cookie to set.
+///
+/// The state cookie contains the random OAuth state, which prevents forged logins, and the path
+/// to return to after logging in.
+pub fn start(client_id: &str, query: Option<&str>) -> Result<(String, String), BadRequest> {
+    let query = match query {
+        Some(query) => serde_urlencoded::from_str::<LoginQuery>(query)
+            .map_err(|e| BadRequest(format!("invalid query string: {}", e)))?,
+        None => LoginQuery::default(),
+    };
+    let return_to = query
+        .return_to
+        .filter(|path| is_local_path(path))
+        .unwrap_or_else(|| "/".to_owned());
+    let state = random_id();
+    let url = format!(
+        "{}?{}",
+        AUTHORIZE_ENDPOINT,
+        serde_urlencoded::to_string(&[("client_id", client_id), ("state", &state)])
+            .expect("encoding query string")
+    );
+    Ok((url, format!("{}:{}", state, return_to)))
+}
+
+/// Checks whether the path to return to after logging in is a local path, so the login cannot be
+/// used to redirect to other sites.
+fn is_local_path(path: &str) -> bool {
+    path.starts_with('/')
+        && !path.starts_with("//")
+        && path
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "/-_.".contains(c))
+}
+
+/// Checks the OAuth state sent back by GitHub against the state cookie set by `start()`, and
+/// returns the path to return to.
+///
+/// The cookie may have been injected, so the path is checked to be local again.
+fn check_state(cookie_state: &str, query_state: &str) -> Result<String, Forbidden> {
+    let mut parts = cookie_state.splitn(2, ':');
+    let (state, return_to) = (parts.next().unwrap_or(""), parts.next().unwrap_or("/"));
+    if state.is_empty() || state != query_state {
+        return Err(Forbidden(
+            "OAuth state does not match, please try logging in again",
+        ));
+    }
+    if is_local_path(return_to) {
+        Ok(return_to.to_owned())
+    } else {
+        Ok("/".to_owned())
+    }
+}
+
+/// Query parameters of `/login/callback`.
+#[derive(Deserialize)]
+struct CallbackQuery {
+    /// The OAuth code to exchange for an access token.
+    code: String,
+    /// The OAuth state, which should match the state cookie.
+    state: String,
+}
+
+/// Reply of the access token endpoint.
+#[derive(Deserialize)]
+struct AccessTokenReply {
+    /// The access token, if the code is accepted.
+    access_token: Option<String>,
+    /// Why the code is rejected.
+    error_description: Option<String>,
+}
+
+/// Reply of the user endpoint.
+#[derive(Deserialize)]
+struct User {
+    /// GitHub user name.
+    login: String,
+}
+
+/// Finishes logging in after GitHub redirects back with the OAuth code.
+///
+/// Returns the new session ID, and the path to return to.
+pub fn finish(
+    client: &Client,
+    client_id: &str,
+    client_secret: &str,
+    query: Option<&str>,
+    cookie: Option<&Cookie>,
+) -> Box<Future<Item = (String, String), Error = Error>> {
+    let query = match serde_urlencoded::from_str::<CallbackQuery>(query.unwrap_or("")) {
+        Ok(query) => query,
+        Err(e) => {
+            let error = BadRequest(format!("invalid OAuth callback: {}", e));
+            return Box::new(Err::<(String, String), Error>(error.into()).into_future());
+        }
+    };
+    let cookie_state = cookie.and_then(|c| c.get(STATE_COOKIE)).unwrap_or("");
+    let return_to = match check_state(cookie_state, &query.state) {
+        Ok(return_to) => return_to,
+        Err(error) => return Box::new(Err::<(String, String), Error>(error.into()).into_future()),
+    };
+
+    let user_client = client.clone();
+    Box::new(
+        client
+            .post(ACCESS_TOKEN_ENDPOINT)
+            .header(ACCEPT, "application/json")
+            .form(&[
+                ("client_id", client_id),
+                ("client_secret", client_secret),
+                ("code", &query.code),
+            ])
+            .send()
+            .and_then(|response| response.error_for_status())
+            .and_then(|response| response.into_body().concat2())
+            .map_err(Error::from)
+            .and_then(|body| {
+                let AccessTokenReply {
+                    access_token,
+                    error_description,
+                } = serde_json::from_slice(&body)?;
+                access_token.ok_or_else(|| {
+                    let reason = error_description.unwrap_or_default();
+                    err_msg(format!("GitHub rejected the login: {}", reason))
+                })
+            })
+            .and_then(move |token| {
+                user_client
+                    .get(USER_ENDPOINT)
+                    .header(AUTHORIZATION, format!("token {}", token))
+                    .header(USER_AGENT, "borsholder")
+                    .send()
+                    .and_then(|response| response.error_for_status())
+                    .and_then(|response| response.into_body().concat2())
+                    .map_err(Error::from)
+                    .and_then(move |body| {
+                        let user = serde_json::from_slice::<User>(&body)?;
+                        info!("{} logged in", user.login);
+                        let id = random_id();
+                        let session = Session {
+                            login: user.login,
+                            token,
+                        };
+                        SESSIONS.lock().expect("poisoned").insert(id.clone(), session);
+                        Ok((id, return_to))
+                    })
+            }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_state, start};
+
+    /// Finds the path to return to after logging in with the `/login` query string.
+    fn return_to(query: &str) -> String {
+        let (_, cookie_state) = start("client", Some(query)).unwrap();
+        let state = cookie_state.split(':').next().unwrap().to_owned();
+        check_state(&cookie_state, &state).unwrap()
+    }
+
+    #[test]
+    fn test_start_return_to() {
+        assert_eq!(return_to("return_to=/rust-lang/rust/"), "/rust-lang/rust/");
+        assert_eq!(return_to("return_to=%2Frust-lang%2Frust%2F"), "/rust-lang/rust/");
+        assert_eq!(return_to(""), "/");
+        assert_eq!(return_to("return_to=//evil.example.com/"), "/");
+        assert_eq!(return_to("return_to=https://evil.example.com/"), "/");
+        assert_eq!(return_to("return_to=/%5Cevil.example.com"), "/");
+        assert_eq!(return_to("return_to=/a?b=c"), "/");
+    }
+
+    #[test]
+    fn test_start_state_is_random() {
+        let (url, first) = start("client", None).unwrap();
+        let (_, second) = start("client", None).unwrap();
+        assert_ne!(first, second);
+        let state = first.split(':').next().unwrap();
+        assert!(url.contains(&format!("state={}", state)));
+        assert!(url.contains("client_id=client"));
+    }
+
+    #[test]
+    fn test_check_state() {
+        assert_eq!(check_state("abc:/rust-lang/rust/", "abc").unwrap(), "/rust-lang/rust/");
+        assert!(check_state("abc:/rust-lang/rust/", "abd").is_err());
+        assert!(check_state("abc:/", "").is_err());
+        assert!(check_state(":/", "").is_err());
+        assert!(check_state("", "").is_err());
+    }
+
+    #[test]
+    fn test_check_state_rejects_injected_return_to() {
+        assert_eq!(check_state("abc://evil.example.com/", "abc").unwrap(), "/");
+        assert_eq!(check_state("abc:https://evil.example.com/", "abc").unwrap(), "/");
+        assert_eq!(check_state("abc", "abc").unwrap(), "/");
+    }
+}
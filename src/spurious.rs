@@ -0,0 +1,213 @@
+//! Classifier of CI failures against a database of known failure signatures.
+//!
+//! The signatures are regexes loaded from a TOML file:
+//!
+//! ```toml
+//! [[signature]]
+//! name = "network timeout in rustup-toolchain-install"
+//! pattern = "curl: \\(28\\) Operation timed out"
+//! issue = 12345
+//! spurious = true
+//! ```
+//!
+//! The description and the job log of every failed status are matched against the signatures in
+//! order. A failure matching a spurious signature is a candidate to `@bors retry`.
+
+use failure::{Error, ResultExt};
+use lru_time_cache::LruCache;
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::fs::read_to_string;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+use toml;
+
+/// Number of seconds to remember that a failure was counted. This outlives the cached excerpts, so
+/// a failure classified again after its excerpt expired is still counted only once.
+const COUNTED_EXPIRY: u64 = 7 * 86400;
+
+/// A known failure signature.
+#[derive(Debug)]
+pub struct Signature {
+    /// Short description of the failure.
+    pub name: String,
+    /// Regex matching the failure text.
+    pub pattern: Regex,
+    /// Number of the issue tracking the failure.
+    pub issue: Option<u32>,
+    /// Whether the failure is unrelated to the PR, so the PR can simply be retried.
+    pub spurious: bool,
+}
+
+/// Content of the signature database file.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SignatureFile {
+    /// The signatures, given as an array of tables.
+    #[serde(default, rename = "signature")]
+    signatures: Vec<SignatureEntry>,
+}
+
+/// A signature in the database file, before compiling the regex.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SignatureEntry {
+    /// Short description of the failure.
+    name: String,
+    /// Regex matching the failure text.
+    pattern: String,
+    /// Number of the issue tracking the failure.
+    issue: Option<u32>,
+    /// Whether the failure is unrelated to the PR. Defaults to `true`.
+    #[serde(default = "default_spurious")]
+    spurious: bool,
+}
+
+/// Signatures are spurious unless stated otherwise.
+fn default_spurious() -> bool {
+    true
+}
+
+/// The verdict on a failed status.
+#[derive(Serialize, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Verdict {
+    /// The failure matches a known signature.
+    Known {
+        /// Short description of the failure.
+        name: String,
+        /// Number of the issue tracking the failure.
+        issue: Option<u32>,
+        /// Whether the failure is unrelated to the PR.
+        spurious: bool,
+    },
+    /// The failure matches no known signature, and is probably caused by the PR.
+    Genuine,
+}
+
+/// The verdict on a failed status of a PR.
+#[derive(Serialize, Clone)]
+pub struct Classification {
+    /// Name of the status context.
+    pub context: String,
+    /// URL of the CI job.
+    pub url: String,
+    /// The verdict.
+    pub verdict: Verdict,
+}
+
+impl Verdict {
+    /// Whether the failure is known to be spurious.
+    pub fn is_spurious(&self) -> bool {
+        match *self {
+            Verdict::Known { spurious, .. } => spurious,
+            Verdict::Genuine => false,
+        }
+    }
+}
+
+/// Number of failures matching each signature since the server started.
+struct Hits {
+    /// The count of each signature, by name.
+    counts: BTreeMap<String, u64>,
+    /// The target URL and signature name of every failure counted recently, so each is counted
+    /// once.
+    counted: LruCache<(String, String), ()>,
+}
+
+impl Default for Hits {
+    fn default() -> Self {
+        Hits {
+            counts: BTreeMap::new(),
+            counted: LruCache::with_expiry_duration(Duration::from_secs(COUNTED_EXPIRY)),
+        }
+    }
+}
+
+lazy_static! {
+    static ref HITS: Mutex<Hits> = Mutex::default();
+}
+
+/// Reads and compiles the signature database.
+pub fn load(path: &Path) -> Result<Vec<Signature>, Error> {
+    let content = read_to_string(path)
+        .with_context(|_| format!("cannot read failure signatures {}", path.display()))?;
+    let file: SignatureFile = toml::from_str(&content)
+        .with_context(|_| format!("invalid failure signatures {}", path.display()))?;
+    file.signatures
+        .into_iter()
+        .map(|entry| {
+            let pattern = Regex::new(&entry.pattern)
+                .with_context(|_| format!("invalid pattern of signature {:?}", entry.name))?;
+            Ok(Signature {
+                name: entry.name,
+                pattern,
+                issue: entry.issue,
+                spurious: entry.spurious,
+            })
+        })
+        .collect()
+}
+
+/// Classifies the failure text against the signatures. The first matching signature wins.
+pub fn classify(signatures: &[Signature], text: &str) -> Verdict {
+    match signatures.iter().find(|s| s.pattern.is_match(text)) {
+        Some(signature) => Verdict::Known {
+            name: signature.name.clone(),
+            issue: signature.issue,
+            spurious: signature.spurious,
+        },
+        None => Verdict::Genuine,
+    }
+}
+
+/// Counts the failure of the CI job at `url` towards the hit count of its signature. Each pair of
+/// job and signature is only counted once, however many times it is classified.
+pub fn record_hit(url: &str, verdict: &Verdict) {
+    if let Verdict::Known { ref name, .. } = *verdict {
+        let mut hits = HITS.lock().expect("poisoned");
+        if hits.counted.insert((url.to_owned(), name.clone()), ()).is_none() {
+            *hits.counts.entry(name.clone()).or_insert(0) += 1;
+        }
+    }
+}
+
+/// Number of failures matching each signature since the server started, sorted by name.
+pub fn hits() -> Vec<(String, u64)> {
+    HITS.lock()
+        .expect("poisoned")
+        .counts
+        .iter()
+        .map(|(name, count)| (name.clone(), *count))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hits, record_hit, Verdict};
+
+    /// The hit count of a signature.
+    fn count(name: &str) -> u64 {
+        hits()
+            .into_iter()
+            .find(|(n, _)| n == name)
+            .map_or(0, |(_, count)| count)
+    }
+
+    #[test]
+    fn test_record_hit_once_per_job() {
+        let verdict = Verdict::Known {
+            name: "test_record_hit_once_per_job".to_owned(),
+            issue: None,
+            spurious: true,
+        };
+        record_hit("https://ci.example.org/job/1", &verdict);
+        record_hit("https://ci.example.org/job/1", &verdict);
+        assert_eq!(count("test_record_hit_once_per_job"), 1);
+        record_hit("https://ci.example.org/job/2", &verdict);
+        assert_eq!(count("test_record_hit_once_per_job"), 2);
+        record_hit("https://ci.example.org/job/3", &Verdict::Genuine);
+        assert_eq!(count("test_record_hit_once_per_job"), 2);
+    }
+}
@@ -1,40 +1,512 @@
 //! GitHub Timeline API access.
 
-use failure::Error;
-use futures::Future;
-use github::{send_github_query, CacheKey};
+use api::BadRequest;
+use chrono::{DateTime, Utc};
+use failure::{err_msg, Error};
+use futures::future::{err, ok};
+use futures::stream::unfold;
+use futures::{Future, Stream};
+use github::{check_budget, send_github_query, CacheKey};
+use lru_time_cache::LruCache;
 use reqwest::async::Client;
-use tera::Value;
+use serde_urlencoded;
+use std::sync::Mutex;
+use std::time::Duration;
 
 /// Types related to the PR timeline GraphQL query.
+///
+/// Every event type queried in `timeline.gql` and `timeline_extras.gql` has its own variant in
+/// [`KnownEvent`]. The events are serialized back with the same field names for the templates.
+///
+/// [`KnownEvent`]: enum.KnownEvent.html
 pub mod graphql {
     #![cfg_attr(feature = "cargo-clippy", allow(missing_docs_in_private_items))]
 
-    use github::graphql::Connection;
+    use chrono::{DateTime, Utc};
+    use github::graphql::{CheckConclusionState, Connection, StatusState};
+    use metrics;
+    use serde::{Deserialize, Deserializer};
+    use serde_json::{self, Value};
 
-    /// The reply of a Timeline GraphQL query.
+    /// The reply of a Timeline GraphQL query. `P` is the pull request object, which differs
+    /// between `timeline.gql` and `timeline_extras.gql`.
     #[derive(Deserialize)]
     #[serde(rename_all = "camelCase")]
-    pub struct Reply {
-        pub data: Data,
+    pub struct Reply<P> {
+        pub data: Data<P>,
     }
 
     #[derive(Deserialize)]
     #[serde(rename_all = "camelCase")]
-    pub struct Data {
-        pub repository: Repository,
+    pub struct Data<P> {
+        pub repository: Repository<P>,
     }
 
     #[derive(Deserialize)]
     #[serde(rename_all = "camelCase")]
-    pub struct Repository {
-        pub pull_request: PullRequest,
+    pub struct Repository<P> {
+        pub pull_request: P,
     }
 
     #[derive(Deserialize)]
     #[serde(rename_all = "camelCase")]
     pub struct PullRequest {
-        pub timeline: Connection<::tera::Value>,
+        pub timeline: Connection<Event>,
+    }
+
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct ExtrasPullRequest {
+        pub timeline_items: Connection<Event>,
+    }
+
+    /// A list of nodes, serialized back for the templates.
+    #[derive(Deserialize, Serialize, Clone)]
+    pub struct Nodes<T> {
+        pub nodes: Vec<T>,
+    }
+
+    impl<T> Default for Nodes<T> {
+        fn default() -> Self {
+            Nodes { nodes: Vec::new() }
+        }
+    }
+
+    /// An event in the timeline of a PR.
+    #[derive(Serialize, Clone)]
+    #[serde(untagged)]
+    pub enum Event {
+        /// An event of a type queried in `timeline.gql`.
+        Known(KnownEvent),
+        /// An event of a type not queried in `timeline.gql`.
+        Unknown {
+            #[serde(rename = "__typename")]
+            typename: String,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            url: Option<String>,
+        },
+        /// An event of a type in [`KnownEvent`] which cannot be parsed, usually because GitHub
+        /// changed its schema.
+        ///
+        /// [`KnownEvent`]: enum.KnownEvent.html
+        Invalid {
+            #[serde(rename = "__typename")]
+            typename: String,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            url: Option<String>,
+        },
+    }
+
+    impl Event {
+        /// When the event happened. `None` if unknown.
+        pub fn time(&self) -> Option<DateTime<Utc>> {
+            let event = match *self {
+                Event::Known(ref event) => event,
+                Event::Unknown { .. } | Event::Invalid { .. } => return None,
+            };
+            Some(match *event {
+                KnownEvent::Commit(ref e) => e.committed_date,
+                KnownEvent::IssueComment(ref e) | KnownEvent::PullRequestReviewComment(ref e) => {
+                    e.created_at
+                }
+                KnownEvent::PullRequestReview(ref e) => e.comment.created_at,
+                KnownEvent::AssignedEvent(ref e) | KnownEvent::UnassignedEvent(ref e) => {
+                    e.base.created_at
+                }
+                KnownEvent::CrossReferencedEvent(ref e) => e.base.created_at,
+                KnownEvent::LabeledEvent(ref e) | KnownEvent::UnlabeledEvent(ref e) => {
+                    e.base.created_at
+                }
+                KnownEvent::ReferencedEvent(ref e) => e.base.created_at,
+                KnownEvent::RenamedTitleEvent(ref e) => e.base.created_at,
+                KnownEvent::ReviewDismissedEvent(ref e) => e.base.created_at,
+                KnownEvent::ReviewRequestedEvent(ref e)
+                | KnownEvent::ReviewRequestRemovedEvent(ref e) => e.base.created_at,
+                KnownEvent::ClosedEvent(ref e)
+                | KnownEvent::ReopenedEvent(ref e)
+                | KnownEvent::ConvertToDraftEvent(ref e)
+                | KnownEvent::ReadyForReviewEvent(ref e) => e.created_at,
+                KnownEvent::MilestonedEvent(ref e) | KnownEvent::DemilestonedEvent(ref e) => {
+                    e.base.created_at
+                }
+                KnownEvent::HeadRefForcePushedEvent(ref e) => e.base.created_at,
+                KnownEvent::MergedEvent(ref e) => e.base.created_at,
+                KnownEvent::MarkedAsDuplicateEvent(ref e) => e.base.created_at,
+                KnownEvent::BaseRefChangedEvent(ref e) => e.base.created_at,
+                KnownEvent::HeadRefDeletedEvent(ref e) => e.base.created_at,
+                KnownEvent::LockedEvent(ref e) => e.base.created_at,
+                // GitHub does not tell when a thread is resolved, so use its last comment instead.
+                KnownEvent::PullRequestReviewThread(ref e) => e.comments.nodes.last()?.created_at,
+            })
+        }
+
+        /// Whether the event is a review thread which has not been resolved. Such threads are
+        /// not shown in the timeline, as their comments are already part of the reviews.
+        pub fn is_unresolved_thread(&self) -> bool {
+            match *self {
+                Event::Known(KnownEvent::PullRequestReviewThread(ref e)) => !e.is_resolved,
+                _ => false,
+            }
+        }
+    }
+
+    impl<'de> Deserialize<'de> for Event {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            let value = Value::deserialize(deserializer)?;
+            let typename = value
+                .get("__typename")
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_owned();
+            let url = value.get("url").and_then(Value::as_str).map(str::to_owned);
+            if !KNOWN_TYPENAMES.contains(&&*typename) {
+                debug!("Treating {} timeline event as unknown", typename);
+                return Ok(Event::Unknown { typename, url });
+            }
+            match serde_json::from_value(value) {
+                Ok(event) => Ok(Event::Known(event)),
+                Err(e) => {
+                    warn!("Cannot parse {} timeline event: {}", typename, e);
+                    metrics::record_timeline_parse_error(&typename);
+                    Ok(Event::Invalid { typename, url })
+                }
+            }
+        }
+    }
+
+    /// The `__typename` of every variant of [`KnownEvent`]. Keep in sync with the enum.
+    ///
+    /// [`KnownEvent`]: enum.KnownEvent.html
+    const KNOWN_TYPENAMES: &[&str] = &[
+        "Commit",
+        "IssueComment",
+        "PullRequestReview",
+        "PullRequestReviewComment",
+        "AssignedEvent",
+        "UnassignedEvent",
+        "CrossReferencedEvent",
+        "LabeledEvent",
+        "UnlabeledEvent",
+        "ReferencedEvent",
+        "RenamedTitleEvent",
+        "ReviewDismissedEvent",
+        "ReviewRequestedEvent",
+        "ReviewRequestRemovedEvent",
+        "ClosedEvent",
+        "ReopenedEvent",
+        "MilestonedEvent",
+        "DemilestonedEvent",
+        "HeadRefForcePushedEvent",
+        "MergedEvent",
+        "ConvertToDraftEvent",
+        "ReadyForReviewEvent",
+        "MarkedAsDuplicateEvent",
+        "BaseRefChangedEvent",
+        "HeadRefDeletedEvent",
+        "LockedEvent",
+        "PullRequestReviewThread",
+    ];
+
+    #[derive(Deserialize, Serialize, Clone)]
+    #[serde(tag = "__typename")]
+    pub enum KnownEvent {
+        Commit(Commit),
+        IssueComment(Comment),
+        PullRequestReview(Review),
+        PullRequestReviewComment(Comment),
+        AssignedEvent(AssignmentEvent),
+        UnassignedEvent(AssignmentEvent),
+        CrossReferencedEvent(CrossReferencedEvent),
+        LabeledEvent(LabelEvent),
+        UnlabeledEvent(LabelEvent),
+        ReferencedEvent(ReferencedEvent),
+        RenamedTitleEvent(RenamedTitleEvent),
+        ReviewDismissedEvent(ReviewDismissedEvent),
+        ReviewRequestedEvent(ReviewRequestEvent),
+        ReviewRequestRemovedEvent(ReviewRequestEvent),
+        ClosedEvent(BaseEvent),
+        ReopenedEvent(BaseEvent),
+        MilestonedEvent(MilestoneEvent),
+        DemilestonedEvent(MilestoneEvent),
+        HeadRefForcePushedEvent(ForcePushEvent),
+        MergedEvent(MergedEvent),
+        ConvertToDraftEvent(BaseEvent),
+        ReadyForReviewEvent(BaseEvent),
+        MarkedAsDuplicateEvent(MarkedAsDuplicateEvent),
+        BaseRefChangedEvent(BaseRefChangedEvent),
+        HeadRefDeletedEvent(HeadRefDeletedEvent),
+        LockedEvent(LockedEvent),
+        PullRequestReviewThread(ReviewThread),
+    }
+
+    #[derive(Deserialize, Serialize, Clone)]
+    pub struct Actor {
+        pub login: String,
+    }
+
+    #[derive(Deserialize, Serialize, Clone)]
+    pub struct Label {
+        pub name: String,
+        pub color: String,
+    }
+
+    #[derive(Deserialize, Serialize, Clone)]
+    pub struct GitActor {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub user: Option<Actor>,
+    }
+
+    #[derive(Deserialize, Serialize, Clone)]
+    #[serde(rename_all = "camelCase")]
+    pub struct Commit {
+        pub url: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub committer: Option<GitActor>,
+        pub committed_date: DateTime<Utc>,
+        pub abbreviated_oid: String,
+        pub message_headline: String,
+        #[serde(default)]
+        pub check_suites: Nodes<CheckSuite>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub status: Option<Status>,
+    }
+
+    #[derive(Deserialize, Serialize, Clone)]
+    #[serde(rename_all = "camelCase")]
+    pub struct CheckSuite {
+        pub check_runs: Nodes<CheckRun>,
+    }
+
+    #[derive(Deserialize, Serialize, Clone)]
+    pub struct CheckRun {
+        pub name: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub title: Option<String>,
+        pub permalink: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub conclusion: Option<CheckConclusionState>,
+    }
+
+    #[derive(Deserialize, Serialize, Clone)]
+    pub struct Status {
+        pub contexts: Vec<StatusContext>,
+    }
+
+    #[derive(Deserialize, Serialize, Clone)]
+    #[serde(rename_all = "camelCase")]
+    pub struct StatusContext {
+        pub context: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub description: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub target_url: Option<String>,
+        pub state: StatusState,
+    }
+
+    #[derive(Deserialize, Serialize, Clone)]
+    #[serde(rename_all = "camelCase")]
+    pub struct Comment {
+        pub url: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub author: Option<Actor>,
+        #[serde(rename = "bodyHTML")]
+        pub body_html: String,
+        /// The Markdown source, only used to find the bors commands.
+        #[serde(default, skip_serializing)]
+        pub body: String,
+        pub created_at: DateTime<Utc>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub editor: Option<Actor>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub last_edited_at: Option<DateTime<Utc>>,
+    }
+
+    #[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+    #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+    pub enum ReviewState {
+        Pending,
+        Commented,
+        Approved,
+        ChangesRequested,
+        Dismissed,
+    }
+
+    #[derive(Deserialize, Serialize, Clone)]
+    pub struct Review {
+        #[serde(flatten)]
+        pub comment: Comment,
+        pub state: ReviewState,
+        pub comments: Nodes<Event>,
+    }
+
+    /// The fields common to all events other than commits and comments.
+    #[derive(Deserialize, Serialize, Clone)]
+    #[serde(rename_all = "camelCase")]
+    pub struct BaseEvent {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub actor: Option<Actor>,
+        pub created_at: DateTime<Utc>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub url: Option<String>,
+    }
+
+    #[derive(Deserialize, Serialize, Clone)]
+    pub struct AssignmentEvent {
+        #[serde(flatten)]
+        pub base: BaseEvent,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub user: Option<Actor>,
+    }
+
+    #[derive(Deserialize, Serialize, Clone)]
+    pub struct CrossReferencedEvent {
+        #[serde(flatten)]
+        pub base: BaseEvent,
+        pub source: ReferencedSubject,
+    }
+
+    /// The issue or PR referencing this PR.
+    #[derive(Deserialize, Serialize, Clone)]
+    pub struct ReferencedSubject {
+        #[serde(rename = "__typename")]
+        pub typename: String,
+        pub title: String,
+        pub number: u32,
+        pub url: String,
+    }
+
+    #[derive(Deserialize, Serialize, Clone)]
+    pub struct LabelEvent {
+        #[serde(flatten)]
+        pub base: BaseEvent,
+        pub label: Label,
+    }
+
+    #[derive(Deserialize, Serialize, Clone)]
+    pub struct ReferencedEvent {
+        #[serde(flatten)]
+        pub base: BaseEvent,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub commit: Option<Commit>,
+    }
+
+    #[derive(Deserialize, Serialize, Clone)]
+    #[serde(rename_all = "camelCase")]
+    pub struct RenamedTitleEvent {
+        #[serde(flatten)]
+        pub base: BaseEvent,
+        pub previous_title: String,
+        pub current_title: String,
+    }
+
+    #[derive(Deserialize, Serialize, Clone)]
+    pub struct ReviewDismissedEvent {
+        #[serde(flatten)]
+        pub base: BaseEvent,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub message: Option<String>,
+    }
+
+    #[derive(Deserialize, Serialize, Clone)]
+    #[serde(rename_all = "camelCase")]
+    pub struct ReviewRequestEvent {
+        #[serde(flatten)]
+        pub base: BaseEvent,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub requested_reviewer: Option<RequestedReviewer>,
+    }
+
+    /// The user or team requested to review. Only users have a login.
+    #[derive(Deserialize, Serialize, Clone)]
+    pub struct RequestedReviewer {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub login: Option<String>,
+    }
+
+    #[derive(Deserialize, Serialize, Clone)]
+    #[serde(rename_all = "camelCase")]
+    pub struct MilestoneEvent {
+        #[serde(flatten)]
+        pub base: BaseEvent,
+        pub milestone_title: String,
+    }
+
+    #[derive(Deserialize, Serialize, Clone)]
+    pub struct CommitOid {
+        #[serde(rename = "abbreviatedOid")]
+        pub abbreviated_oid: String,
+    }
+
+    #[derive(Deserialize, Serialize, Clone)]
+    #[serde(rename_all = "camelCase")]
+    pub struct ForcePushEvent {
+        #[serde(flatten)]
+        pub base: BaseEvent,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub before_commit: Option<CommitOid>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub after_commit: Option<CommitOid>,
+    }
+
+    #[derive(Deserialize, Serialize, Clone)]
+    #[serde(rename_all = "camelCase")]
+    pub struct MergedEvent {
+        #[serde(flatten)]
+        pub base: BaseEvent,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub commit: Option<CommitOid>,
+        pub merge_ref_name: String,
+    }
+
+    #[derive(Deserialize, Serialize, Clone)]
+    pub struct MarkedAsDuplicateEvent {
+        #[serde(flatten)]
+        pub base: BaseEvent,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub canonical: Option<ReferencedSubject>,
+    }
+
+    #[derive(Deserialize, Serialize, Clone)]
+    #[serde(rename_all = "camelCase")]
+    pub struct BaseRefChangedEvent {
+        #[serde(flatten)]
+        pub base: BaseEvent,
+        pub previous_ref_name: String,
+        pub current_ref_name: String,
+    }
+
+    #[derive(Deserialize, Serialize, Clone)]
+    #[serde(rename_all = "camelCase")]
+    pub struct HeadRefDeletedEvent {
+        #[serde(flatten)]
+        pub base: BaseEvent,
+        pub head_ref_name: String,
+    }
+
+    #[derive(Deserialize, Serialize, Clone)]
+    #[serde(rename_all = "camelCase")]
+    pub struct LockedEvent {
+        #[serde(flatten)]
+        pub base: BaseEvent,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub lock_reason: Option<String>,
+    }
+
+    #[derive(Deserialize, Serialize, Clone)]
+    #[serde(rename_all = "camelCase")]
+    pub struct ReviewThread {
+        pub is_resolved: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub resolved_by: Option<Actor>,
+        pub path: String,
+        pub comments: Nodes<ThreadComment>,
+    }
+
+    #[derive(Deserialize, Serialize, Clone)]
+    #[serde(rename_all = "camelCase")]
+    pub struct ThreadComment {
+        pub url: String,
+        pub created_at: DateTime<Utc>,
     }
 }
 
@@ -45,11 +517,29 @@ struct Request<'variables> {
     query: &'static str,
     /// Variables of the query.
     variables: Variables<'variables>,
+    /// The logged-in visitor whose token sends the request. Not sent to GitHub, but used to keep
+    /// the cached responses of each visitor apart.
+    #[serde(skip)]
+    viewer: Option<&'variables str>,
+}
+
+/// A GraphQL request for the supplementary timeline events, `EXTRAS_QUERY`.
+#[derive(Serialize)]
+struct ExtrasRequest<'variables> {
+    /// The query string.
+    query: &'static str,
+    /// Variables of the query. `last` is always `None`.
+    variables: Variables<'variables>,
+    /// The logged-in visitor whose token sends the request. Not sent to GitHub, but used to keep
+    /// the cached responses of each visitor apart.
+    #[serde(skip)]
+    viewer: Option<&'variables str>,
 }
 
 /// Variables in a Timeline GraphQL request.
 ///
-/// This structure is hard-coded to support the Timeline GraphQL query, `QUERY`.
+/// This structure is hard-coded to support the Timeline GraphQL queries, `QUERY` and
+/// `EXTRAS_QUERY`.
 #[derive(Serialize)]
 struct Variables<'variables> {
     /// Owner of the repository.
@@ -58,38 +548,409 @@ struct Variables<'variables> {
     repo: &'variables str,
     /// PR number.
     number: u32,
+    /// Number of events in a page. Always `None` for `EXTRAS_QUERY`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last: Option<u32>,
+    /// Fetch the events before this cursor. If `None`, fetch the latest events.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    before: Option<&'variables str>,
 }
 
 impl<'a, 'v> From<&'a Request<'v>> for CacheKey {
     fn from(req: &'a Request<'v>) -> Self {
-        CacheKey::Timeline(req.variables.number)
+        CacheKey::Timeline {
+            owner: req.variables.owner.into(),
+            repo: req.variables.repo.into(),
+            number: req.variables.number,
+            before: req.variables.before.map(Into::into),
+            history: req.variables.last == Some(HISTORY_PAGE_SIZE),
+            viewer: req.viewer.map(Into::into),
+        }
+    }
+}
+
+impl<'a, 'v> From<&'a ExtrasRequest<'v>> for CacheKey {
+    fn from(req: &'a ExtrasRequest<'v>) -> Self {
+        CacheKey::TimelineExtras {
+            owner: req.variables.owner.into(),
+            repo: req.variables.repo.into(),
+            number: req.variables.number,
+            before: req.variables.before.map(Into::into),
+            viewer: req.viewer.map(Into::into),
+        }
     }
 }
 
 /// The Timeline GraphQL query.
 const QUERY: &str = include!("timeline.gql");
 
-/// Fetch the most recent timeline of a pull request.
-pub fn query(
+/// The GraphQL query of the timeline events not covered by `QUERY`.
+const EXTRAS_QUERY: &str = include!("timeline_extras.gql");
+
+/// Number of events in a page shown on the dashboard.
+const PAGE_SIZE: u32 = 10;
+
+/// Number of events in a page when fetching the whole history. This is the most GitHub allows.
+const HISTORY_PAGE_SIZE: u32 = 100;
+
+/// Maximum number of pages fetched for the whole history.
+const MAX_HISTORY_PAGES: usize = 50;
+
+/// Maximum number of pages of supplementary events fetched for a PR. Each page has 100 events.
+const MAX_EXTRAS_PAGES: usize = 10;
+
+/// Number of seconds the supplementary events fetched with the latest page are reused for the
+/// earlier pages.
+const EXTRAS_EXPIRY: u64 = 3600;
+
+/// Maximum number of PRs whose supplementary events are kept.
+const EXTRAS_CAPACITY: usize = 1000;
+
+lazy_static! {
+    /// The supplementary events of each PR, keyed by the `CacheKey` of their latest page.
+    static ref EXTRAS: Mutex<LruCache<CacheKey, Vec<graphql::Event>>> = Mutex::new(
+        LruCache::with_expiry_duration_and_capacity(
+            Duration::from_secs(EXTRAS_EXPIRY),
+            EXTRAS_CAPACITY,
+        )
+    );
+}
+
+/// Which part of the timeline to fetch, parsed from the query string of `/timeline/{n}`.
+#[derive(Deserialize, Default)]
+pub struct Range {
+    /// Fetch the page before this cursor. If `None`, fetch the latest page.
+    pub before: Option<String>,
+    /// The time of the oldest event of the page after the requested one. Supplementary events
+    /// from this time on are already shown with that page.
+    pub until: Option<DateTime<Utc>>,
+    /// Fetch the whole history instead of a single page.
+    #[serde(default)]
+    pub all: bool,
+}
+
+impl Range {
+    /// Parses the range from the query string of the request URL.
+    pub fn from_query(query: Option<&str>) -> Result<Self, BadRequest> {
+        match query {
+            Some(query) => serde_urlencoded::from_str(query)
+                .map_err(|e| BadRequest(format!("invalid query string: {}", e))),
+            None => Ok(Self::default()),
+        }
+    }
+}
+
+/// A page of the timeline of a pull request.
+pub struct Page {
+    /// The events, from the oldest to the newest.
+    pub events: Vec<graphql::Event>,
+    /// The cursor to fetch the page before this one, `None` if this page starts at the beginning.
+    pub earlier: Option<String>,
+    /// The time of the oldest event in this page, to be passed as `until` when fetching the page
+    /// before this one.
+    pub start: Option<DateTime<Utc>>,
+}
+
+/// Fetch a page of the main timeline query, returning the events and the cursor of the page
+/// before.
+fn query_page(
     client: &Client,
     token: &str,
-    owner: &str,
-    repo: &str,
-    number: u32,
-) -> Box<Future<Item = Vec<Value>, Error = Error>> {
+    viewer: Option<&str>,
+    variables: Variables,
+) -> Box<Future<Item = (Vec<graphql::Event>, Option<String>), Error = Error>> {
     Box::new(
         send_github_query(
             client,
             token,
             &Request {
                 query: QUERY,
+                variables,
+                viewer,
+            },
+            true,
+            viewer.is_none(),
+        )
+        .map(|reply: graphql::Reply<graphql::PullRequest>| {
+            let timeline = reply.data.repository.pull_request.timeline;
+            let page_info = timeline.page_info;
+            let earlier = if page_info.has_previous_page {
+                page_info.start_cursor
+            } else {
+                None
+            };
+            (timeline.nodes, earlier)
+        }),
+    )
+}
+
+/// Fetch the supplementary events of a pull request, sorted by time. Unresolved review threads
+/// are dropped.
+///
+/// The events are fetched from GitHub only if `refresh` is true or they are not kept from an
+/// earlier call, so loading the earlier pages of a timeline does not fetch them again.
+fn query_extras(
+    client: &Client,
+    token: &str,
+    viewer: Option<&str>,
+    owner: &str,
+    repo: &str,
+    number: u32,
+    refresh: bool,
+) -> Box<Future<Item = Vec<graphql::Event>, Error = Error>> {
+    let key = CacheKey::TimelineExtras {
+        owner: owner.into(),
+        repo: repo.into(),
+        number,
+        before: None,
+        viewer: viewer.map(Into::into),
+    };
+    if !refresh {
+        if let Some(events) = EXTRAS.lock().expect("poisoned").get(&key) {
+            return Box::new(ok(events.clone()));
+        }
+    }
+
+    let client = client.clone();
+    let token = token.to_owned();
+    let viewer = viewer.map(str::to_owned);
+    let owner = owner.to_owned();
+    let repo = repo.to_owned();
+    // The state is the cursor of the next page to fetch, `None` after the first page is fetched,
+    // and the number of pages fetched so far.
+    let pages = unfold((Some(None), 0), move |(before, fetched)| {
+        let before: Option<String> = before?;
+        if fetched == MAX_EXTRAS_PAGES {
+            warn!("Skipping the earliest supplementary timeline events of #{}", number);
+            return None;
+        }
+        let viewer = viewer.as_ref().map(|s| &**s);
+        let page = send_github_query(
+            &client,
+            &token,
+            &ExtrasRequest {
+                query: EXTRAS_QUERY,
                 variables: Variables {
-                    owner,
-                    repo,
+                    owner: &owner,
+                    repo: &repo,
                     number,
+                    last: None,
+                    before: before.as_ref().map(|s| &**s),
                 },
+                viewer,
             },
+            true,
+            viewer.is_none(),
+        );
+        Some(page.map(move |reply: graphql::Reply<graphql::ExtrasPullRequest>| {
+            let items = reply.data.repository.pull_request.timeline_items;
+            let earlier = if items.page_info.has_previous_page {
+                items.page_info.start_cursor.map(Some)
+            } else {
+                None
+            };
+            (items.nodes, (earlier, fetched + 1))
+        }))
+    });
+    Box::new(pages.concat2().map(move |mut events| {
+        events.retain(|event| !event.is_unresolved_thread());
+        events.sort_by_key(graphql::Event::time);
+        EXTRAS.lock().expect("poisoned").insert(key, events.clone());
+        events
+    }))
+}
+
+/// Forgets the supplementary events of the PRs of a repository whose number satisfies the
+/// predicate.
+pub fn evict_extras<F: Fn(u32) -> bool>(owner: &str, repo: &str, predicate: F) {
+    let mut extras = EXTRAS.lock().expect("poisoned");
+    let keys = extras
+        .peek_iter()
+        .map(|(key, _)| key)
+        .filter(|key| match **key {
+            CacheKey::TimelineExtras {
+                owner: ref o,
+                repo: ref r,
+                number,
+                ..
+            } => o.eq_ignore_ascii_case(owner) && r.eq_ignore_ascii_case(repo) && predicate(number),
+            _ => false,
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+    for key in keys {
+        extras.remove(&key);
+    }
+}
+
+/// Merges the sorted supplementary events into the timeline events. Timeline events without a
+/// known time keep their positions.
+fn merge(events: Vec<graphql::Event>, extras: Vec<graphql::Event>) -> Vec<graphql::Event> {
+    let mut result = Vec::with_capacity(events.len() + extras.len());
+    let mut extras = extras.into_iter().peekable();
+    for event in events {
+        if let Some(time) = event.time() {
+            while extras
+                .peek()
+                .map_or(false, |extra| extra.time().map_or(true, |t| t <= time))
+            {
+                result.extend(extras.next());
+            }
+        }
+        result.push(event);
+    }
+    result.extend(extras);
+    result
+}
+
+/// Fetch a page of the timeline of a pull request, ending before the cursor `range.before`, or
+/// the most recent page if it is `None`.
+///
+/// The supplementary events are fetched separately, and those between the oldest event of the
+/// page and `range.until` are merged into the page. They are fetched from GitHub with the latest
+/// page only, and reused for the earlier pages.
+///
+/// If `viewer` is given, `token` belongs to that logged-in visitor. The rate limit status of the
+/// server is not updated, and the responses are cached for this visitor only.
+pub fn query(
+    client: &Client,
+    token: &str,
+    viewer: Option<&str>,
+    owner: &str,
+    repo: &str,
+    number: u32,
+    range: &Range,
+) -> Box<Future<Item = Page, Error = Error>> {
+    let before = range.before.as_ref().map(|s| &**s);
+    let until = range.until;
+    let variables = Variables {
+        owner,
+        repo,
+        number,
+        last: Some(PAGE_SIZE),
+        before,
+    };
+    let page = query_page(client, token, viewer, variables);
+    let extras = query_extras(client, token, viewer, owner, repo, number, before.is_none());
+    Box::new(page.join(extras).map(move |((events, earlier), extras)| {
+        // If no event in the page has a known time, it shows nothing but the extras after `until`.
+        let start = events.iter().filter_map(graphql::Event::time).min().or(until);
+        let lower = if earlier.is_some() { start } else { None };
+        let extras = extras
+            .into_iter()
+            .filter(|extra| match extra.time() {
+                Some(t) => lower.map_or(true, |l| t >= l) && until.map_or(true, |u| t < u),
+                None => until.is_none(),
+            })
+            .collect();
+        Page {
+            events: merge(events, extras),
+            earlier,
+            start,
+        }
+    }))
+}
+
+/// Fetch the whole timeline of a pull request, by following the pages from the most recent one
+/// back to the beginning. See `query()` for `viewer`.
+///
+/// With the server token, every page is refused once the rate limit budget drops below `reserve`.
+/// Histories longer than `MAX_HISTORY_PAGES` pages are refused as well.
+pub fn query_all(
+    client: Client,
+    token: String,
+    viewer: Option<String>,
+    reserve: u32,
+    owner: String,
+    repo: String,
+    number: u32,
+) -> Box<Future<Item = Vec<graphql::Event>, Error = Error>> {
+    let extras = query_extras(
+        &client,
+        &token,
+        viewer.as_ref().map(|s| &**s),
+        &owner,
+        &repo,
+        number,
+        true,
+    );
+    // The state is the cursor of the next page to fetch, `None` after the first page is fetched,
+    // and the number of pages fetched so far.
+    let pages = unfold((Some(None), 0), move |(before, fetched)| {
+        let before: Option<String> = before?;
+        if fetched == MAX_HISTORY_PAGES {
+            let error = format!("the timeline of #{} is too long to be fetched", number);
+            return Some(Box::new(err(err_msg(error))) as Box<Future<Item = _, Error = _>>);
+        }
+        if viewer.is_none() {
+            if let Err(e) = check_budget(reserve) {
+                return Some(Box::new(err(e.into())));
+            }
+        }
+        let variables = Variables {
+            owner: &owner,
+            repo: &repo,
+            number,
+            last: Some(HISTORY_PAGE_SIZE),
+            before: before.as_ref().map(|s| &**s),
+        };
+        let page = query_page(&client, &token, viewer.as_ref().map(|s| &**s), variables);
+        Some(Box::new(page.map(move |(events, earlier)| {
+            (events, (earlier.map(Some), fetched + 1))
+        })))
+    });
+    Box::new(pages.collect().join(extras).map(|(pages, extras)| {
+        let events = pages.into_iter().rev().flatten().collect();
+        merge(events, extras)
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::graphql::{Event, KnownEvent};
+    use serde_json::from_str;
+
+    #[test]
+    fn test_parse_known_event() {
+        let event = from_str::<Event>(
+            r#"{
+                "__typename": "ClosedEvent",
+                "actor": {"login": "bors"},
+                "createdAt": "2019-01-01T00:00:00Z",
+                "url": "https://github.com/rust-lang/rust/pull/1#event-1"
+            }"#,
         )
-        .map(|reply: graphql::Reply| reply.data.repository.pull_request.timeline.nodes),
-    )
+        .unwrap();
+        match event {
+            Event::Known(KnownEvent::ClosedEvent(e)) => assert_eq!(e.actor.unwrap().login, "bors"),
+            _ => panic!("expected a ClosedEvent"),
+        }
+    }
+
+    #[test]
+    fn test_parse_unknown_event() {
+        let event = from_str::<Event>(r#"{"__typename": "SubscribedEvent"}"#).unwrap();
+        match event {
+            Event::Unknown { typename, url } => {
+                assert_eq!(typename, "SubscribedEvent");
+                assert_eq!(url, None);
+            }
+            _ => panic!("expected an unknown event"),
+        }
+    }
+
+    #[test]
+    fn test_parse_invalid_event() {
+        let event = from_str::<Event>(
+            r#"{"__typename": "ClosedEvent", "url": "https://github.com/rust-lang/rust/pull/1"}"#,
+        )
+        .unwrap();
+        match event {
+            Event::Invalid { typename, url } => {
+                assert_eq!(typename, "ClosedEvent");
+                assert_eq!(url.unwrap(), "https://github.com/rust-lang/rust/pull/1");
+            }
+            _ => panic!("expected an invalid event"),
+        }
+    }
 }
@@ -0,0 +1,296 @@
+//! Receiver of GitHub webhook deliveries.
+//!
+//! GitHub notifies borsholder whenever a PR is updated, commented on or has its CI status changed.
+//! The cached responses affected by the event are then evicted, so the change is reflected within
+//! seconds instead of waiting for the cache to expire.
+
+use api::{BadRequest, Forbidden, PayloadTooLarge};
+use hex;
+use hmac::{Hmac, Mac};
+use serde::de::IgnoredAny;
+use serde_json;
+use sha2::Sha256;
+
+/// Maximum size of a delivery body in bytes. GitHub caps the payloads at 25 MB.
+const MAX_PAYLOAD_SIZE: usize = 25 << 20;
+
+/// Checks that a delivery body of the given size is acceptable, before it is buffered in memory for
+/// verifying the signature.
+pub fn check_size(size: u64) -> Result<(), PayloadTooLarge> {
+    if size > MAX_PAYLOAD_SIZE as u64 {
+        Err(PayloadTooLarge("webhook payload is too large"))
+    } else {
+        Ok(())
+    }
+}
+
+/// The PRs affected by a webhook delivery.
+pub struct Delivery {
+    /// Owner of the repository.
+    pub owner: String,
+    /// Name of the repository.
+    pub repo: String,
+    /// Numbers of the PRs affected. If empty, only the PR list is affected.
+    pub numbers: Vec<u32>,
+    /// The commit whose CI status changed. The PRs having it as their last commit are affected
+    /// too, since the payload does not always name them.
+    pub head_sha: Option<String>,
+    /// Whether the event may change the CI status or the mergeability of the PRs. GitHub does not
+    /// bump the update time of the PRs for these changes, so only a full sync picks them up.
+    pub needs_full_sync: bool,
+}
+
+/// Verifies the `X-Hub-Signature-256` header of a delivery, which is the HMAC-SHA256 of the body
+/// keyed by the webhook secret.
+pub fn verify(secret: &str, signature: Option<&[u8]>, body: &[u8]) -> Result<(), Forbidden> {
+    let signature = signature.ok_or(Forbidden("missing X-Hub-Signature-256 header"))?;
+    let prefix = b"sha256=";
+    if !signature.starts_with(prefix) {
+        return Err(Forbidden("malformed X-Hub-Signature-256 header"));
+    }
+    let signature = hex::decode(&signature[prefix.len()..])
+        .map_err(|_| Forbidden("malformed X-Hub-Signature-256 header"))?;
+    let mut mac = Hmac::<Sha256>::new_varkey(secret.as_bytes()).expect("HMAC accepts any key");
+    mac.input(body);
+    mac.verify(&signature)
+        .map_err(|_| Forbidden("X-Hub-Signature-256 does not match"))
+}
+
+/// The parts of a webhook payload needed to find the affected PRs.
+#[derive(Deserialize)]
+struct Payload {
+    /// What happened, e.g. `synchronize` for a `pull_request` event.
+    action: Option<String>,
+    /// The commit whose status changed, for `status` events.
+    sha: Option<String>,
+    /// The repository where the event occurred.
+    repository: Option<RepositoryPayload>,
+    /// The PR, for `pull_request` and `pull_request_review` events.
+    pull_request: Option<Numbered>,
+    /// The issue or PR, for `issue_comment` events.
+    issue: Option<Issue>,
+    /// The check run, for `check_run` events.
+    check_run: Option<CheckRun>,
+}
+
+/// A repository in a webhook payload.
+#[derive(Deserialize)]
+struct RepositoryPayload {
+    /// Name of the repository.
+    name: String,
+    /// Owner of the repository.
+    owner: Owner,
+}
+
+/// Owner of a repository in a webhook payload.
+#[derive(Deserialize)]
+struct Owner {
+    /// User name of the owner.
+    login: String,
+}
+
+/// An object with a PR number.
+#[derive(Deserialize)]
+struct Numbered {
+    /// The PR number.
+    number: u32,
+}
+
+/// An issue in a webhook payload. PRs are also issues.
+#[derive(Deserialize)]
+struct Issue {
+    /// The issue number.
+    number: u32,
+    /// Present only if the issue is a PR.
+    pull_request: Option<IgnoredAny>,
+}
+
+/// A check run in a webhook payload.
+#[derive(Deserialize)]
+struct CheckRun {
+    /// The checked commit.
+    head_sha: String,
+    /// The PRs containing the checked commit. PRs from forks are never listed.
+    pull_requests: Vec<Numbered>,
+}
+
+/// Finds out the PRs affected by a delivery of the `event` type.
+///
+/// Returns `None` if the event is irrelevant, e.g. a `ping` or a comment on an issue.
+pub fn parse(event: &str, body: &[u8]) -> Result<Option<Delivery>, BadRequest> {
+    match event {
+        "pull_request" | "pull_request_review" | "issue_comment" | "status" | "check_run"
+        | "label" => {}
+        _ => return Ok(None),
+    }
+    let payload = serde_json::from_slice::<Payload>(body)
+        .map_err(|e| BadRequest(format!("invalid webhook payload: {}", e)))?;
+    let repository = match payload.repository {
+        Some(repository) => repository,
+        None => return Ok(None),
+    };
+
+    let mut head_sha = None;
+    let numbers = match event {
+        "pull_request" | "pull_request_review" => payload
+            .pull_request
+            .into_iter()
+            .map(|pr| pr.number)
+            .collect(),
+        "issue_comment" => match payload.issue {
+            Some(Issue {
+                number,
+                pull_request: Some(_),
+            }) => vec![number],
+            _ => return Ok(None),
+        },
+        "check_run" => match payload.check_run {
+            Some(check_run) => {
+                head_sha = Some(check_run.head_sha);
+                check_run.pull_requests.into_iter().map(|pr| pr.number).collect()
+            }
+            None => Vec::new(),
+        },
+        "status" => {
+            head_sha = payload.sha;
+            Vec::new()
+        }
+        _ => Vec::new(),
+    };
+
+    // New commits reset the CI status, and a new base branch may change the mergeability.
+    let action = payload.action.unwrap_or_default();
+    let needs_full_sync = event == "status"
+        || event == "check_run"
+        || event == "pull_request" && ["synchronize", "edited", "reopened"].contains(&&*action);
+
+    Ok(Some(Delivery {
+        owner: repository.owner.login,
+        repo: repository.name,
+        numbers,
+        head_sha,
+        needs_full_sync,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_size, parse, verify, Delivery, MAX_PAYLOAD_SIZE};
+
+    /// The example in GitHub's documentation on validating webhook deliveries.
+    const SECRET: &str = "It's a Secret to Everybody";
+    const BODY: &[u8] = b"Hello, World!";
+    const SIGNATURE: &[u8] =
+        b"sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17";
+
+    /// Parses a delivery which should be relevant.
+    fn delivery(event: &str, body: &str) -> Delivery {
+        match parse(event, body.as_bytes()) {
+            Ok(Some(delivery)) => delivery,
+            Ok(None) => panic!("{} event should not be ignored: {}", event, body),
+            Err(e) => panic!("cannot parse {} event: {}", event, e.0),
+        }
+    }
+
+    #[test]
+    fn test_verify() {
+        assert!(verify(SECRET, Some(SIGNATURE), BODY).is_ok());
+        assert!(verify(SECRET, Some(SIGNATURE), b"Hello, World?").is_err());
+        assert!(verify("another secret", Some(SIGNATURE), BODY).is_err());
+        assert!(verify(SECRET, None, BODY).is_err());
+    }
+
+    #[test]
+    fn test_verify_malformed() {
+        let hex = &SIGNATURE[7..];
+        assert!(verify(SECRET, Some(hex), BODY).is_err());
+        assert!(verify(SECRET, Some(&[b"sha1=", hex].concat()), BODY).is_err());
+        assert!(verify(SECRET, Some(b"sha256=zz"), BODY).is_err());
+        assert!(verify(SECRET, Some(&SIGNATURE[..SIGNATURE.len() - 1]), BODY).is_err());
+        assert!(verify(SECRET, Some(b"sha256="), BODY).is_err());
+    }
+
+    #[test]
+    fn test_check_size() {
+        assert!(check_size(0).is_ok());
+        assert!(check_size(MAX_PAYLOAD_SIZE as u64).is_ok());
+        assert!(check_size(MAX_PAYLOAD_SIZE as u64 + 1).is_err());
+        assert!(check_size(1 << 40).is_err());
+    }
+
+    #[test]
+    fn test_parse_issue_comment() {
+        let repository = r#""repository": {"name": "rust", "owner": {"login": "rust-lang"}}"#;
+        let on_issue = format!(r#"{{"issue": {{"number": 1}}, {}}}"#, repository);
+        assert!(parse("issue_comment", on_issue.as_bytes()).unwrap().is_none());
+
+        let on_pr = format!(
+            r#"{{"issue": {{"number": 2, "pull_request": {{}}}}, {}}}"#,
+            repository
+        );
+        let delivery = delivery("issue_comment", &on_pr);
+        assert_eq!(delivery.owner, "rust-lang");
+        assert_eq!(delivery.repo, "rust");
+        assert_eq!(delivery.numbers, vec![2]);
+        assert!(!delivery.needs_full_sync);
+    }
+
+    #[test]
+    fn test_parse_check_run() {
+        let delivery = delivery(
+            "check_run",
+            r#"{
+                "action": "completed",
+                "check_run": {
+                    "head_sha": "abc123",
+                    "pull_requests": [{"number": 3}, {"number": 4}]
+                },
+                "repository": {"name": "rust", "owner": {"login": "rust-lang"}}
+            }"#,
+        );
+        assert_eq!(delivery.numbers, vec![3, 4]);
+        assert_eq!(delivery.head_sha.as_ref().unwrap(), "abc123");
+        assert!(delivery.needs_full_sync);
+    }
+
+    #[test]
+    fn test_parse_status() {
+        let delivery = delivery(
+            "status",
+            r#"{
+                "sha": "def456",
+                "state": "failure",
+                "repository": {"name": "rust", "owner": {"login": "rust-lang"}}
+            }"#,
+        );
+        assert!(delivery.numbers.is_empty());
+        assert_eq!(delivery.head_sha.as_ref().unwrap(), "def456");
+        assert!(delivery.needs_full_sync);
+    }
+
+    #[test]
+    fn test_parse_pull_request() {
+        let body = |action| {
+            format!(
+                r#"{{
+                    "action": "{}",
+                    "pull_request": {{"number": 5}},
+                    "repository": {{"name": "rust", "owner": {{"login": "rust-lang"}}}}
+                }}"#,
+                action
+            )
+        };
+        let synchronized = delivery("pull_request", &body("synchronize"));
+        assert_eq!(synchronized.numbers, vec![5]);
+        assert!(synchronized.head_sha.is_none());
+        assert!(synchronized.needs_full_sync);
+        assert!(!delivery("pull_request", &body("labeled")).needs_full_sync);
+    }
+
+    #[test]
+    fn test_parse_irrelevant() {
+        assert!(parse("ping", b"not even JSON").unwrap().is_none());
+        assert!(parse("issue_comment", b"{}").unwrap().is_none());
+        assert!(parse("pull_request", b"not JSON").is_err());
+    }
+}